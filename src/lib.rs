@@ -1,30 +1,41 @@
-//! Fixed-cost token sale contract that focuses on total number of tokens being sold and offers optional linear vesting of tokens (without cliff or instant unlock support)
+//! Fixed-cost token sale contract that focuses on total number of tokens being sold and offers optional linear vesting of tokens with an optional cliff and instant unlock at purchase
 //! If token vesting is enabled, users can tokenize the claim of tokens in an NFT allowing the owner of the NFT to have exclusivity on claiming the remaining unlocks (if applicable)
 //! The program is ABI-equivalent with Solidity, which means you can call it from both Solidity and Rust. To do this, run `cargo stylus export-abi`.
 
 // Allow `cargo stylus export-abi` to generate a main function.
-#![cfg_attr(not(feature = "export-abi"), no_main)]
+#![cfg_attr(not(any(test, feature = "export-abi")), no_main)]
+// The ABI export chains an iterator per public method which outgrows the default limit
+#![recursion_limit = "512"]
 
 extern crate alloc;
 
-use alloy_sol_types::sol; // Define errors and interfaces
+#[cfg(all(test, not(feature = "export-abi")))]
+mod test_host;
+
+use alloy_sol_types::{sol, SolCall}; // Define errors and interfaces
 use stylus_sdk::{
-    alloy_primitives::{U256, Address},
+    alloy_primitives::{U256, U8, Address, B256},
     prelude::*, // Contains common traits and macros.
     block,      // Includes block::timestamp
     msg,        // Access msg::sender
-    evm         // Events
+    evm,        // Events
+    call::{self, Call},
+    abi::{AbiType, Bytes, ConstString, Router}, // Pass Solidity structs to public methods and route batched calls
+    contract    // Access contract::address
 };
 
 sol_interface! {
-    interface IERC20 {
-        function transfer(address, uint256) external returns (bool);
-        function transferFrom(address, address, uint256) external returns (bool);
-    }
-
     interface IERC721 {
         function ownerOf(uint256) external returns (address);
     }
+
+    interface IERC1155 {
+        function balanceOf(address, uint256) external view returns (uint256);
+    }
+
+    interface ITokensClaimedReceiver {
+        function onTokensClaimed(address, uint256, bytes calldata) external;
+    }
 }
 
 // Define some persistent storage using the Solidity ABI.
@@ -35,6 +46,7 @@ sol_storage! {
         bool initialized;                               // Required before contract usage
         address owner;                                  // Smart contract manager 
         address token;                                  // Token being purchased
+        uint8 token_decimals;                           // Number of decimals used by the token being purchased
         address currency;                               // Payment currency for token
         uint256 price_per_token;                        // Price per token being purchased
         uint256 total_tokens_available;                 // Total number of tokens available for purchase
@@ -42,14 +54,73 @@ sol_storage! {
         address nft_claim;                              // Address of the NFT contract that can tokenise vesting
         uint256 total_tokens_purchased;                 // Total number of tokens purchased accross all users
         mapping(address => uint256) tokens_purchased;   // Tracking how many tokens a user has bought
-        mapping(address => uint256) tokens_purchased_at;// Tracking the timestamp when a user purchased their tokens
+        mapping(address => uint256) tokens_purchased_at;// Tracking the timestamp when a user first purchased their tokens
         mapping(address => uint256) tokens_claimed;     // Total number of vested tokens that have already been claimed
         mapping(address => uint256) tokens_claimed_at;  // Last timestamp of claim or zero if not been claimed yet
         mapping(address => uint256) nft_claim_token_id; // If enabled, the token ID of the NFT that is allowed to claim the vested tokens
+        address pending_owner;                          // Nominated owner that must accept ownership before it takes effect
+        bool paused;                                    // Emergency stop for purchases and claims
+        uint256 sale_start;                             // Timestamp from which purchases are accepted or zero if unbounded
+        uint256 sale_end;                               // Timestamp after which purchases are rejected or zero if unbounded
+        bool allowlist_enabled;                         // Whether purchases are restricted to allowlisted addresses
+        mapping(address => bool) allowlisted;           // Addresses allowed to purchase when the allowlist is enabled
+        uint256 cliff_length_in_seconds;                // Time after purchase before any vested tokens are released
+        uint256 instant_unlock_bps;                     // Basis points of each vested purchase released immediately at purchase
+        bool locked;                                    // Set while a purchase or claim is calling out to token contracts
+        mapping(address => bool) vesting_tokenized;     // Whether the vesting of a user is controlled by the NFT recorded in nft_claim_token_id
+        mapping(address => uint256) user_vesting_length;// Vesting length in force when the user first purchased
+        bool refunds_enabled;                           // Whether buyers that have not claimed can get their payment back
+        uint256 refund_reserve;                         // Currency deposited by the owner that is available for refunds
+        bool sale_closed;                               // Whether the owner has finalized the sale ahead of the end of the sale window
+        mapping(address => bool) vesting_split;         // Whether a tokenized vesting position is split across several NFTs
+        mapping(address => uint256) tokens_claimed_at_split;                          // Tokens the user had claimed when their position was split
+        mapping(address => mapping(uint256 => uint256)) nft_claim_shares;             // Remaining tokens at the time of the split assigned to each NFT
+        mapping(address => mapping(uint256 => uint256)) nft_claim_shares_claimed;     // Tokens already claimed by each NFT holding a share of a split position
+        uint256 max_currency_raised;                    // Most currency the sale will accept or zero if unlimited
+        uint256 total_currency_raised;                  // Total currency paid for purchases net of refunds
+        mapping(address => bool) approved_nft_collections;  // NFT contracts that buyers can use to tokenize their vesting
+        mapping(address => address) user_nft_collection;    // NFT contract chosen by a user when tokenizing their vesting
+        address treasury;                               // Receives purchase proceeds so that custody of funds is separate from ownership
+        uint256 fee_bps;                                // Basis points of each purchase cost paid to the fee recipient
+        address fee_recipient;                          // Platform operator receiving the fee taken from purchases
+        bool allow_multiple_purchases;                  // Whether a user can top up an existing allocation with further purchases
+        bool vest_from_sale_end;                        // Whether every buyer vests from the end of the sale rather than their purchase
+        mapping(address => bool) blacklisted;           // Addresses frozen by the owner that can neither purchase nor claim
+        uint256 max_buyers;                             // Most distinct addresses that can hold a purchase or zero if unlimited
+        uint256 unique_buyers;                          // Number of distinct addresses currently holding a purchase
+        uint256 release_interval_in_seconds;            // Vested tokens are released in steps of this length or continuously if zero
+        uint8 init_version;                             // Version of the initialization applied to storage or zero if never initialized
+        bool drained;                                   // Set once the owner has moved the sold tokens to safety which permanently disables claims
+        uint256 total_tokens_claimed;                   // Total number of purchased tokens released to buyers across all users
+        mapping(uint256 => bool) blocked_token_ids;     // NFT token IDs that the owner does not allow to tokenize vesting
+        bool any_position_tokenized;                    // Set once the first position has been tokenized which fixes the NFT claim contract
+        address currency_alt;                           // Alternate payment currency that buyers can choose or zero if only one currency is accepted
+        uint256 price_per_token_alt;                    // Price in the alternate currency per token being purchased
+        uint256 total_currency_alt_raised;              // Total alternate currency paid for purchases net of refunds
+        mapping(address => uint256) tokens_purchased_with_alt; // Portion of the tokens purchased by a user that were paid for in the alternate currency
+        uint256 refund_reserve_alt;                     // Alternate currency deposited by the owner that is available for refunds
+        bool fractional_purchases;                      // Whether purchase amounts are given in base units of the token rather than whole tokens
+        uint256 claim_grace_period;                     // Time after a position fully vests before the owner can sweep it or zero if sweeping is disabled
+        uint256 min_claim_interval;                     // Shortest time allowed between two claims of a position or zero if unlimited
+        mapping(address => uint8) roles;                // Bitflags of the operational roles delegated to an address by the owner
+        bool nft_is_1155;                               // Whether NFT collections used to tokenize vesting are ERC-1155 rather than ERC721
+        uint256 referral_bps;                           // Basis points of each referred purchase cost paid to the referrer
+        mapping(address => uint256) referral_volume;    // Total currency spent on purchases referred by an address
+        uint8 storage_layout_version;                   // Version of the storage layout written by `init` which is checked before the contract is used
+        mapping(address => uint256) user_purchase_price;     // Price per token in the currency when the user first purchased
+        uint256 purchase_counter;                       // ID of the most recent purchase where IDs start at one and increase with every purchase
+        bool credit_actual_received;                    // Whether purchases in the currency credit the buyer with what was received rather than reverting on a shortfall
+        mapping(address => uint256[]) nft_share_token_ids;    // Tokens holding a share of each split position in the order they were assigned
+        mapping(address => uint256[]) top_up_amounts;         // Tokens added by each top up of a vesting position after the first purchase
+        mapping(address => uint256[]) top_ups_purchased_at;   // Timestamp of each top up which vests from then rather than from the first purchase
+        mapping(address => uint256) accelerated_vesting_length;  // Shorter vesting length set by the owner in place of the purchase terms or zero if not accelerated
+        // Reserved slots at the end of storage so that an upgrade behind a proxy can add fields without moving existing ones.
+        // New fields go directly above the gap which shrinks by the number of slots they take
+        uint256[43] storage_gap;
     }
 }
 
-// Declare events and Solidity error types
+// Declare events, Solidity error types and the raw ERC20 calls used for transfers
 sol! {
     error NotInitialized();
     error AlreadyInitialized();
@@ -58,6 +129,7 @@ sol! {
     error InvalidPercentage();
     error VestingLengthTooShort();
     error VestingLengthTooLong();
+    error TooManyDecimals();
     error OnlyOnePurchase();
     error SoldOut();
     error VestingNotEnabled();
@@ -65,12 +137,145 @@ sol! {
     error NoTokensPurchased();
     error AlreadyTokenized();
     error AllTokensClaimed();
-    error TokensAreVested();
+    error UseVestedClaim();
     error TransferFailed();
+    error OnlyPendingOwner();
+    error EnforcedPause();
+    error ExpectedPause();
+    error InvalidSaleWindow();
+    error SaleNotStarted();
+    error SaleEnded();
+    error SaleNotEnded();
+    error NoUnsoldTokens();
+    error NotAllowlisted();
+    error InvalidCliffLength();
+    error CliffNotReached();
+    error Reentrancy();
+    error NotTokenized();
+    error TokensAlreadyPurchased();
+    error ZeroCost();
+    error LengthMismatch();
+    error RefundsNotEnabled();
+    error TokensAlreadyClaimed();
+    error InsufficientRefundReserve();
+    error SaleIsClosed();
+    error PositionSplit();
+    error InvalidSplit();
+    error NotAContract();
+    error RescueExceedsSurplus();
+    error RefundsOwed();
+    error RaiseCapReached();
+    error NftCollectionNotApproved();
+    error ClaimAmountTooHigh();
+    error PermitFailed();
+    error PositionNotFullyClaimed();
+    error FeeOnTransferUnsupported();
+    error Blacklisted();
+    error BuyerCapReached();
+    error InvalidReleaseInterval();
+    error ContractDrained();
+    error CostExceedsMax();
+    error InvalidVestingEnd();
+    error BlockedTokenId();
+    error DeadlinePassed();
+    error PositionsAlreadyTokenized();
+    error AltCurrencyNotConfigured();
+    error InvalidAltCurrency();
+    error SweepingNotEnabled();
+    error GracePeriodNotOver();
+    error InexactCost();
+    error ClaimTooSoon();
+    error MissingRole();
+    error InvalidRole();
+    error MathOverflow();
+    error SelfReferral();
+    error InsufficientBacking();
+    error StorageLayoutMismatch();
+    error UseUnlockedClaim();
+    error UnknownSelector();
+    error ClaimCallbackFailed();
+    error UnevenSupply();
+    error TooManyTopUps();
+
+    struct SaleConfig {
+        address token;
+        address currency;
+        uint256 price_per_token;
+        uint256 total_tokens_available;
+        uint256 total_vesting_length_in_seconds;
+        address nft_claim;
+        uint8 token_decimals;
+        uint256 sale_start;
+        uint256 sale_end;
+        bool allowlist_enabled;
+        uint256 cliff_length_in_seconds;
+        uint256 instant_unlock_bps;
+        address treasury;
+        uint256 fee_bps;
+        address fee_recipient;
+        bool allow_multiple_purchases;
+        address currency_alt;
+        uint256 price_per_token_alt;
+        bool require_prefunded;
+    }
+
+    function transfer(address to, uint256 amount) external returns (bool);
+    function transferFrom(address from, address to, uint256 amount) external returns (bool);
+    function balanceOf(address account) external view returns (uint256);
+    function permit(address owner, address spender, uint256 value, uint256 deadline, uint8 v, bytes32 r, bytes32 s) external;
 
-    event TokensPurchased(address indexed user, uint256 amount);
+    event TokensPurchased(address indexed user, uint256 indexed purchaseId, uint256 amount, uint256 cost, uint256 pricePerToken, uint256 purchasedAt);
     event TokenizedVestingEnabled(address indexed user, uint256 indexed nft_token_id);
     event TokensClaimed(address indexed user, address indexed recipient, uint256 amount);
+    event OwnershipTransferred(address indexed previous, address indexed next);
+    event Paused(address account);
+    event Unpaused(address account);
+    event UnsoldTokensWithdrawn(address indexed recipient, uint256 amount);
+    event AllowlistUpdated(address indexed user, bool allowed);
+    event TokenizedVestingDisabled(address indexed user, uint256 indexed nft_token_id);
+    event PriceUpdated(uint256 old, uint256 new);
+    event SaleProgress(uint256 purchased, uint256 remaining);
+    event VestingLengthUpdated(uint256 old, uint256 new);
+    event RefundsEnabled();
+    event RefundReserveDeposited(address indexed currency, uint256 amount);
+    event Refunded(address indexed user, uint256 tokens, uint256 amount, uint256 amountAlt);
+    event SaleClosed(uint256 totalPurchased);
+    event TokensRescued(address indexed token, address indexed recipient, uint256 amount);
+    event MaxCurrencyRaisedUpdated(uint256 old, uint256 new);
+    event NftCollectionApprovalUpdated(address indexed collection, bool approved);
+    event TreasuryUpdated(address indexed previous, address indexed next);
+    event FeeCollected(address recipient, uint256 amount);
+    event SaleSupplyIncreased(uint256 additional, uint256 totalAvailable);
+    event VestFromSaleEndUpdated(bool enabled);
+    event PositionFullyClaimed(address indexed user, uint256 total);
+    event BlacklistUpdated(address indexed user, bool blocked);
+    event MaxBuyersUpdated(uint256 old, uint256 new);
+    event ReleaseIntervalUpdated(uint256 old, uint256 new);
+    event EmergencyWithdrawal(address indexed recipient, uint256 amount);
+    event NftClaim(address indexed user, uint256 indexed tokenId, address indexed claimant, uint256 amount);
+    event VestingAccelerated(address indexed user, uint256 oldEnd, uint256 newEnd);
+    event BlockedTokenIdUpdated(uint256 indexed tokenId, bool blocked);
+    event NftClaimUpdated(address indexed previous, address indexed next);
+    event PositionMigrated(address indexed from, address indexed to, uint256 tokens);
+    event FractionalPurchasesUpdated(bool enabled);
+    event ClaimGracePeriodUpdated(uint256 old, uint256 new);
+    event AbandonedTokensSwept(address indexed user, address indexed treasury, uint256 amount);
+    event MinClaimIntervalUpdated(uint256 old, uint256 new);
+    event RoleGranted(address indexed account, uint8 role);
+    event RoleRevoked(address indexed account, uint8 role);
+    event NftStandardUpdated(bool is1155);
+    event ReferralBpsUpdated(uint256 old, uint256 new);
+    event ReferralRewarded(address indexed referrer, address indexed buyer, uint256 cost, uint256 reward);
+    event CreditActualReceivedUpdated(bool enabled);
+}
+
+// Allow `SaleConfig` to be passed to public methods where it is ABI encoded in the same way as a tuple of its fields
+impl AbiType for SaleConfig {
+    type SolType = Self;
+
+    const ABI: ConstString = ConstString::new(
+        "(address,address,uint256,uint256,uint256,address,uint8,uint256,uint256,bool,uint256,uint256,address,uint256,address,bool,address,uint256,bool)"
+    );
 }
 
 /// Exporting Solidity errors defined in sol! as Rust enums
@@ -83,6 +288,7 @@ pub enum Errors {
     InvalidPercentage(InvalidPercentage),
     VestingLengthTooShort(VestingLengthTooShort),
     VestingLengthTooLong(VestingLengthTooLong),
+    TooManyDecimals(TooManyDecimals),
     OnlyOnePurchase(OnlyOnePurchase),
     SoldOut(SoldOut),
     VestingNotEnabled(VestingNotEnabled),
@@ -90,8 +296,65 @@ pub enum Errors {
     NoTokensPurchased(NoTokensPurchased),
     AlreadyTokenized(AlreadyTokenized),
     AllTokensClaimed(AllTokensClaimed),
-    TokensAreVested(TokensAreVested),
-    TransferFailed(TransferFailed)
+    UseVestedClaim(UseVestedClaim),
+    TransferFailed(TransferFailed),
+    OnlyPendingOwner(OnlyPendingOwner),
+    EnforcedPause(EnforcedPause),
+    ExpectedPause(ExpectedPause),
+    InvalidSaleWindow(InvalidSaleWindow),
+    SaleNotStarted(SaleNotStarted),
+    SaleEnded(SaleEnded),
+    SaleNotEnded(SaleNotEnded),
+    NoUnsoldTokens(NoUnsoldTokens),
+    NotAllowlisted(NotAllowlisted),
+    InvalidCliffLength(InvalidCliffLength),
+    CliffNotReached(CliffNotReached),
+    Reentrancy(Reentrancy),
+    NotTokenized(NotTokenized),
+    TokensAlreadyPurchased(TokensAlreadyPurchased),
+    ZeroCost(ZeroCost),
+    LengthMismatch(LengthMismatch),
+    RefundsNotEnabled(RefundsNotEnabled),
+    TokensAlreadyClaimed(TokensAlreadyClaimed),
+    InsufficientRefundReserve(InsufficientRefundReserve),
+    SaleIsClosed(SaleIsClosed),
+    PositionSplit(PositionSplit),
+    InvalidSplit(InvalidSplit),
+    NotAContract(NotAContract),
+    RescueExceedsSurplus(RescueExceedsSurplus),
+    RefundsOwed(RefundsOwed),
+    RaiseCapReached(RaiseCapReached),
+    NftCollectionNotApproved(NftCollectionNotApproved),
+    ClaimAmountTooHigh(ClaimAmountTooHigh),
+    PermitFailed(PermitFailed),
+    PositionNotFullyClaimed(PositionNotFullyClaimed),
+    FeeOnTransferUnsupported(FeeOnTransferUnsupported),
+    Blacklisted(Blacklisted),
+    BuyerCapReached(BuyerCapReached),
+    InvalidReleaseInterval(InvalidReleaseInterval),
+    ContractDrained(ContractDrained),
+    CostExceedsMax(CostExceedsMax),
+    InvalidVestingEnd(InvalidVestingEnd),
+    BlockedTokenId(BlockedTokenId),
+    DeadlinePassed(DeadlinePassed),
+    PositionsAlreadyTokenized(PositionsAlreadyTokenized),
+    AltCurrencyNotConfigured(AltCurrencyNotConfigured),
+    InvalidAltCurrency(InvalidAltCurrency),
+    SweepingNotEnabled(SweepingNotEnabled),
+    GracePeriodNotOver(GracePeriodNotOver),
+    InexactCost(InexactCost),
+    ClaimTooSoon(ClaimTooSoon),
+    MissingRole(MissingRole),
+    InvalidRole(InvalidRole),
+    MathOverflow(MathOverflow),
+    SelfReferral(SelfReferral),
+    InsufficientBacking(InsufficientBacking),
+    StorageLayoutMismatch(StorageLayoutMismatch),
+    UseUnlockedClaim(UseUnlockedClaim),
+    UnknownSelector(UnknownSelector),
+    ClaimCallbackFailed(ClaimCallbackFailed),
+    UnevenSupply(UnevenSupply),
+    TooManyTopUps(TooManyTopUps)
 }
 
 /// One day defined in seconds as the minimum vesting length if applicable
@@ -100,6 +363,34 @@ const MIN_VESTING_LENGTH: i32 = 86_400;
 /// 365 days defined in seconds as the maximum vesting length if applicable
 const MAX_VESTING_LENGTH: i32 = 31_536_000;
 
+/// Upper bound on the decimals of the token being sold so that scaling whole tokens cannot overflow
+const MAX_TOKEN_DECIMALS: u8 = 36;
+
+/// Most top ups a vesting position can take so that claims adding up every lot of the position stay cheap
+const MAX_TOP_UPS: usize = 32;
+
+/// Denominator for values expressed in basis points where 10,000 is 100%
+const BASIS_POINTS: i32 = 10_000;
+
+/// Version recorded in storage by `init` so that any prior initialization can be detected even across layout changes
+const INIT_VERSION: u8 = 1;
+
+/// Version of the storage layout this code expects which must be bumped, with a migration, whenever the layout changes
+const STORAGE_LAYOUT_VERSION: u8 = 1;
+
+/// Sale phases reported by `sale_phase`
+const SALE_PHASE_UNINITIALIZED: u8 = 0;
+const SALE_PHASE_NOT_STARTED: u8 = 1;
+const SALE_PHASE_ACTIVE: u8 = 2;
+const SALE_PHASE_SOLD_OUT: u8 = 3;
+const SALE_PHASE_ENDED: u8 = 4;
+
+// Roles the owner can delegate, combined as bitflags
+const ROLE_PAUSER: u8 = 1;
+const ROLE_ALLOWLIST_MANAGER: u8 = 2;
+const ROLE_CONFIG_ADMIN: u8 = 4;
+const ALL_ROLES: u8 = ROLE_PAUSER | ROLE_ALLOWLIST_MANAGER | ROLE_CONFIG_ADMIN;
+
 /// External methods for `TokenSaleWithTokenizedVesting`
 #[public]
 impl TokenSaleWithTokenizedVesting {
@@ -113,7 +404,21 @@ impl TokenSaleWithTokenizedVesting {
     /// * `price_per_token` - Price in the currency per token being purchased
     /// * `total_tokens_available` - Total number of tokens available for purchase
     /// * `total_vesting_length_in_seconds` - If vesting is to be enabled, specify the vesting length
-    /// * `nft_claim` - Address of the ERC721 smart contract that can tokenize vesting if available
+    /// * `nft_claim` - Address of the ERC721 smart contract that can tokenize vesting which is required when vesting is enabled
+    /// * `token_decimals` - Number of decimals of the ERC20 being sold used to scale whole token amounts
+    /// * `sale_start` - Timestamp from which purchases are accepted or zero to accept them immediately
+    /// * `sale_end` - Timestamp after which purchases are rejected or zero to never close the sale
+    /// * `allowlist_enabled` - Whether purchases are restricted to addresses allowlisted by the owner
+    /// * `cliff_length_in_seconds` - Time after purchase before vested tokens start being released which must be shorter than the vesting length
+    /// * `instant_unlock_bps` - Basis points of each vested purchase released immediately with the remainder vesting linearly
+    /// * `treasury` - The address receiving purchase proceeds or zero to send them to the owner
+    /// * `fee_bps` - Basis points of each purchase cost paid to the fee recipient or zero for no fee
+    /// * `fee_recipient` - The address receiving the fee which is only required when a fee is taken
+    /// * `allow_multiple_purchases` - Whether a user can purchase again to add to their allocation or is limited to one purchase
+    /// * `currency_alt` - The address of a second ERC 20 payment token buyers can choose or zero to only accept `currency`
+    /// * `price_per_token_alt` - Price in the alternate currency per token being purchased which is required when it is set
+    /// * `require_prefunded` - Whether the smart contract must already hold every token available for purchase
+    #[allow(clippy::too_many_arguments)]
     pub fn init(
         &mut self,
         token: Address,
@@ -122,355 +427,6809 @@ impl TokenSaleWithTokenizedVesting {
         total_tokens_available: U256,
         total_vesting_length_in_seconds: U256,
         nft_claim: Address,
+        token_decimals: u8,
+        sale_start: U256,
+        sale_end: U256,
+        allowlist_enabled: bool,
+        cliff_length_in_seconds: U256,
+        instant_unlock_bps: U256,
+        treasury: Address,
+        fee_bps: U256,
+        fee_recipient: Address,
+        allow_multiple_purchases: bool,
+        currency_alt: Address,
+        price_per_token_alt: U256,
+        require_prefunded: bool,
     ) -> Result<(), Errors> {
-        // Perform required validation
-        self.validate_initialization()?;
-        self.validate_price_per_token(price_per_token)?;
-        self.validate_address(token)?;
-        self.validate_address(currency)?;
-        self.validate_total_tokens_for_sale(total_tokens_available)?;
-        self.validate_vesting_length(total_vesting_length_in_seconds)?;
-        self.validate_address(nft_claim)?;
-
-        // Setup the smart contract by configuring storage
-        self.initialized.set(true);
-        self.owner.set(msg::sender());
-        self.token.set(token);
-        self.currency.set(currency);
-        self.price_per_token.set(price_per_token);
-        self.total_tokens_available.set(total_tokens_available);
-        self.total_vesting_length_in_seconds.set(total_vesting_length_in_seconds);
-        self.nft_claim.set(nft_claim);
+        self.initialize(SaleConfig {
+            token,
+            currency,
+            price_per_token,
+            total_tokens_available,
+            total_vesting_length_in_seconds,
+            nft_claim,
+            token_decimals,
+            sale_start,
+            sale_end,
+            allowlist_enabled,
+            cliff_length_in_seconds,
+            instant_unlock_bps,
+            treasury,
+            fee_bps,
+            fee_recipient,
+            allow_multiple_purchases,
+            currency_alt,
+            price_per_token_alt,
+            require_prefunded
+        })
+    }
 
-        Ok(())
+    /// Initialize the smart contract from a config whose fields are named in the ABI so that arguments cannot be swapped
+    /// by mistake. Behaves exactly like `init`
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Every setting accepted by `init` with the same meaning
+    pub fn init_with_config(&mut self, config: SaleConfig) -> Result<(), Errors> {
+        self.initialize(config)
     }
 
     /// Main entry point for users to buy tokens
     ///
     /// # Arguments
     ///
-    /// * `amount` - Number of whole tokens being purchase which will calculate cost
+    /// * `amount` - Number of whole tokens being purchase, or base units of the token when fractional purchases are enabled,
+    /// which will calculate cost and is added to any previous purchase
     pub fn purchase_tokens(&mut self, amount: U256) -> Result<(), Errors> {
-        // No need to proceed if the contract is not yet initialized
-        self.validate_is_initialized()?;
+        self.non_reentrant(|sale| sale.process_purchase(amount, false, None))
+    }
 
-        // For simplicity on vesting, we only let the address buy a token allocation once. They can create other addresses if they want more
-        let tokens_purchased_by_user = self.tokens_purchased.get(msg::sender());
-        if tokens_purchased_by_user > U256::ZERO {
-            return Err(Errors::OnlyOnePurchase(OnlyOnePurchase {}))
+    /// Buy tokens paying in either the primary or the alternate currency at the price configured for that currency
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Number of whole tokens being purchase which will calculate cost and is added to any previous purchase
+    /// * `use_alt` - Whether to pay in the alternate currency at `price_per_token_alt` rather than the primary currency
+    pub fn purchase_tokens_with(&mut self, amount: U256, use_alt: bool) -> Result<(), Errors> {
+        self.non_reentrant(|sale| sale.process_purchase(amount, use_alt, None))
+    }
+
+    /// Buy tokens crediting the address that referred the buyer, who is paid a share of the cost out of the proceeds when
+    /// referral rewards are configured
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Number of whole tokens being purchased
+    /// * `referrer` - The address that referred the buyer which cannot be the buyer themselves
+    pub fn purchase_tokens_with_referrer(&mut self, amount: U256, referrer: Address) -> Result<(), Errors> {
+        self.validate_address(referrer)?;
+        self.validate_not_blacklisted(referrer)?;
+        if referrer == msg::sender() {
+            return Err(Errors::SelfReferral(SelfReferral {}))
         }
 
-        // Check if global limit has been reached
-        let total_tokens_purchased = self.total_tokens_purchased.get();
-        let purchase_amount = amount * U256::from(1_i32.pow(18));
-        if total_tokens_purchased + purchase_amount > self.total_tokens_available.get() {
-            return Err(Errors::SoldOut(SoldOut {}))
+        self.non_reentrant(|sale| sale.process_purchase(amount, false, Some(referrer)))
+    }
+
+    /// Buy tokens as long as the transaction is included before a deadline so that a stale transaction cannot execute later
+    /// under different conditions
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Number of whole tokens being purchased
+    /// * `deadline` - Last timestamp at which the purchase can go ahead
+    pub fn purchase_tokens_before(&mut self, amount: U256, deadline: U256) -> Result<(), Errors> {
+        if U256::from(block::timestamp()) > deadline {
+            return Err(Errors::DeadlinePassed(DeadlinePassed {}))
         }
 
-        // Record how many tokens user is buying and when they bought it
-        self.tokens_purchased.setter(msg::sender()).set(purchase_amount);
-        self.tokens_purchased_at.setter(msg::sender()).set(U256::from(block::timestamp()));
-        self.total_tokens_purchased.set(total_tokens_purchased + purchase_amount);
+        self.non_reentrant(|sale| sale.process_purchase(amount, false, None))
+    }
 
-        // calculate cost
-        let cost = amount * self.price_per_token.get();
-        let owner = self.owner.get();
+    /// Buy tokens while guarding against a price change between quoting and purchasing
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Number of whole tokens being purchased
+    /// * `max_cost` - Most the buyer is willing to pay in the currency for the tokens
+    pub fn purchase_tokens_max_cost(&mut self, amount: U256, max_cost: U256) -> Result<(), Errors> {
+        if self.quote_cost(amount)? > max_cost {
+            return Err(Errors::CostExceedsMax(CostExceedsMax {}))
+        }
 
-        // Log the purchase and conclude the transaction
-        evm::log(TokensPurchased {
-            user: msg::sender(),
-            amount
-        });
+        self.non_reentrant(|sale| sale.process_purchase(amount, false, None))
+    }
 
-        // Do the transfer
-        match IERC20::new(self.currency.get()).transfer_from(
-            self,
-            msg::sender(), 
-            owner,
-            cost
-        ) {
-            Ok(transfer_success) => if transfer_success { 
-                Ok(()) 
-            } else { 
-                Err(Errors::TransferFailed(TransferFailed {})) 
-            },
-            Err(_) => Err(Errors::TransferFailed(TransferFailed {}))
+    /// Buy as many tokens as a currency budget pays for as long as the budget is spent exactly, so that the buyer is never
+    /// left with dust currency
+    ///
+    /// # Arguments
+    ///
+    /// * `currency_amount` - Amount of currency to spend which must be an exact multiple of the cost of the smallest purchase
+    pub fn purchase_for_exact_cost(&mut self, currency_amount: U256) -> Result<(), Errors> {
+        let amount = self.tokens_for_currency(currency_amount)?;
+        if self.quote_cost(amount)? != currency_amount {
+            return Err(Errors::InexactCost(InexactCost {}))
         }
+
+        self.non_reentrant(|sale| sale.process_purchase(amount, false, None))
+    }
+
+    /// Buy tokens in a single transaction by approving the cost with an EIP-2612 permit signed for the currency
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Number of whole tokens being purchased
+    /// * `deadline` - Timestamp after which the permit signature is no longer valid
+    /// * `v` - Recovery byte of the permit signature
+    /// * `r` - First 32 bytes of the permit signature
+    /// * `s` - Second 32 bytes of the permit signature
+    pub fn purchase_tokens_with_permit(
+        &mut self,
+        amount: U256,
+        deadline: U256,
+        v: u8,
+        r: B256,
+        s: B256
+    ) -> Result<(), Errors> {
+        self.non_reentrant(|sale| {
+            sale.permit_currency(sale.quote_cost(amount)?, deadline, v, r, s)?;
+            sale.process_purchase(amount, false, None)
+        })
     }
 
     /// Allows a user that purchased tokens to nominate an NFT that is allowed to claim vested tokens if applicable
     ///
     /// # Arguments
     ///
+    /// * `nft_contract` - The approved NFT collection that the token belongs to
     /// * `token_id` - The token that can claim vested tokens regardless of its future owner
-    pub fn enable_tokenized_vesting(&mut self, token_id: U256) -> Result<(), Errors> {
-        // Validate whether it is possible to enable tokenized vesting
+    pub fn enable_tokenized_vesting(&mut self, nft_contract: Address, token_id: U256) -> Result<(), Errors> {
+        self.tokenize_vesting(msg::sender(), nft_contract, token_id)
+    }
+
+    /// Buy tokens and tokenize the resulting vesting position in a single transaction so that the position is never
+    /// claimable by the buyer. Either both steps succeed or neither does
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Number of whole tokens being purchased which is added to any previous purchase before tokenizing
+    /// * `nft_contract` - The approved NFT collection that the token belongs to
+    /// * `token_id` - The token that can claim vested tokens regardless of its future owner
+    pub fn purchase_and_tokenize(&mut self, amount: U256, nft_contract: Address, token_id: U256) -> Result<(), Errors> {
+        // Fail before taking payment if the position could not be tokenized
         let _ = self.validate_vesting_enabled()?;
+        self.validate_nft_collection_approved(nft_contract)?;
+        self.validate_token_id_not_blocked(token_id)?;
 
-        let tokens_purchased_by_user = self.tokens_purchased.get(msg::sender());
-        if tokens_purchased_by_user == U256::ZERO {
-            return Err(Errors::NoTokensVested(NoTokensVested {}))
-        }
+        self.non_reentrant(|sale| {
+            sale.process_purchase(amount, false, None)?;
+            sale.tokenize_vesting(msg::sender(), nft_contract, token_id)
+        })
+    }
+ 
+    /// Allow a user to claim vested tokens as long as it is active and not tokenized, returning the amount claimed
+    pub fn claim_tokens(&mut self) -> Result<U256, Errors> {
+        self.validate_not_paused()?;
 
-        let nft_claim_token_id = self.nft_claim_token_id.get(msg::sender());
-        if nft_claim_token_id != U256::ZERO {
+        if self.vesting_tokenized.get(msg::sender()) {
             return Err(Errors::AlreadyTokenized(AlreadyTokenized {}))
         }
 
-        if token_id == U256::ZERO {
-            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
-        }
-        
-        // Check they have not claimed everything
-        if self.tokens_claimed.get(msg::sender()) == tokens_purchased_by_user {
-            return Err(Errors::AllTokensClaimed(AllTokensClaimed {}))
+        self.non_reentrant(|sale| sale.claim_tokens_from_user(msg::sender(), msg::sender(), None))
+    }
+
+    /// If tokenized vesting is enabled, then allow the owner of the NFT to claim the vested tokens, returning the amount claimed
+    pub fn claim_tokens_by_nft(&mut self, user: Address) -> Result<U256, Errors> {
+        self.validate_not_paused()?;
+        if !self.vesting_tokenized.get(user) {
+            return Err(Errors::NotTokenized(NotTokenized {}))
         }
 
-        // Record the NFT that tokenized the vesting so that its owner can start claiming tokens
-        self.nft_claim_token_id.setter(msg::sender()).set(token_id);
+        self.validate_not_split(user)?;
+        let nft_contract = self.user_nft_collection.get(user);
+        let nft_claim_token_id = self.nft_claim_token_id.get(user);
+        self.validate_sender_owns_nft(nft_contract, nft_claim_token_id)?;
+        let amount = self.non_reentrant(|sale| sale.claim_tokens_from_user(user, msg::sender(), None))?;
 
-        // Log the vesting being enabled and conclude the transaction
-        evm::log(TokenizedVestingEnabled {
-            user: msg::sender(),
-            nft_token_id: token_id
+        // Attribute the claim to the holder of the NFT at the time of claiming
+        evm::log(NftClaim {
+            user,
+            tokenId: nft_claim_token_id,
+            claimant: msg::sender(),
+            amount
         });
-        
-        Ok(())
+
+        Ok(amount)
     }
- 
-    /// Allow a user to claim vested tokens as long as it is active and not tokenized
-    pub fn claim_tokens(&mut self) -> Result<(), Errors> {
-        let nft_claim_token_id = self.nft_claim_token_id.get(msg::sender());
-        if nft_claim_token_id != U256::ZERO {
-            return Err(Errors::AlreadyTokenized(AlreadyTokenized {}))
-        }
 
-        self.claim_tokens_from_user(msg::sender(), msg::sender())
+    /// When vesting is not enabled, allow the purchaser of tokens to claim all of the unlocked tokens, returning the amount claimed
+    pub fn claim_unlocked_tokens(&mut self) -> Result<U256, Errors> {
+        self.validate_not_paused()?;
+        self.non_reentrant(|sale| sale.claim_unlocked_tokens_from_user(msg::sender(), msg::sender()))
     }
 
-    /// If tokenized vesting is enabled, then allow the owner of the NFT to claim the vested tokens
-    pub fn claim_tokens_by_nft(&mut self, user: Address) -> Result<(), Errors> {
-        self.validate_sender_owns_nft(self.nft_claim_token_id.get(user))?;
-        self.claim_tokens_from_user(user, msg::sender())
+    /// Allows the current owner to nominate a new owner which only takes effect once accepted
+    ///
+    /// # Arguments
+    ///
+    /// * `new_owner` - The address that will be allowed to accept ownership of the smart contract
+    pub fn transfer_ownership(&mut self, new_owner: Address) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_address(new_owner)?;
+
+        self.pending_owner.set(new_owner);
+
+        Ok(())
     }
 
-    /// When vesting is not enabled, allow the purchaser of tokens to claim all of the unlocked tokens
-    pub fn claim_unlocked_tokens(&mut self) -> Result<(), Errors> {
-        // This function is only for token sales that have no vesting
-        if self.total_vesting_length_in_seconds.get() != U256::ZERO {
-            return Err(Errors::TokensAreVested(TokensAreVested {}))
+    /// Allows the nominated owner to complete the ownership handover
+    pub fn accept_ownership(&mut self) -> Result<(), Errors> {
+        let pending_owner = self.pending_owner.get();
+        if msg::sender() != pending_owner {
+            return Err(Errors::OnlyPendingOwner(OnlyPendingOwner {}))
         }
 
-        // Ensure the user has not claimed anything
-        if self.tokens_claimed.get(msg::sender()) != U256::ZERO {
-            return Err(Errors::AllTokensClaimed(AllTokensClaimed {}))
-        }
+        // Finalize the handover and clear the nomination
+        let previous_owner = self.owner.get();
+        self.owner.set(pending_owner);
+        self.pending_owner.set(Address::default());
 
-        // Record the claim in state
-        let tokens_purchased = self.tokens_purchased.get(msg::sender());
-        if tokens_purchased == U256::ZERO {
-            return Err(Errors::NoTokensPurchased(NoTokensPurchased {}))
-        }
+        // Log the handover and conclude the transaction
+        evm::log(OwnershipTransferred {
+            previous: previous_owner,
+            next: pending_owner
+        });
 
-        self.tokens_claimed.setter(msg::sender()).set(tokens_purchased);
-        self.tokens_claimed_at.setter(msg::sender()).set(U256::from(block::timestamp()));
+        Ok(())
+    }
 
-        // Log the amount of tokens sent and conclude the transaction
-        evm::log(TokensClaimed {
-            user: msg::sender(),
-            recipient: msg::sender(),
-            amount: tokens_purchased
-        });
-
-        // Send the user all the tokens that they purchased
-        match IERC20::new(self.token.get()).transfer(
-            self,
-            msg::sender(),
-            tokens_purchased
-        ) {
-            Ok(transfer_success) => if transfer_success { 
-                Ok(()) 
-            } else { 
-                Err(Errors::TransferFailed(TransferFailed {})) 
-            },
-            Err(_) => Err(Errors::TransferFailed(TransferFailed {}))
-        }
+    /// Address of the smart contract manager
+    pub fn owner(&self) -> Address {
+        self.owner.get()
     }
 
-}
+    /// Allows the owner to delegate operational duties by granting roles to an address. Roles are bitflags where 1 can
+    /// pause, 2 can manage the allowlist and 4 can change the sale configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The address being granted the roles
+    /// * `role` - One or more role bitflags to add to those the address already holds
+    pub fn grant_role(&mut self, account: Address, role: u8) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_address(account)?;
+        self.validate_role(role)?;
 
-// Internal methods for `TokenSaleWithTokenizedVesting`
-impl TokenSaleWithTokenizedVesting {
-    /// Function ensuring we are initialized
-    pub fn validate_is_initialized(&self) -> Result<(), Errors> {
-        if !self.initialized.get() {
-            return Err(Errors::NotInitialized(NotInitialized {}))
-        }
+        let roles = self.roles.get(account).to::<u8>();
+        self.roles.setter(account).set(U8::from(roles | role));
+
+        evm::log(RoleGranted {
+            account,
+            role
+        });
 
         Ok(())
     }
 
-    /// Function ensuring we are not already initialized
-    pub fn validate_initialization(&self) -> Result<(), Errors> {
-        if self.initialized.get() {
-            return Err(Errors::AlreadyInitialized(AlreadyInitialized {}))
-        } 
+    /// Allows the owner to take roles away from an address
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The address losing the roles
+    /// * `role` - One or more role bitflags to remove from those the address holds
+    pub fn revoke_role(&mut self, account: Address, role: u8) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_role(role)?;
+
+        let roles = self.roles.get(account).to::<u8>();
+        self.roles.setter(account).set(U8::from(roles & !role));
+
+        evm::log(RoleRevoked {
+            account,
+            role
+        });
 
         Ok(())
     }
 
-    /// Function ensuring sender is owner of the smart contract (simple ownership)
-    pub fn validate_sender_is_owner(&self) -> Result<(), Errors> {
-        if msg::sender() != self.owner.get() {
-            return Err(Errors::OnlyOwner(OnlyOwner {}))
-        }
-        
-        Ok(())
+    /// Role bitflags held by an address where 1 can pause, 2 can manage the allowlist and 4 can change the sale configuration
+    pub fn roles_of(&self, account: Address) -> u8 {
+        self.roles.get(account).to::<u8>()
     }
 
-    /// Function ensuring a zero price is not supplied to the smart contract
-    pub fn validate_price_per_token(&self, price_per_token: U256) -> Result<(), Errors> {
-        if price_per_token == U256::ZERO {
-            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
-        }
+    /// Address of the ERC20 being sold
+    pub fn token(&self) -> Address {
+        self.token.get()
+    }
 
-        Ok(())
+    /// Number of decimals of the ERC20 being sold
+    pub fn token_decimals(&self) -> u8 {
+        self.token_decimals.get().to::<u8>()
     }
 
-    /// Function ensuring that a zero value is not supplied for an address
-    pub fn validate_address(&self, value: Address) -> Result<(), Errors> {
-        if value == Address::default() {
-            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
-        }
+    /// Address of the ERC20 payment currency
+    pub fn currency(&self) -> Address {
+        self.currency.get()
+    }
 
-        Ok(())
+    /// Price in the currency per whole token being purchased
+    pub fn price_per_token(&self) -> U256 {
+        self.price_per_token.get()
     }
 
-    /// Function ensuring that total number of tokens being sold is not zero
-    pub fn validate_total_tokens_for_sale(&self, total_tokens: U256) -> Result<(), Errors> {
-        if total_tokens == U256::ZERO {
-            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
-        }
+    /// Total number of tokens available for purchase
+    pub fn total_tokens_available(&self) -> U256 {
+        self.total_tokens_available.get()
+    }
 
-        Ok(())
+    /// Vesting length in seconds or zero if vesting is not enabled
+    pub fn total_vesting_length_in_seconds(&self) -> U256 {
+        self.total_vesting_length_in_seconds.get()
     }
 
-    /// Function ensuring that when vesting length is not zero, it is a sensible length for users of the smart contract
-    pub fn validate_vesting_length(&self, vesting_length: U256) -> Result<(), Errors> {
-        if vesting_length != U256::ZERO {
-            if vesting_length < U256::from(MIN_VESTING_LENGTH) {
-                return Err(Errors::VestingLengthTooShort(VestingLengthTooShort {}))
-            }
-    
-            if vesting_length > U256::from(MAX_VESTING_LENGTH) {
-                return Err(Errors::VestingLengthTooLong(VestingLengthTooLong {}))
-            }
-        }
+    /// Address of the ERC721 smart contract that can tokenize vesting
+    pub fn nft_claim(&self) -> Address {
+        self.nft_claim.get()
+    }
+
+    /// Total number of tokens purchased across all users
+    pub fn total_tokens_purchased(&self) -> U256 {
+        self.total_tokens_purchased.get()
+    }
+
+    /// Allows the owner or a pauser to halt purchases and claims in an emergency
+    pub fn pause(&mut self) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_PAUSER)?;
+        self.validate_not_paused()?;
+
+        self.paused.set(true);
+
+        evm::log(Paused {
+            account: msg::sender()
+        });
 
         Ok(())
     }
 
-    /// Function ensuring that we only proceed if vesting is enabled returning the vesting length in seconds
-    pub fn validate_vesting_enabled(&self) -> Result<U256, Errors> {
-        let total_vesting_length_in_seconds = self.total_vesting_length_in_seconds.get();
-        if total_vesting_length_in_seconds == U256::ZERO {
-            return Err(Errors::VestingNotEnabled(VestingNotEnabled {}))
+    /// Allows the owner or a pauser to resume purchases and claims after an emergency
+    pub fn unpause(&mut self) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_PAUSER)?;
+        if !self.paused.get() {
+            return Err(Errors::ExpectedPause(ExpectedPause {}))
         }
 
-        Ok(total_vesting_length_in_seconds)
+        self.paused.set(false);
+
+        evm::log(Unpaused {
+            account: msg::sender()
+        });
+
+        Ok(())
     }
 
-    /// Function ensuring msg.sender is the owner of a ERC721 token
-    pub fn validate_sender_owns_nft(&mut self, token_id: U256) -> Result<(), Errors> {
-        let owner = match IERC721::new(self.nft_claim.get()).owner_of(self, token_id) {
-            Ok(owner) => owner,
-            Err(_) => Address::default()
-        };
+    /// Whether purchases and claims are currently halted
+    pub fn paused(&self) -> bool {
+        self.paused.get()
+    }
 
-        if owner != msg::sender() {
-            return Err(Errors::OnlyOwner(OnlyOwner {}))
-        }
+    /// Timestamp from which purchases are accepted or zero if unbounded
+    pub fn sale_start(&self) -> U256 {
+        self.sale_start.get()
+    }
 
-        Ok(())
+    /// Timestamp after which purchases are rejected or zero if unbounded
+    pub fn sale_end(&self) -> U256 {
+        self.sale_end.get()
     }
 
-    /// Logic for performing a claim of tokens if the tokens are vested, releasing a tranche since the last timestamp
+    /// Allows the owner to recover any tokens that were not sold once the sale has closed
     ///
     /// # Arguments
     ///
-    /// * `user` - The Ethereum wallet address of the user that purchased tokens
-    /// * `recipient` - The Ethereum wallet address which will receive unlocked tokens which can be different from the user
-    pub fn claim_tokens_from_user(
-        &mut self, 
-        user: Address, 
-        recipient: Address
-    ) -> Result<(), Errors> {
-        // Check whether tokens are vested by anyone purchasing
-        let total_vesting_length_in_seconds = self.validate_vesting_enabled()?;
-
-        // Check whether the user purchased any tokens
-        let tokens_purchased_by_user = self.tokens_purchased.get(user);
-        if tokens_purchased_by_user == U256::ZERO {
-            return Err(Errors::NoTokensVested(NoTokensVested {}))
-        }
+    /// * `recipient` - The address that will receive the unsold tokens
+    pub fn withdraw_unsold_tokens(&mut self, recipient: Address) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_address(recipient)?;
 
-        // Check when the last claim happened
-        let tokens_purchased_at = self.tokens_purchased_at.get(user);
-        let tokens_claimed_by_user = self.tokens_claimed.get(user);
-        let mut last_user_claim_timestamp = self.tokens_claimed_at.get(user);
-        if tokens_claimed_by_user == U256::ZERO {
-            last_user_claim_timestamp = tokens_purchased_at;
+        // When a sale window is configured, unsold tokens are only known once it has ended or the sale has been closed
+        let sale_end = self.sale_end.get();
+        if !self.sale_closed.get() && sale_end != U256::ZERO && U256::from(block::timestamp()) <= sale_end {
+            return Err(Errors::SaleNotEnded(SaleNotEnded {}))
         }
 
-        // Check they have not claimed everything
-        if tokens_claimed_by_user == tokens_purchased_by_user {
-            return Err(Errors::AllTokensClaimed(AllTokensClaimed {}))
+        let total_tokens_purchased = self.total_tokens_purchased.get();
+        let amount = self.tokens_remaining()?;
+        if amount == U256::ZERO {
+            return Err(Errors::NoUnsoldTokens(NoUnsoldTokens {}))
         }
 
-        // Calculate how many tokens to release 
-        let current_time = U256::from(block::timestamp());
-        let last_token_claim_at = tokens_purchased_at + total_vesting_length_in_seconds;
-        let mut tokens_claimed_setter = self.tokens_claimed.setter(user);
-        let mut tokens_claimed_at_setter = self.tokens_claimed_at.setter(user);
-        let amount: U256 = if current_time >= last_token_claim_at {
-            // Update the claim amount and last claim timestamp which is upperbound to the end
-            tokens_claimed_setter.set(tokens_purchased_by_user);
-            tokens_claimed_at_setter.set(last_token_claim_at);
-
-            // Amount to transfer will be all remaining tokens
-            tokens_purchased_by_user - tokens_claimed_by_user
-        } else {
-            // Amount to transfer will be based on how many have unlocked since the last claim
-            let time_since_last_claim = current_time - last_user_claim_timestamp;
-            let tokens_per_second_to_claim = ((tokens_purchased_by_user * U256::from(1e12)) / total_vesting_length_in_seconds) / U256::from(1e12);
-            let transfer_amount: U256 = time_since_last_claim * tokens_per_second_to_claim;
-            
-            // Update the total claimed by the user and the current timestamp
-            tokens_claimed_setter.set(tokens_claimed_by_user + transfer_amount);
-            tokens_claimed_at_setter.set(current_time);
-
-            transfer_amount
-        };
+        // Cap the supply at what has been sold so that withdrawn tokens can no longer be purchased
+        self.total_tokens_available.set(total_tokens_purchased);
 
-        // Log the amount of tokens received and distinguish between who paid and who is receiving the tokens
-        evm::log(TokensClaimed {
-            user,
+        // Log the withdrawal and conclude the transaction
+        evm::log(UnsoldTokensWithdrawn {
             recipient,
             amount
         });
 
-        // Transfer the unlocked tokens to the target recipient
-        match IERC20::new(self.token.get()).transfer(
-            self,
-            recipient,
-            amount
-        ) {
-            Ok(transfer_success) => if transfer_success { 
-                Ok(()) 
-            } else { 
-                Err(Errors::TransferFailed(TransferFailed {})) 
-            },
-            Err(_) => Err(Errors::TransferFailed(TransferFailed {}))
-        }
+        self.safe_transfer(self.token.get(), recipient, amount)
     }
-}
\ No newline at end of file
+
+    /// Allows the owner or an allowlist manager to add or remove an address from the purchase allowlist
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The address being added or removed
+    /// * `allowed` - Whether the address is allowed to purchase when the allowlist is enabled
+    pub fn set_allowlist(&mut self, user: Address, allowed: bool) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_ALLOWLIST_MANAGER)?;
+        self.update_allowlist(user, allowed)
+    }
+
+    /// Allows the owner or an allowlist manager to add or remove many addresses from the purchase allowlist in a single transaction
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - The addresses being added or removed
+    /// * `allowed` - Whether the addresses are allowed to purchase when the allowlist is enabled
+    pub fn set_allowlist_batch(&mut self, users: Vec<Address>, allowed: bool) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_ALLOWLIST_MANAGER)?;
+        for user in users {
+            self.update_allowlist(user, allowed)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether purchases are restricted to allowlisted addresses
+    pub fn allowlist_enabled(&self) -> bool {
+        self.allowlist_enabled.get()
+    }
+
+    /// Whether an address is on the purchase allowlist
+    pub fn is_allowlisted(&self, user: Address) -> bool {
+        self.allowlisted.get(user)
+    }
+
+    /// Time after purchase before any vested tokens are released
+    pub fn cliff_length_in_seconds(&self) -> U256 {
+        self.cliff_length_in_seconds.get()
+    }
+
+    /// Number of purchased tokens that the user could claim right now
+    pub fn claimable_amount(&self, user: Address) -> U256 {
+        self.claimable_tokens(user)
+    }
+
+    /// Basis points of each vested purchase released immediately at purchase
+    pub fn instant_unlock_bps(&self) -> U256 {
+        self.instant_unlock_bps.get()
+    }
+
+    /// Allows a user that tokenized their vesting to take back direct control of claims while they still own the NFT
+    pub fn disable_tokenized_vesting(&mut self) -> Result<(), Errors> {
+        if !self.vesting_tokenized.get(msg::sender()) {
+            return Err(Errors::NotTokenized(NotTokenized {}))
+        }
+
+        // A split position cannot be handed back since its shares may be held by different owners
+        self.validate_not_split(msg::sender())?;
+
+        // Only the holder of the NFT can give up the claim rights it carries
+        let nft_contract = self.user_nft_collection.get(msg::sender());
+        let nft_claim_token_id = self.nft_claim_token_id.get(msg::sender());
+        self.validate_sender_owns_nft(nft_contract, nft_claim_token_id)?;
+
+        self.vesting_tokenized.setter(msg::sender()).set(false);
+        self.user_nft_collection.setter(msg::sender()).set(Address::ZERO);
+        self.nft_claim_token_id.setter(msg::sender()).set(U256::ZERO);
+
+        // Log the vesting being disabled and conclude the transaction
+        evm::log(TokenizedVestingDisabled {
+            user: msg::sender(),
+            nft_token_id: nft_claim_token_id
+        });
+
+        Ok(())
+    }
+
+    /// Allows the owner or a config admin to change the price per token as long as nobody has purchased yet
+    ///
+    /// # Arguments
+    ///
+    /// * `new_price` - Price in the currency per token being purchased
+    pub fn set_price_per_token(&mut self, new_price: U256) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_price_per_token(new_price)?;
+        self.validate_no_purchases()?;
+
+        let old_price = self.price_per_token.get();
+        self.price_per_token.set(new_price);
+
+        evm::log(PriceUpdated {
+            old: old_price,
+            new: new_price
+        });
+
+        Ok(())
+    }
+
+    /// Full vesting position of a user returned as the tokens purchased, when they were purchased, the tokens claimed, when
+    /// they were last claimed and the token ID of the NFT controlling claims if tokenized
+    pub fn vesting_info(&self, user: Address) -> (U256, U256, U256, U256, U256) {
+        (
+            self.tokens_purchased.get(user),
+            self.tokens_purchased_at.get(user),
+            self.tokens_claimed.get(user),
+            self.tokens_claimed_at.get(user),
+            self.nft_claim_token_id.get(user)
+        )
+    }
+
+    /// Allows a buyer such as an OTC desk to purchase allocations for several recipients paying for all of them at once
+    ///
+    /// # Arguments
+    ///
+    /// * `recipients` - The addresses that will own each purchased allocation
+    /// * `amounts` - Number of whole tokens being purchased for the recipient at the same index
+    pub fn purchase_tokens_for(&mut self, recipients: Vec<Address>, amounts: Vec<U256>) -> Result<(), Errors> {
+        self.non_reentrant(|sale| sale.process_batch_purchase(recipients, amounts))
+    }
+
+    /// Allow a user to claim vested tokens to another address as long as it is active and not tokenized, returning the amount claimed
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient` - The address that will receive the claimed tokens such as a cold wallet
+    pub fn claim_tokens_to(&mut self, recipient: Address) -> Result<U256, Errors> {
+        self.validate_not_paused()?;
+        self.validate_address(recipient)?;
+
+        if self.vesting_tokenized.get(msg::sender()) {
+            return Err(Errors::AlreadyTokenized(AlreadyTokenized {}))
+        }
+
+        self.non_reentrant(|sale| sale.claim_tokens_from_user(msg::sender(), recipient, None))
+    }
+
+    /// Allow a user to claim vested tokens to a smart contract such as a staking vault and notify it by calling
+    /// `onTokensClaimed(user, amount, data)` once the tokens have arrived, returning the amount claimed. The whole claim
+    /// reverts if the hook fails and no hook is called when the recipient is not a smart contract
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient` - The address that will receive the claimed tokens and the callback
+    /// * `data` - Arbitrary data forwarded to the recipient in the callback
+    pub fn claim_tokens_with_callback(&mut self, recipient: Address, data: Bytes) -> Result<U256, Errors> {
+        self.validate_not_paused()?;
+        self.validate_address(recipient)?;
+
+        if self.vesting_tokenized.get(msg::sender()) {
+            return Err(Errors::AlreadyTokenized(AlreadyTokenized {}))
+        }
+
+        self.non_reentrant(|sale| {
+            let amount = sale.claim_tokens_from_user(msg::sender(), recipient, None)?;
+            if recipient.has_code() {
+                ITokensClaimedReceiver::new(recipient)
+                    .on_tokens_claimed(&mut *sale, msg::sender(), amount, data.0.into())
+                    .map_err(|_| Errors::ClaimCallbackFailed(ClaimCallbackFailed {}))?;
+            }
+
+            Ok(amount)
+        })
+    }
+
+    /// When vesting is not enabled, allow the purchaser of tokens to claim all of the unlocked tokens to another address,
+    /// returning the amount claimed
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient` - The address that will receive the claimed tokens such as a cold wallet
+    pub fn claim_unlocked_tokens_to(&mut self, recipient: Address) -> Result<U256, Errors> {
+        self.validate_not_paused()?;
+        self.validate_address(recipient)?;
+        self.non_reentrant(|sale| sale.claim_unlocked_tokens_from_user(msg::sender(), recipient))
+    }
+
+    /// Number of tokens that can still be purchased before the sale sells out
+    pub fn tokens_remaining(&self) -> Result<U256, Errors> {
+        self.total_tokens_available.get().checked_sub(self.total_tokens_purchased.get()).ok_or(Errors::MathOverflow(MathOverflow {}))
+    }
+
+    /// Allows the owner or a config admin to change the vesting length applied to future buyers while existing buyers keep their schedule
+    ///
+    /// # Arguments
+    ///
+    /// * `new_length` - The vesting length in seconds applied to users making their first purchase from now on
+    pub fn set_vesting_length(&mut self, new_length: U256) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+
+        // Switching vesting on or off would change which claim path existing buyers must use
+        let old_length = self.validate_vesting_enabled()?;
+        if new_length == U256::ZERO {
+            return Err(Errors::VestingNotEnabled(VestingNotEnabled {}))
+        }
+
+        self.validate_vesting_length(new_length)?;
+        self.validate_cliff_length(self.cliff_length_in_seconds.get(), new_length)?;
+        self.validate_release_interval(self.release_interval_in_seconds.get(), new_length)?;
+
+        self.total_vesting_length_in_seconds.set(new_length);
+
+        evm::log(VestingLengthUpdated {
+            old: old_length,
+            new: new_length
+        });
+
+        Ok(())
+    }
+
+    /// Vesting length in seconds applied to the tokens purchased by a user, which is shorter than the purchase terms if the
+    /// owner has accelerated their vesting
+    pub fn vesting_length_of(&self, user: Address) -> U256 {
+        let accelerated_vesting_length = self.accelerated_vesting_length.get(user);
+        if accelerated_vesting_length != U256::ZERO {
+            return accelerated_vesting_length
+        }
+
+        self.user_vesting_length.get(user)
+    }
+
+    /// Price per token in the currency and vesting length in seconds snapshotted when a user first purchased, which later
+    /// configuration changes do not affect
+    pub fn purchase_terms_of(&self, user: Address) -> (U256, U256) {
+        (self.user_purchase_price.get(user), self.user_vesting_length.get(user))
+    }
+
+    /// Allows the owner to let buyers that have not claimed anything get their payment back, for example if a sale is cancelled
+    pub fn enable_refunds(&mut self) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+
+        self.refunds_enabled.set(true);
+
+        evm::log(RefundsEnabled {});
+
+        Ok(())
+    }
+
+    /// Allows the owner to deposit the currency used to pay refunds since purchase proceeds are sent straight to the owner
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Amount of currency approved to the smart contract that is moved into the refund reserve
+    pub fn deposit_refund_reserve(&mut self, amount: U256) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        if amount == U256::ZERO {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        self.refund_reserve.set(self.refund_reserve.get() + amount);
+
+        let currency = self.currency.get();
+        evm::log(RefundReserveDeposited {
+            currency,
+            amount
+        });
+
+        self.safe_transfer_from(currency, msg::sender(), contract::address(), amount)
+    }
+
+    /// Allows the owner to deposit the alternate currency used to refund buyers that paid in it
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Amount of alternate currency approved to the smart contract that is moved into the refund reserve
+    pub fn deposit_refund_reserve_alt(&mut self, amount: U256) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        let currency_alt = self.currency_alt.get();
+        if currency_alt == Address::ZERO {
+            return Err(Errors::AltCurrencyNotConfigured(AltCurrencyNotConfigured {}))
+        }
+
+        if amount == U256::ZERO {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        self.refund_reserve_alt.set(self.refund_reserve_alt.get() + amount);
+
+        evm::log(RefundReserveDeposited {
+            currency: currency_alt,
+            amount
+        });
+
+        self.safe_transfer_from(currency_alt, msg::sender(), contract::address(), amount)
+    }
+
+    /// Allows a buyer that has not claimed any tokens to hand back their allocation and get their payment back once refunds
+    /// are enabled, returning the amount refunded in the primary and the alternate currency
+    pub fn refund(&mut self) -> Result<(U256, U256), Errors> {
+        self.validate_not_paused()?;
+        self.non_reentrant(|sale| sale.refund_user(msg::sender()))
+    }
+
+    /// Allows a buyer that has not claimed any tokens to cancel their purchase once refunds are enabled, freeing their
+    /// allocation for other buyers. This is the same operation as `refund` under the name integrators look for
+    pub fn cancel_purchase(&mut self) -> Result<(U256, U256), Errors> {
+        self.refund()
+    }
+
+    /// Whether buyers that have not claimed can get their payment back
+    pub fn refunds_enabled(&self) -> bool {
+        self.refunds_enabled.get()
+    }
+
+    /// Currency deposited by the owner that is available for refunds
+    pub fn refund_reserve(&self) -> U256 {
+        self.refund_reserve.get()
+    }
+
+    /// Alternate currency held by the smart contract that is available for refunds
+    pub fn refund_reserve_alt(&self) -> U256 {
+        self.refund_reserve_alt.get()
+    }
+
+    /// Allows the owner to stop accepting purchases before the end of the sale window, for example once a target is hit.
+    /// Claims are not affected
+    pub fn close_sale(&mut self) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_is_initialized()?;
+        if self.sale_closed.get() {
+            return Err(Errors::SaleIsClosed(SaleIsClosed {}))
+        }
+
+        self.sale_closed.set(true);
+
+        evm::log(SaleClosed {
+            totalPurchased: self.total_tokens_purchased.get()
+        });
+
+        Ok(())
+    }
+
+    /// Whether the owner has finalized the sale ahead of the end of the sale window
+    pub fn sale_closed(&self) -> bool {
+        self.sale_closed.get()
+    }
+
+    /// Allows a user that purchased tokens to split their remaining vesting across several NFTs so that each holder can claim
+    /// their share independently, for example to trade part of a position
+    ///
+    /// # Arguments
+    ///
+    /// * `nft_contract` - The approved NFT collection that every token belongs to
+    /// * `token_ids` - The tokens that can claim their share of vested tokens regardless of their future owners
+    /// * `amounts` - Number of remaining tokens assigned to the token at the same index which must add up to everything
+    /// not yet claimed
+    pub fn split_tokenized_vesting(
+        &mut self,
+        nft_contract: Address,
+        token_ids: Vec<U256>,
+        amounts: Vec<U256>
+    ) -> Result<(), Errors> {
+        let user = msg::sender();
+        self.validate_can_tokenize(user)?;
+        self.validate_nft_collection_approved(nft_contract)?;
+
+        if token_ids.len() != amounts.len() {
+            return Err(Errors::LengthMismatch(LengthMismatch {}))
+        }
+
+        if token_ids.is_empty() {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        // Record each share making sure that no NFT is used twice
+        let mut total_shares = U256::ZERO;
+        for (token_id, amount) in token_ids.into_iter().zip(amounts) {
+            if amount == U256::ZERO {
+                return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+            }
+
+            self.validate_token_id_not_blocked(token_id)?;
+            if self.nft_claim_shares.getter(user).get(token_id) != U256::ZERO {
+                return Err(Errors::InvalidSplit(InvalidSplit {}))
+            }
+
+            self.nft_claim_shares.setter(user).setter(token_id).set(amount);
+            self.nft_share_token_ids.setter(user).push(token_id);
+            total_shares += amount;
+
+            evm::log(TokenizedVestingEnabled {
+                user,
+                nft_token_id: token_id
+            });
+        }
+
+        // The shares must account for exactly what the user has left to claim
+        let tokens_claimed_by_user = self.tokens_claimed.get(user);
+        if total_shares != self.tokens_purchased.get(user) - tokens_claimed_by_user {
+            return Err(Errors::InvalidSplit(InvalidSplit {}))
+        }
+
+        self.any_position_tokenized.set(true);
+        self.vesting_tokenized.setter(user).set(true);
+        self.vesting_split.setter(user).set(true);
+        self.user_nft_collection.setter(user).set(nft_contract);
+        self.tokens_claimed_at_split.setter(user).set(tokens_claimed_by_user);
+
+        Ok(())
+    }
+
+    /// If a vesting position has been split, allow the owner of one of its NFTs to claim that share of the vested tokens,
+    /// returning the amount claimed
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens and split their position
+    /// * `token_id` - The token holding the share being claimed
+    pub fn claim_tokens_by_nft_share(&mut self, user: Address, token_id: U256) -> Result<U256, Errors> {
+        self.validate_not_paused()?;
+        if !self.vesting_split.get(user) {
+            return Err(Errors::NotTokenized(NotTokenized {}))
+        }
+
+        let nft_contract = self.user_nft_collection.get(user);
+        self.validate_sender_owns_nft(nft_contract, token_id)?;
+        let amount = self.non_reentrant(|sale| sale.claim_tokens_from_nft_share(user, token_id, msg::sender()))?;
+
+        // Attribute the claim to the holder of the NFT at the time of claiming
+        evm::log(NftClaim {
+            user,
+            tokenId: token_id,
+            claimant: msg::sender(),
+            amount
+        });
+
+        Ok(amount)
+    }
+
+    /// Remaining tokens assigned to an NFT when the position was split and how many of them it has already claimed
+    pub fn nft_claim_share(&self, user: Address, token_id: U256) -> (U256, U256) {
+        (
+            self.nft_claim_shares.getter(user).get(token_id),
+            self.nft_claim_shares_claimed.getter(user).get(token_id)
+        )
+    }
+
+    /// Number of tokens that the holder of an NFT with a share of a split position could claim right now
+    pub fn claimable_amount_by_nft_share(&self, user: Address, token_id: U256) -> U256 {
+        self.claimable_tokens_by_nft_share(user, token_id)
+    }
+
+    /// Whether the claims of a user are controlled by an NFT and the token ID of that NFT, which is only meaningful when
+    /// tokenized since zero is a valid token ID. The tokens holding the shares of a split position are listed by
+    /// `share_token_ids_of`
+    pub fn tokenization_of(&self, user: Address) -> (bool, U256) {
+        let tokenized = self.vesting_tokenized.get(user);
+        (tokenized, if tokenized { self.nft_claim_token_id.get(user) } else { U256::ZERO })
+    }
+
+    /// Token IDs of the NFTs holding a share of a split position in the order they were assigned or empty if the position
+    /// of the user is not split
+    pub fn share_token_ids_of(&self, user: Address) -> Vec<U256> {
+        let share_token_ids = self.nft_share_token_ids.get(user);
+        (0..share_token_ids.len()).filter_map(|index| share_token_ids.get(index)).collect()
+    }
+
+    /// Whether a user could enable tokenized vesting right now, mirroring the checks performed when enabling it
+    pub fn can_tokenize(&self, user: Address) -> bool {
+        self.validate_can_tokenize(user).is_ok()
+    }
+
+    /// Allows the owner to recover tokens sent to the smart contract by mistake without touching funds that belong to buyers
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The ERC20 token being recovered
+    /// * `recipient` - The address that will receive the recovered tokens
+    /// * `amount` - Number of tokens being recovered
+    pub fn rescue_tokens(&mut self, token: Address, recipient: Address, amount: U256) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_address(token)?;
+        self.validate_address(recipient)?;
+        if amount == U256::ZERO {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        // Only what the smart contract holds beyond the tokens still owed to buyers can be taken out
+        if token == self.token.get() {
+            let surplus = self.balance_of(token, contract::address())?.saturating_sub(self.total_outstanding());
+            if amount > surplus {
+                return Err(Errors::RescueExceedsSurplus(RescueExceedsSurplus {}))
+            }
+
+            // Rescued unsold tokens can no longer be purchased and whole token sales must still be able to sell out exactly
+            let unsold_rescued = amount.min(self.tokens_remaining()?);
+            if unsold_rescued != U256::ZERO {
+                let total_tokens_available = self.total_tokens_available.get() - unsold_rescued;
+                self.validate_even_supply(total_tokens_available, self.token_decimals())?;
+                self.total_tokens_available.set(total_tokens_available);
+            }
+        }
+
+        // The currency held by the smart contract is kept for buyers while refunds are open
+        if token == self.currency.get() {
+            if self.refunds_enabled.get() {
+                return Err(Errors::RefundsOwed(RefundsOwed {}))
+            }
+
+            self.refund_reserve.set(self.refund_reserve.get().saturating_sub(amount));
+        }
+
+        if token == self.currency_alt.get() && token != Address::ZERO {
+            if self.refunds_enabled.get() {
+                return Err(Errors::RefundsOwed(RefundsOwed {}))
+            }
+
+            self.refund_reserve_alt.set(self.refund_reserve_alt.get().saturating_sub(amount));
+        }
+
+        // Log the recovery and conclude the transaction
+        evm::log(TokensRescued {
+            token,
+            recipient,
+            amount
+        });
+
+        self.safe_transfer(token, recipient, amount)
+    }
+
+    /// Allows the owner or a config admin to cap the currency raised by the sale as long as nobody has purchased yet
+    ///
+    /// # Arguments
+    ///
+    /// * `max_currency_raised` - Most currency the sale will accept or zero if unlimited
+    pub fn set_max_currency_raised(&mut self, max_currency_raised: U256) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_no_purchases()?;
+
+        let old_max_currency_raised = self.max_currency_raised.get();
+        self.max_currency_raised.set(max_currency_raised);
+
+        evm::log(MaxCurrencyRaisedUpdated {
+            old: old_max_currency_raised,
+            new: max_currency_raised
+        });
+
+        Ok(())
+    }
+
+    /// Most currency the sale will accept or zero if unlimited
+    pub fn max_currency_raised(&self) -> U256 {
+        self.max_currency_raised.get()
+    }
+
+    /// Total currency paid for purchases net of refunds
+    pub fn total_currency_raised(&self) -> U256 {
+        self.total_currency_raised.get()
+    }
+
+    /// ID of the most recent purchase or zero if nobody has purchased, where IDs start at one and increase by one per purchase
+    pub fn last_purchase_id(&self) -> U256 {
+        self.purchase_counter.get()
+    }
+
+    /// Alias of `total_currency_raised` for reporting the running total of currency raised by the sale
+    pub fn total_raised(&self) -> U256 {
+        self.total_currency_raised()
+    }
+
+    /// Cost in the currency of purchasing a number of whole tokens, or base units when fractional purchases are enabled,
+    /// matching what a purchase of the same amount would pull
+    pub fn quote_cost(&self, amount: U256) -> Result<U256, Errors> {
+        self.cost_at_price(amount, self.price_per_token.get())
+    }
+
+    /// Cost in the alternate currency of purchasing a number of whole tokens, or base units when fractional purchases are
+    /// enabled, or zero if no alternate currency is accepted
+    pub fn quote_cost_alt(&self, amount: U256) -> Result<U256, Errors> {
+        self.cost_at_price(amount, self.price_per_token_alt.get())
+    }
+
+    /// Address of the alternate ERC20 payment currency or zero if only the primary currency is accepted
+    pub fn currency_alt(&self) -> Address {
+        self.currency_alt.get()
+    }
+
+    /// Price in the alternate currency per whole token being purchased
+    pub fn price_per_token_alt(&self) -> U256 {
+        self.price_per_token_alt.get()
+    }
+
+    /// Total alternate currency paid for purchases net of refunds
+    pub fn total_currency_alt_raised(&self) -> U256 {
+        self.total_currency_alt_raised.get()
+    }
+
+    /// Allows the owner or a config admin to approve or revoke an NFT collection that buyers can use to tokenize their vesting.
+    /// Revoking a collection does not affect positions that were already tokenized with it
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The ERC721 contract being approved or revoked
+    /// * `approved` - Whether buyers can tokenize their vesting with the collection
+    pub fn set_approved_nft_collection(&mut self, collection: Address, approved: bool) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_address(collection)?;
+        if approved && !collection.has_code() {
+            return Err(Errors::NotAContract(NotAContract {}))
+        }
+
+        self.approved_nft_collections.setter(collection).set(approved);
+
+        evm::log(NftCollectionApprovalUpdated {
+            collection,
+            approved
+        });
+
+        Ok(())
+    }
+
+    /// Whether buyers can tokenize their vesting with an NFT collection
+    pub fn is_approved_nft_collection(&self, collection: Address) -> bool {
+        self.approved_nft_collections.get(collection)
+    }
+
+    /// NFT collection that a user chose when tokenizing their vesting or zero if not tokenized
+    pub fn nft_collection_of(&self, user: Address) -> Address {
+        self.user_nft_collection.get(user)
+    }
+
+    /// Allow a user to claim only part of their vested tokens as long as it is active and not tokenized, for example to
+    /// manage when tokens are received. The rest stays claimable later
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Number of tokens to claim which cannot exceed what is currently claimable
+    pub fn claim_partial(&mut self, amount: U256) -> Result<U256, Errors> {
+        self.validate_not_paused()?;
+
+        if self.vesting_tokenized.get(msg::sender()) {
+            return Err(Errors::AlreadyTokenized(AlreadyTokenized {}))
+        }
+
+        self.non_reentrant(|sale| sale.claim_tokens_from_user(msg::sender(), msg::sender(), Some(amount)))
+    }
+
+    /// Timestamp from which the tokens of a user's first purchase start vesting, taking vesting from the end of the sale into
+    /// account, or zero if they never purchased, are not vesting or have claimed everything. Any cliff runs from this
+    /// timestamp while top ups vest from when they were bought
+    pub fn vesting_start(&self, user: Address) -> U256 {
+        if !self.has_open_vesting_position(user) {
+            return U256::ZERO
+        }
+
+        self.effective_vesting_start(user)
+    }
+
+    /// Timestamp from which all of a user's purchased tokens are claimable or zero if they never purchased, are not vesting
+    /// or have claimed everything
+    pub fn vesting_end(&self, user: Address) -> U256 {
+        if !self.has_open_vesting_position(user) {
+            return U256::ZERO
+        }
+
+        self.latest_vesting_start(user) + self.vesting_length_of(user)
+    }
+
+    /// Share of a user's vesting period that has elapsed in basis points for progress displays, regardless of the cliff,
+    /// release steps or how much has been claimed, with the lots of a topped up position weighted by their tokens. Zero if
+    /// they never purchased or are not vesting and the whole 10,000 once everything has vested or been claimed
+    pub fn vested_bps(&self, user: Address) -> U256 {
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        let user_vesting_length = self.vesting_length_of(user);
+        if tokens_purchased_by_user == U256::ZERO || user_vesting_length == U256::ZERO {
+            return U256::ZERO
+        }
+
+        // Everything can only have been claimed once it has vested and the timestamps of such positions are cleared
+        if self.tokens_claimed.get(user) == tokens_purchased_by_user {
+            return U256::from(BASIS_POINTS)
+        }
+
+        let current_time = U256::from(block::timestamp());
+        let elapsed_tokens = self.purchase_lots(user).into_iter().fold(U256::ZERO, |elapsed_tokens, (lot_tokens, vesting_start)| {
+            elapsed_tokens + lot_tokens * current_time.saturating_sub(vesting_start).min(user_vesting_length)
+        });
+        (elapsed_tokens * U256::from(BASIS_POINTS)) / (tokens_purchased_by_user * user_vesting_length)
+    }
+
+    /// Allows the owner to change where purchase proceeds are sent
+    ///
+    /// # Arguments
+    ///
+    /// * `new_treasury` - The address that will receive purchase proceeds
+    pub fn set_treasury(&mut self, new_treasury: Address) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_address(new_treasury)?;
+
+        let previous = self.treasury.get();
+        self.treasury.set(new_treasury);
+
+        evm::log(TreasuryUpdated {
+            previous,
+            next: new_treasury
+        });
+
+        Ok(())
+    }
+
+    /// Address receiving purchase proceeds
+    pub fn treasury(&self) -> Address {
+        self.treasury.get()
+    }
+
+    /// Basis points of each purchase cost paid to the fee recipient
+    pub fn fee_bps(&self) -> U256 {
+        self.fee_bps.get()
+    }
+
+    /// Platform operator receiving the fee taken from purchases
+    pub fn fee_recipient(&self) -> Address {
+        self.fee_recipient.get()
+    }
+
+    /// Whether a user can top up an existing allocation with further purchases, with each top up of a vesting position vesting
+    /// from when it was bought
+    pub fn allow_multiple_purchases(&self) -> bool {
+        self.allow_multiple_purchases.get()
+    }
+
+    /// Current phase of the sale where 0 is uninitialized, 1 is not started, 2 is active, 3 is sold out and 4 is ended or
+    /// closed by the owner
+    pub fn sale_phase(&self) -> u8 {
+        if !self.initialized.get() {
+            return SALE_PHASE_UNINITIALIZED
+        }
+
+        let current_time = U256::from(block::timestamp());
+        let sale_end = self.sale_end.get();
+        if self.sale_closed.get() || (sale_end != U256::ZERO && current_time > sale_end) {
+            return SALE_PHASE_ENDED
+        }
+
+        if self.total_tokens_purchased.get() >= self.total_tokens_available.get() {
+            return SALE_PHASE_SOLD_OUT
+        }
+
+        if current_time < self.sale_start.get() {
+            return SALE_PHASE_NOT_STARTED
+        }
+
+        SALE_PHASE_ACTIVE
+    }
+
+    /// Allows the owner to add more tokens to the sale, transferring them into the smart contract at the same time so that
+    /// every purchase stays fully backed
+    ///
+    /// # Arguments
+    ///
+    /// * `additional` - Number of tokens in base units approved to the smart contract and added to the supply
+    pub fn increase_total_available(&mut self, additional: U256) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_is_initialized()?;
+        if additional == U256::ZERO {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        let total_tokens_available = self.total_tokens_available.get().checked_add(additional).ok_or(Errors::MathOverflow(MathOverflow {}))?;
+        self.validate_even_supply(total_tokens_available, self.token_decimals())?;
+        self.total_tokens_available.set(total_tokens_available);
+
+        // Log the new supply and conclude the transaction
+        evm::log(SaleSupplyIncreased {
+            additional,
+            totalAvailable: total_tokens_available
+        });
+
+        self.safe_transfer_from(self.token.get(), msg::sender(), contract::address(), additional)
+    }
+
+    /// Allows a user that has claimed everything they purchased to clear the remaining bookkeeping of their position for a
+    /// gas refund. Claims already do this automatically once the last tokens are released
+    pub fn prune_position(&mut self) -> Result<(), Errors> {
+        let tokens_purchased_by_user = self.tokens_purchased.get(msg::sender());
+        if tokens_purchased_by_user == U256::ZERO {
+            return Err(Errors::NoTokensPurchased(NoTokensPurchased {}))
+        }
+
+        if self.tokens_claimed.get(msg::sender()) != tokens_purchased_by_user {
+            return Err(Errors::PositionNotFullyClaimed(PositionNotFullyClaimed {}))
+        }
+
+        self.prune_fully_claimed_position(msg::sender());
+
+        Ok(())
+    }
+
+    /// Allow the owner of several NFTs to claim the vested tokens of every tokenized position they control in one
+    /// transaction, returning the total amount claimed. Reverts if any of the positions is not controlled by the sender
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - The Ethereum wallet addresses of the users whose tokenized positions are being claimed
+    pub fn claim_tokens_by_nft_batch(&mut self, users: Vec<Address>) -> Result<U256, Errors> {
+        self.validate_not_paused()?;
+        if users.is_empty() {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        // Check the sender controls every position before anything is released
+        for user in users.iter() {
+            if !self.vesting_tokenized.get(*user) {
+                return Err(Errors::NotTokenized(NotTokenized {}))
+            }
+
+            self.validate_not_split(*user)?;
+            let nft_contract = self.user_nft_collection.get(*user);
+            self.validate_sender_owns_nft(nft_contract, self.nft_claim_token_id.get(*user))?;
+        }
+
+        self.non_reentrant(|sale| {
+            let mut total_claimed = U256::ZERO;
+            for user in users {
+                let nft_claim_token_id = sale.nft_claim_token_id.get(user);
+                let amount = sale.claim_tokens_from_user(user, msg::sender(), None)?;
+                total_claimed += amount;
+
+                // Attribute the claim to the holder of the NFT at the time of claiming
+                evm::log(NftClaim {
+                    user,
+                    tokenId: nft_claim_token_id,
+                    claimant: msg::sender(),
+                    amount
+                });
+            }
+
+            Ok(total_claimed)
+        })
+    }
+
+    /// Whether the smart contract has been initialized and is ready to use
+    pub fn is_initialized(&self) -> bool {
+        self.initialized.get()
+    }
+
+    /// Allows the owner or a config admin to make every buyer vest from the end of the sale rather than from their purchase so
+    /// that all cohorts share the same unlock curve, as long as nobody has purchased yet
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether vesting starts at the end of the sale which must be configured
+    pub fn set_vest_from_sale_end(&mut self, enabled: bool) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_no_purchases()?;
+        if enabled {
+            let _ = self.validate_vesting_enabled()?;
+            if self.sale_end.get() == U256::ZERO {
+                return Err(Errors::InvalidSaleWindow(InvalidSaleWindow {}))
+            }
+        }
+
+        self.vest_from_sale_end.set(enabled);
+
+        evm::log(VestFromSaleEndUpdated {
+            enabled
+        });
+
+        Ok(())
+    }
+
+    /// Whether every buyer vests from the end of the sale rather than their purchase
+    pub fn vest_from_sale_end(&self) -> bool {
+        self.vest_from_sale_end.get()
+    }
+
+    /// Allows the owner or a config admin to switch purchase amounts between whole tokens and base units of the token, so that
+    /// buyers can purchase fractions of a token, as long as nobody has purchased yet
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether purchase amounts are given in base units of the token
+    pub fn set_fractional_purchases(&mut self, enabled: bool) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_no_purchases()?;
+
+        self.fractional_purchases.set(enabled);
+        self.validate_even_supply(self.total_tokens_available.get(), self.token_decimals())?;
+
+        evm::log(FractionalPurchasesUpdated {
+            enabled
+        });
+
+        Ok(())
+    }
+
+    /// Whether purchase amounts are given in base units of the token rather than whole tokens
+    pub fn fractional_purchases(&self) -> bool {
+        self.fractional_purchases.get()
+    }
+
+    /// Allows the owner or a config admin to accept a currency that delivers less than was sent, such as one taking a fee on
+    /// transfer, by crediting buyers with the tokens paid for by the currency that actually arrived rather than reverting,
+    /// as long as nobody has purchased yet. Applies to single purchases in the primary currency
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether buyers are credited with what was actually received
+    pub fn set_credit_actual_received(&mut self, enabled: bool) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_no_purchases()?;
+
+        self.credit_actual_received.set(enabled);
+
+        evm::log(CreditActualReceivedUpdated {
+            enabled
+        });
+
+        Ok(())
+    }
+
+    /// Whether purchases in the primary currency credit the buyer with what was received rather than reverting on a shortfall
+    pub fn credit_actual_received(&self) -> bool {
+        self.credit_actual_received.get()
+    }
+
+    /// Allows the owner to freeze or unfreeze an address so that it cannot purchase, claim or receive claimed tokens, for
+    /// example for compliance reasons
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The address being frozen or unfrozen
+    /// * `blocked` - Whether the address is frozen
+    pub fn set_blacklist(&mut self, user: Address, blocked: bool) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_address(user)?;
+
+        self.blacklisted.setter(user).set(blocked);
+
+        evm::log(BlacklistUpdated {
+            user,
+            blocked
+        });
+
+        Ok(())
+    }
+
+    /// Whether an address has been frozen by the owner
+    pub fn is_blacklisted(&self, user: Address) -> bool {
+        self.blacklisted.get(user)
+    }
+
+    /// Allows the owner or a config admin to cap how many distinct addresses can take part in the sale as long as nobody has purchased yet
+    ///
+    /// # Arguments
+    ///
+    /// * `max_buyers` - Most distinct addresses that can hold a purchase or zero if unlimited
+    pub fn set_max_buyers(&mut self, max_buyers: U256) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_no_purchases()?;
+
+        let old_max_buyers = self.max_buyers.get();
+        self.max_buyers.set(max_buyers);
+
+        evm::log(MaxBuyersUpdated {
+            old: old_max_buyers,
+            new: max_buyers
+        });
+
+        Ok(())
+    }
+
+    /// Most distinct addresses that can hold a purchase or zero if unlimited
+    pub fn max_buyers(&self) -> U256 {
+        self.max_buyers.get()
+    }
+
+    /// Number of distinct addresses currently holding a purchase
+    pub fn unique_buyers(&self) -> U256 {
+        self.unique_buyers.get()
+    }
+
+    /// Allows the owner or a config admin to release vested tokens in steps, such as monthly, rather than continuously as long
+    /// as nobody has purchased yet
+    ///
+    /// # Arguments
+    ///
+    /// * `release_interval` - Length of each step in seconds which must divide the vesting length or zero for continuous release
+    pub fn set_release_interval(&mut self, release_interval: U256) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_no_purchases()?;
+        self.validate_release_interval(release_interval, self.total_vesting_length_in_seconds.get())?;
+
+        let old_release_interval = self.release_interval_in_seconds.get();
+        self.release_interval_in_seconds.set(release_interval);
+
+        evm::log(ReleaseIntervalUpdated {
+            old: old_release_interval,
+            new: release_interval
+        });
+
+        Ok(())
+    }
+
+    /// Length of each step in which vested tokens are released or zero if released continuously
+    pub fn release_interval_in_seconds(&self) -> U256 {
+        self.release_interval_in_seconds.get()
+    }
+
+    /// Seconds until the next step of a user's stepped vesting is released, for countdown displays, or zero if vesting is
+    /// continuous, they never purchased or their vesting has ended. Before the cliff this counts down to the first release
+    pub fn seconds_until_next_unlock(&self, user: Address) -> U256 {
+        let release_interval = self.release_interval_in_seconds.get();
+        let vesting_end = self.vesting_end(user);
+        if release_interval == U256::ZERO || vesting_end == U256::ZERO {
+            return U256::ZERO
+        }
+
+        // Every lot of a topped up position steps on its own schedule so the next release is the soonest across the lots
+        let current_time = U256::from(block::timestamp());
+        let user_vesting_length = self.vesting_length_of(user);
+        let cliff_length_in_seconds = self.cliff_length_in_seconds.get();
+        self.purchase_lots(user)
+            .into_iter()
+            .map(|(_, vesting_start)| {
+                seconds_until_next_step(current_time, vesting_start, cliff_length_in_seconds, release_interval, vesting_start + user_vesting_length)
+            })
+            .filter(|seconds| *seconds != U256::ZERO)
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Allows the owner or a config admin to reward referrers with a share of the cost of the purchases they refer, paid out
+    /// of the proceeds, as long as nobody has purchased yet
+    ///
+    /// # Arguments
+    ///
+    /// * `referral_bps` - Basis points of each referred purchase cost paid to the referrer which together with the fee
+    /// cannot exceed the whole cost
+    pub fn set_referral_bps(&mut self, referral_bps: U256) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_no_purchases()?;
+        if self.fee_bps.get() + referral_bps > U256::from(BASIS_POINTS) {
+            return Err(Errors::InvalidPercentage(InvalidPercentage {}))
+        }
+
+        let old_referral_bps = self.referral_bps.get();
+        self.referral_bps.set(referral_bps);
+
+        evm::log(ReferralBpsUpdated {
+            old: old_referral_bps,
+            new: referral_bps
+        });
+
+        Ok(())
+    }
+
+    /// Basis points of each referred purchase cost paid to the referrer
+    pub fn referral_bps(&self) -> U256 {
+        self.referral_bps.get()
+    }
+
+    /// Total currency spent on purchases referred by an address
+    pub fn referral_volume(&self, referrer: Address) -> U256 {
+        self.referral_volume.get(referrer)
+    }
+
+    /// Allows the owner or a config admin to limit how often a position can be claimed so that relayers cannot spam dust
+    /// claims, as long as nobody has purchased yet
+    ///
+    /// # Arguments
+    ///
+    /// * `min_claim_interval` - Shortest time in seconds allowed between two claims of a position or zero for no limit
+    pub fn set_min_claim_interval(&mut self, min_claim_interval: U256) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_no_purchases()?;
+
+        let old_min_claim_interval = self.min_claim_interval.get();
+        self.min_claim_interval.set(min_claim_interval);
+
+        evm::log(MinClaimIntervalUpdated {
+            old: old_min_claim_interval,
+            new: min_claim_interval
+        });
+
+        Ok(())
+    }
+
+    /// Shortest time allowed between two claims of a position or zero if unlimited
+    pub fn min_claim_interval(&self) -> U256 {
+        self.min_claim_interval.get()
+    }
+
+    /// Allows the owner or a config admin to set how long buyers have to claim once their tokens have fully vested before any
+    /// unclaimed tokens can be swept to the treasury, as long as nobody has purchased yet
+    ///
+    /// # Arguments
+    ///
+    /// * `claim_grace_period` - Time in seconds after the end of vesting or zero to never allow sweeping
+    pub fn set_claim_grace_period(&mut self, claim_grace_period: U256) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_no_purchases()?;
+
+        let old_claim_grace_period = self.claim_grace_period.get();
+        self.claim_grace_period.set(claim_grace_period);
+
+        evm::log(ClaimGracePeriodUpdated {
+            old: old_claim_grace_period,
+            new: claim_grace_period
+        });
+
+        Ok(())
+    }
+
+    /// Time after a position fully vests before the owner can sweep it or zero if sweeping is disabled
+    pub fn claim_grace_period(&self) -> U256 {
+        self.claim_grace_period.get()
+    }
+
+    /// Allows the owner to send the unclaimed tokens of a buyer that never came back to the treasury once the position has
+    /// fully vested and the claim grace period has passed. The position is marked as fully claimed
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user whose position is abandoned
+    pub fn sweep_abandoned(&mut self, user: Address) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.non_reentrant(|sale| sale.sweep_abandoned_position(user))
+    }
+
+    /// Version of the initialization applied to storage or zero if never initialized
+    pub fn init_version(&self) -> u8 {
+        self.init_version.get().to::<u8>()
+    }
+
+    /// Version of the storage layout written at initialization or zero if never initialized
+    pub fn storage_layout_version(&self) -> u8 {
+        self.storage_layout_version.get().to::<u8>()
+    }
+
+    /// Number of whole tokens, or base units when fractional purchases are enabled, that a budget in the currency can
+    /// purchase, rounding down so that `quote_cost` of the result never exceeds the budget
+    pub fn tokens_for_currency(&self, currency_amount: U256) -> Result<U256, Errors> {
+        let price_per_token = self.price_per_token.get();
+        if price_per_token == U256::ZERO {
+            return Ok(U256::ZERO)
+        }
+
+        if self.fractional_purchases.get() {
+            return Ok(currency_amount.checked_mul(self.token_unit()).ok_or(Errors::MathOverflow(MathOverflow {}))? / price_per_token)
+        }
+
+        Ok(currency_amount / price_per_token)
+    }
+
+    /// Allows anyone such as a relayer sponsoring gas to claim a user's unlocked tokens on their behalf, sending the tokens
+    /// to the user themselves, as long as the position is not tokenized. Returns the amount claimed
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens and receives them
+    pub fn claim_tokens_for(&mut self, user: Address) -> Result<U256, Errors> {
+        self.validate_not_paused()?;
+
+        if self.vesting_tokenized.get(user) {
+            return Err(Errors::AlreadyTokenized(AlreadyTokenized {}))
+        }
+
+        if self.total_vesting_length_in_seconds.get() == U256::ZERO {
+            return self.non_reentrant(|sale| sale.claim_unlocked_tokens_from_user(user, user))
+        }
+
+        self.non_reentrant(|sale| sale.claim_tokens_from_user(user, user, None))
+    }
+
+    /// Allows the owner to move the entire balance of the sold token to safety while the smart contract is paused, for
+    /// example after discovering an exploit. This is a break glass tool that permanently disables purchases and claims
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient` - The address receiving the sold tokens held by the smart contract
+    pub fn emergency_withdraw(&mut self, recipient: Address) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_address(recipient)?;
+        if !self.paused.get() {
+            return Err(Errors::ExpectedPause(ExpectedPause {}))
+        }
+
+        let token = self.token.get();
+        let amount = self.balance_of(token, contract::address())?;
+        self.drained.set(true);
+
+        // Log the withdrawal and conclude the transaction
+        evm::log(EmergencyWithdrawal {
+            recipient,
+            amount
+        });
+
+        self.safe_transfer(token, recipient, amount)
+    }
+
+    /// Whether the sold tokens have been moved out in an emergency which permanently disables purchases and claims
+    pub fn drained(&self) -> bool {
+        self.drained.get()
+    }
+
+    /// Number of purchased tokens that have not been claimed yet across every position, which is the least the smart
+    /// contract must hold to honour all claims
+    pub fn total_outstanding(&self) -> U256 {
+        self.total_tokens_purchased.get().saturating_sub(self.total_tokens_claimed.get())
+    }
+
+    /// Whether the smart contract holds enough of the sold token to cover every purchased token that has not been claimed
+    pub fn is_fully_backed(&self) -> bool {
+        let tokens_owed = self.total_outstanding();
+        match self.balance_of(self.token.get(), contract::address()) {
+            Ok(balance) => balance >= tokens_owed,
+            Err(_) => false
+        }
+    }
+
+    /// Dry run of a purchase by the sender returning its cost and whether it would currently be accepted, including the
+    /// supply, caps and per user rules, without changing any state
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Number of whole tokens that would be purchased
+    pub fn simulate_purchase(&self, amount: U256) -> (U256, bool) {
+        // A purchase whose cost cannot be worked out is never accepted
+        let cost = match self.quote_cost(amount) {
+            Ok(cost) => cost,
+            Err(_) => return (U256::ZERO, false)
+        };
+
+        let accepted = self.validate_purchases_open().is_ok() && self.validate_purchase(msg::sender(), amount, false).is_ok();
+        (cost, accepted)
+    }
+
+    /// Allows the owner to release a user's tokens sooner by bringing forward the end of their vesting. Vesting can only
+    /// ever be shortened and any cliff still applies
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user whose vesting is accelerated
+    /// * `new_end` - Timestamp from which all of the user's tokens will be claimable which must be earlier than the current end
+    pub fn accelerate_vesting(&mut self, user: Address, new_end: U256) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+
+        let old_end = self.vesting_end(user);
+        if old_end == U256::ZERO || self.tokens_claimed.get(user) == self.tokens_purchased.get(user) {
+            return Err(Errors::NoTokensVested(NoTokensVested {}))
+        }
+
+        // Every lot of a topped up position must still have some vesting left to shorten
+        let vesting_start = self.latest_vesting_start(user);
+        if new_end >= old_end || new_end <= vesting_start {
+            return Err(Errors::InvalidVestingEnd(InvalidVestingEnd {}))
+        }
+
+        // Shortening the vesting length of the user brings the end forward and releases tokens faster from now on. It is kept
+        // apart from the vesting length the position was purchased on
+        self.accelerated_vesting_length.setter(user).set(new_end - vesting_start);
+
+        evm::log(VestingAccelerated {
+            user,
+            oldEnd: old_end,
+            newEnd: new_end
+        });
+
+        Ok(())
+    }
+
+    /// Number of purchased tokens that have been released to a user including any instant unlock
+    pub fn claimed_by(&self, user: Address) -> U256 {
+        self.tokens_claimed.get(user)
+    }
+
+    /// Total number of purchased tokens released to buyers across all users
+    pub fn total_claimed(&self) -> U256 {
+        self.total_tokens_claimed.get()
+    }
+
+    /// Allows the owner or a config admin to stop an NFT token ID that is known to be compromised or reserved from being used
+    /// to tokenize vesting. Positions that were already tokenized with it are not affected
+    ///
+    /// # Arguments
+    ///
+    /// * `token_id` - The NFT token ID being blocked or unblocked
+    /// * `blocked` - Whether the token ID is prevented from tokenizing vesting
+    pub fn set_blocked_token_id(&mut self, token_id: U256, blocked: bool) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+
+        self.blocked_token_ids.setter(token_id).set(blocked);
+
+        evm::log(BlockedTokenIdUpdated {
+            tokenId: token_id,
+            blocked
+        });
+
+        Ok(())
+    }
+
+    /// Whether an NFT token ID is prevented from tokenizing vesting
+    pub fn is_blocked_token_id(&self, token_id: U256) -> bool {
+        self.blocked_token_ids.get(token_id)
+    }
+
+    /// Allows the owner or a config admin to set the NFT claim contract after deployment, for example when the wrapper NFT is
+    /// deployed after the sale, as long as no position has been tokenized yet
+    ///
+    /// # Arguments
+    ///
+    /// * `nft_claim` - Address of the ERC721 smart contract that can tokenize vesting
+    pub fn set_nft_claim(&mut self, nft_claim: Address) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        self.validate_address(nft_claim)?;
+        if !nft_claim.has_code() {
+            return Err(Errors::NotAContract(NotAContract {}))
+        }
+
+        if self.any_position_tokenized.get() {
+            return Err(Errors::PositionsAlreadyTokenized(PositionsAlreadyTokenized {}))
+        }
+
+        // The NFT claim contract is always an approved collection so swap the approval over
+        let previous = self.nft_claim.get();
+        self.approved_nft_collections.setter(previous).set(false);
+        self.approved_nft_collections.setter(nft_claim).set(true);
+        self.nft_claim.set(nft_claim);
+
+        evm::log(NftClaimUpdated {
+            previous,
+            next: nft_claim
+        });
+
+        Ok(())
+    }
+
+    /// Allows the owner or a config admin to switch the NFT collections used to tokenize vesting between ERC721 and ERC-1155,
+    /// as long as no position has been tokenized yet. For ERC-1155 any holder of the token ID can claim
+    ///
+    /// # Arguments
+    ///
+    /// * `is_1155` - Whether NFT collections are ERC-1155 rather than ERC721
+    pub fn set_nft_is_1155(&mut self, is_1155: bool) -> Result<(), Errors> {
+        self.validate_sender_has_role(ROLE_CONFIG_ADMIN)?;
+        if self.any_position_tokenized.get() {
+            return Err(Errors::PositionsAlreadyTokenized(PositionsAlreadyTokenized {}))
+        }
+
+        self.nft_is_1155.set(is_1155);
+
+        evm::log(NftStandardUpdated {
+            is1155: is_1155
+        });
+
+        Ok(())
+    }
+
+    /// Whether NFT collections used to tokenize vesting are ERC-1155 rather than ERC721
+    pub fn nft_is_1155(&self) -> bool {
+        self.nft_is_1155.get()
+    }
+
+    /// Allows the owner to move a buyer's position to a new address, for example when the buyer has lost access to their
+    /// wallet. Everything recorded against the old address moves across and the old address is left without a position
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The address currently holding the position which must not be tokenized
+    /// * `to` - The address receiving the position which must not already hold one
+    pub fn migrate_position(&mut self, from: Address, to: Address) -> Result<(), Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_address(to)?;
+        self.validate_not_blacklisted(to)?;
+
+        let tokens_purchased_by_user = self.tokens_purchased.get(from);
+        if tokens_purchased_by_user == U256::ZERO {
+            return Err(Errors::NoTokensPurchased(NoTokensPurchased {}))
+        }
+
+        if self.tokens_purchased.get(to) != U256::ZERO {
+            return Err(Errors::TokensAlreadyPurchased(TokensAlreadyPurchased {}))
+        }
+
+        // Claims of a tokenized position follow the NFT so there is nothing to move
+        if self.vesting_tokenized.get(from) {
+            return Err(Errors::AlreadyTokenized(AlreadyTokenized {}))
+        }
+
+        // Copy the position across
+        let tokens_purchased_at = self.tokens_purchased_at.get(from);
+        let tokens_claimed = self.tokens_claimed.get(from);
+        let tokens_claimed_at = self.tokens_claimed_at.get(from);
+        let user_vesting_length = self.user_vesting_length.get(from);
+        let accelerated_vesting_length = self.accelerated_vesting_length.get(from);
+        let user_purchase_price = self.user_purchase_price.get(from);
+        let tokens_purchased_with_alt = self.tokens_purchased_with_alt.get(from);
+        self.tokens_purchased.setter(to).set(tokens_purchased_by_user);
+        self.tokens_purchased_at.setter(to).set(tokens_purchased_at);
+        self.tokens_claimed.setter(to).set(tokens_claimed);
+        self.tokens_claimed_at.setter(to).set(tokens_claimed_at);
+        self.user_vesting_length.setter(to).set(user_vesting_length);
+        self.accelerated_vesting_length.setter(to).set(accelerated_vesting_length);
+        self.user_purchase_price.setter(to).set(user_purchase_price);
+        self.tokens_purchased_with_alt.setter(to).set(tokens_purchased_with_alt);
+        for index in 0..self.top_up_amounts.get(from).len() {
+            let top_up_amount = self.top_up_amounts.get(from).get(index).unwrap_or_default();
+            let top_up_purchased_at = self.top_ups_purchased_at.get(from).get(index).unwrap_or_default();
+            self.top_up_amounts.setter(to).push(top_up_amount);
+            self.top_ups_purchased_at.setter(to).push(top_up_purchased_at);
+        }
+
+        // Then clear the old address
+        self.tokens_purchased.setter(from).set(U256::ZERO);
+        self.tokens_purchased_at.setter(from).set(U256::ZERO);
+        self.tokens_claimed.setter(from).set(U256::ZERO);
+        self.tokens_claimed_at.setter(from).set(U256::ZERO);
+        self.user_vesting_length.setter(from).set(U256::ZERO);
+        self.accelerated_vesting_length.setter(from).set(U256::ZERO);
+        self.user_purchase_price.setter(from).set(U256::ZERO);
+        self.tokens_purchased_with_alt.setter(from).set(U256::ZERO);
+        self.top_up_amounts.setter(from).erase();
+        self.top_ups_purchased_at.setter(from).erase();
+
+        evm::log(PositionMigrated {
+            from,
+            to,
+            tokens: tokens_purchased_by_user
+        });
+
+        Ok(())
+    }
+
+    /// Allows the owner to push the currently unlocked tokens of several buyers to them rather than waiting for each buyer
+    /// to claim, returning the total amount released. Each user is claimed for through the claim matching whether the sale
+    /// vests. Tokenized positions and users with nothing to claim right now are skipped rather than failing the whole batch
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - The Ethereum wallet addresses of the users whose unlocked tokens are sent to them
+    pub fn distribute_claims(&mut self, users: Vec<Address>) -> Result<U256, Errors> {
+        self.validate_sender_is_owner()?;
+        self.validate_not_paused()?;
+
+        self.non_reentrant(|sale| {
+            let vesting_enabled = sale.total_vesting_length_in_seconds.get() != U256::ZERO;
+            let mut total_claimed = U256::ZERO;
+            for user in users {
+                if !sale.can_distribute_to(user) {
+                    continue
+                }
+
+                total_claimed += if vesting_enabled {
+                    sale.claim_tokens_from_user(user, user, None)?
+                } else {
+                    sale.claim_unlocked_tokens_from_user(user, user)?
+                };
+            }
+
+            Ok(total_claimed)
+        })
+    }
+
+    /// Run several calls to this smart contract in a single transaction as the sender, returning the output of each call.
+    /// If any call reverts the whole batch reverts with its error
+    ///
+    /// # Arguments
+    ///
+    /// * `calls` - ABI encoded calldata of each call including its selector, run in order
+    pub fn multicall(&mut self, calls: Vec<Bytes>) -> Result<Vec<Bytes>, Vec<u8>> {
+        let mut results = Vec::with_capacity(calls.len());
+        for call in calls {
+            if call.len() < 4 {
+                return Err(Errors::UnknownSelector(UnknownSelector {}).into())
+            }
+
+            let selector = u32::from_be_bytes([call[0], call[1], call[2], call[3]]);
+            match <Self as Router<Self>>::route(self, selector, &call[4..]) {
+                Some(Ok(output)) => results.push(Bytes(output)),
+                Some(Err(revert_data)) => return Err(revert_data),
+                None => return Err(Errors::UnknownSelector(UnknownSelector {}).into())
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Purchase limits a user is subject to right now returned as the most tokens they can still purchase paying in the
+    /// primary currency, whether they pass the allowlist (always true when it is disabled) and whether they already hold
+    /// a purchase. The amount is in the smallest unit of the token and is zero when purchases are not open or a sale rule
+    /// prevents any purchase
+    pub fn purchase_limits(&self, user: Address) -> (U256, bool, bool) {
+        let allowlisted = self.validate_is_allowlisted(user).is_ok();
+        let already_purchased = self.tokens_purchased.get(user) != U256::ZERO;
+
+        // Any purchase at all is only possible if the smallest one the sale accepts would go through, applying the same
+        // checks as `simulate_purchase`
+        let blocked = self.validate_purchases_open().is_err() || self.validate_purchase(user, U256::from(1), false).is_err();
+        if blocked {
+            return (U256::ZERO, allowlisted, already_purchased)
+        }
+
+        // Otherwise limited by the supply left and by whatever the raise cap still allows
+        let mut remaining = self.tokens_remaining().unwrap_or_default();
+        let max_currency_raised = self.max_currency_raised.get();
+        if max_currency_raised != U256::ZERO {
+            let budget = max_currency_raised.saturating_sub(self.total_currency_raised.get());
+            let tokens_within_cap = self.tokens_for_currency(budget)
+                .and_then(|amount| self.purchase_amount_in_base_units(amount))
+                .unwrap_or_default();
+            remaining = remaining.min(tokens_within_cap);
+        }
+
+        (remaining, allowlisted, already_purchased)
+    }
+
+}
+
+// Internal methods for `TokenSaleWithTokenizedVesting`
+impl TokenSaleWithTokenizedVesting {
+    /// Function ensuring we are initialized
+    pub fn validate_is_initialized(&self) -> Result<(), Errors> {
+        if !self.initialized.get() {
+            return Err(Errors::NotInitialized(NotInitialized {}))
+        }
+
+        self.validate_storage_layout_version()
+    }
+
+    /// Function ensuring storage was written with the layout this code expects so that an upgrade behind a proxy never
+    /// reads fields from the wrong slots
+    pub fn validate_storage_layout_version(&self) -> Result<(), Errors> {
+        if self.storage_layout_version.get() != U8::from(STORAGE_LAYOUT_VERSION) {
+            return Err(Errors::StorageLayoutMismatch(StorageLayoutMismatch {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring we are not already initialized
+    pub fn validate_initialization(&self) -> Result<(), Errors> {
+        if self.initialized.get() || self.init_version.get() != U8::ZERO {
+            return Err(Errors::AlreadyInitialized(AlreadyInitialized {}))
+        } 
+
+        Ok(())
+    }
+
+    /// Function ensuring purchases and claims have not been halted by the owner
+    pub fn validate_not_paused(&self) -> Result<(), Errors> {
+        if self.paused.get() {
+            return Err(Errors::EnforcedPause(EnforcedPause {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring the sold tokens have not been moved out by an emergency withdrawal
+    pub fn validate_not_drained(&self) -> Result<(), Errors> {
+        if self.drained.get() {
+            return Err(Errors::ContractDrained(ContractDrained {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function running the supplied logic with the reentrancy lock held, rejecting the call if the lock is already held
+    pub fn non_reentrant<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Errors>) -> Result<T, Errors> {
+        if self.locked.get() {
+            return Err(Errors::Reentrancy(Reentrancy {}))
+        }
+
+        self.locked.set(true);
+        let result = f(self);
+        self.locked.set(false);
+
+        result
+    }
+
+    /// Function ensuring the contract is initialized and purchases are neither halted nor outside the sale window
+    pub fn validate_purchases_open(&self) -> Result<(), Errors> {
+        self.validate_is_initialized()?;
+        self.validate_not_paused()?;
+        self.validate_not_drained()?;
+        self.validate_sale_is_open()
+    }
+
+    /// Function ensuring sender is owner of the smart contract (simple ownership)
+    pub fn validate_sender_is_owner(&self) -> Result<(), Errors> {
+        if msg::sender() != self.owner.get() {
+            return Err(Errors::OnlyOwner(OnlyOwner {}))
+        }
+        
+        Ok(())
+    }
+
+    /// Function ensuring sender is either the owner or has been granted the role by the owner
+    pub fn validate_sender_has_role(&self, role: u8) -> Result<(), Errors> {
+        let sender = msg::sender();
+        if sender == self.owner.get() || self.roles.get(sender).to::<u8>() & role != 0 {
+            return Ok(())
+        }
+
+        Err(Errors::MissingRole(MissingRole {}))
+    }
+
+    /// Function ensuring a role is made up only of known role bitflags
+    pub fn validate_role(&self, role: u8) -> Result<(), Errors> {
+        if role == 0 || role & !ALL_ROLES != 0 {
+            return Err(Errors::InvalidRole(InvalidRole {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring a zero price is not supplied to the smart contract
+    pub fn validate_price_per_token(&self, price_per_token: U256) -> Result<(), Errors> {
+        if price_per_token == U256::ZERO {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring that nobody has purchased tokens yet so that existing buyers are never affected by configuration changes
+    pub fn validate_no_purchases(&self) -> Result<(), Errors> {
+        if self.total_tokens_purchased.get() != U256::ZERO {
+            return Err(Errors::TokensAlreadyPurchased(TokensAlreadyPurchased {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring that a zero value is not supplied for an address
+    pub fn validate_address(&self, value: Address) -> Result<(), Errors> {
+        if value == Address::default() {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring that total number of tokens being sold is not zero
+    pub fn validate_total_tokens_for_sale(&self, total_tokens: U256) -> Result<(), Errors> {
+        if total_tokens == U256::ZERO {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring that when purchases are made in whole tokens the supply is made up of whole tokens, so that the
+    /// last buyer is never left unable to take a fractional remainder
+    pub fn validate_even_supply(&self, total_tokens: U256, token_decimals: u8) -> Result<(), Errors> {
+        if !self.fractional_purchases.get() && !is_whole_token_amount(total_tokens, token_decimals) {
+            return Err(Errors::UnevenSupply(UnevenSupply {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring that when vesting length is not zero, it is a sensible length for users of the smart contract
+    pub fn validate_vesting_length(&self, vesting_length: U256) -> Result<(), Errors> {
+        if vesting_length != U256::ZERO {
+            if vesting_length < U256::from(MIN_VESTING_LENGTH) {
+                return Err(Errors::VestingLengthTooShort(VestingLengthTooShort {}))
+            }
+    
+            if vesting_length > U256::from(MAX_VESTING_LENGTH) {
+                return Err(Errors::VestingLengthTooLong(VestingLengthTooLong {}))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring that when the allowlist is enabled, only allowlisted addresses can purchase
+    pub fn validate_is_allowlisted(&self, user: Address) -> Result<(), Errors> {
+        if self.allowlist_enabled.get() && !self.allowlisted.get(user) {
+            return Err(Errors::NotAllowlisted(NotAllowlisted {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring the decimals of the token being sold are within a range that can be safely scaled
+    pub fn validate_token_decimals(&self, token_decimals: u8) -> Result<(), Errors> {
+        if token_decimals > MAX_TOKEN_DECIMALS {
+            return Err(Errors::TooManyDecimals(TooManyDecimals {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function returning one whole token expressed in the smallest unit of the token being sold
+    pub fn token_unit(&self) -> U256 {
+        U256::from(10).pow(U256::from(self.token_decimals.get()))
+    }
+
+    /// Function returning how many base units of the token a purchase amount represents
+    ///
+    /// Whole token purchases are scaled up by the decimals of the token while fractional purchases are already in base units
+    pub fn purchase_amount_in_base_units(&self, amount: U256) -> Result<U256, Errors> {
+        if self.fractional_purchases.get() {
+            return Ok(amount)
+        }
+
+        amount.checked_mul(self.token_unit()).ok_or(Errors::MathOverflow(MathOverflow {}))
+    }
+
+    /// Function returning the cost of a purchase amount at a price per whole token
+    ///
+    /// Fractional purchases are charged pro rata and rounded up so that no part of a token is ever handed out for free
+    pub fn cost_at_price(&self, amount: U256, price_per_token: U256) -> Result<U256, Errors> {
+        let token_unit = if self.fractional_purchases.get() { self.token_unit() } else { U256::from(1) };
+        purchase_cost(amount, price_per_token, token_unit)
+    }
+
+    /// Function ensuring that when both bounds of the sale window are set, the sale ends after it starts
+    pub fn validate_sale_window(&self, sale_start: U256, sale_end: U256) -> Result<(), Errors> {
+        if sale_start != U256::ZERO && sale_end != U256::ZERO && sale_end <= sale_start {
+            return Err(Errors::InvalidSaleWindow(InvalidSaleWindow {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring the sale has not been closed and the current block falls within the sale window where a zero bound is unbounded
+    pub fn validate_sale_is_open(&self) -> Result<(), Errors> {
+        if self.sale_closed.get() {
+            return Err(Errors::SaleIsClosed(SaleIsClosed {}))
+        }
+
+        let current_time = U256::from(block::timestamp());
+
+        let sale_start = self.sale_start.get();
+        if sale_start != U256::ZERO && current_time < sale_start {
+            return Err(Errors::SaleNotStarted(SaleNotStarted {}))
+        }
+
+        let sale_end = self.sale_end.get();
+        if sale_end != U256::ZERO && current_time > sale_end {
+            return Err(Errors::SaleEnded(SaleEnded {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring that a cliff is only configured alongside vesting and ends before vesting does
+    pub fn validate_cliff_length(&self, cliff_length: U256, vesting_length: U256) -> Result<(), Errors> {
+        if cliff_length != U256::ZERO && cliff_length >= vesting_length {
+            return Err(Errors::InvalidCliffLength(InvalidCliffLength {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring that a release interval is only configured alongside vesting and splits it into whole steps
+    pub fn validate_release_interval(&self, release_interval: U256, vesting_length: U256) -> Result<(), Errors> {
+        if release_interval == U256::ZERO {
+            return Ok(())
+        }
+
+        if vesting_length == U256::ZERO || release_interval > vesting_length || vesting_length % release_interval != U256::ZERO {
+            return Err(Errors::InvalidReleaseInterval(InvalidReleaseInterval {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring an instant unlock is a valid percentage and only configured alongside vesting
+    pub fn validate_instant_unlock_bps(&self, instant_unlock_bps: U256, vesting_length: U256) -> Result<(), Errors> {
+        if instant_unlock_bps > U256::from(BASIS_POINTS) {
+            return Err(Errors::InvalidPercentage(InvalidPercentage {}))
+        }
+
+        if instant_unlock_bps != U256::ZERO && vesting_length == U256::ZERO {
+            return Err(Errors::InvalidPercentage(InvalidPercentage {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring an alternate currency, when configured, is a different token to the primary currency with a price
+    pub fn validate_alt_currency(&self, currency_alt: Address, price_per_token_alt: U256, currency: Address) -> Result<(), Errors> {
+        if currency_alt == Address::ZERO {
+            return Ok(())
+        }
+
+        if currency_alt == currency {
+            return Err(Errors::InvalidAltCurrency(InvalidAltCurrency {}))
+        }
+
+        self.validate_price_per_token(price_per_token_alt)
+    }
+
+    /// Function ensuring the smart contract already holds enough of the token being sold to back every token for sale
+    pub fn validate_prefunded(&self, token: Address, total_tokens_available: U256) -> Result<(), Errors> {
+        if self.balance_of(token, contract::address())? < total_tokens_available {
+            return Err(Errors::InsufficientBacking(InsufficientBacking {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring an address has not been frozen by the owner
+    pub fn validate_not_blacklisted(&self, user: Address) -> Result<(), Errors> {
+        if self.blacklisted.get(user) {
+            return Err(Errors::Blacklisted(Blacklisted {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring the purchase fee is a valid percentage with somewhere to send it
+    pub fn validate_fee(&self, fee_bps: U256, fee_recipient: Address) -> Result<(), Errors> {
+        if fee_bps > U256::from(BASIS_POINTS) {
+            return Err(Errors::InvalidPercentage(InvalidPercentage {}))
+        }
+
+        if fee_bps != U256::ZERO {
+            self.validate_address(fee_recipient)?;
+        }
+
+        Ok(())
+    }
+
+    /// Function returning how many of the given purchased tokens are released immediately at purchase
+    pub fn instant_unlock_tokens(&self, tokens: U256) -> U256 {
+        (tokens * self.instant_unlock_bps.get()) / U256::from(BASIS_POINTS)
+    }
+
+    /// Function ensuring that we only proceed if vesting is enabled returning the vesting length in seconds
+    pub fn validate_vesting_enabled(&self) -> Result<U256, Errors> {
+        let total_vesting_length_in_seconds = self.total_vesting_length_in_seconds.get();
+        if total_vesting_length_in_seconds == U256::ZERO {
+            return Err(Errors::VestingNotEnabled(VestingNotEnabled {}))
+        }
+
+        Ok(total_vesting_length_in_seconds)
+    }
+
+    /// Function ensuring the NFT contract is set and deployed when vesting is enabled so that tokenized vesting can work.
+    /// Without vesting there is nothing to tokenize so it can be left as zero
+    pub fn validate_nft_claim(&self, nft_claim: Address, vesting_length: U256) -> Result<(), Errors> {
+        if vesting_length == U256::ZERO {
+            return Ok(())
+        }
+
+        self.validate_address(nft_claim)?;
+        if !nft_claim.has_code() {
+            return Err(Errors::NotAContract(NotAContract {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring a user has a vesting position that is not tokenized and still has tokens left to claim
+    pub fn validate_can_tokenize(&self, user: Address) -> Result<(), Errors> {
+        let _ = self.validate_vesting_enabled()?;
+
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        if tokens_purchased_by_user == U256::ZERO {
+            return Err(Errors::NoTokensVested(NoTokensVested {}))
+        }
+
+        if self.vesting_tokenized.get(user) {
+            return Err(Errors::AlreadyTokenized(AlreadyTokenized {}))
+        }
+
+        // Check they have not claimed everything
+        if self.tokens_claimed.get(user) == tokens_purchased_by_user {
+            return Err(Errors::AllTokensClaimed(AllTokensClaimed {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring a tokenized vesting position is controlled by a single NFT rather than split across several
+    pub fn validate_not_split(&self, user: Address) -> Result<(), Errors> {
+        if self.vesting_split.get(user) {
+            return Err(Errors::PositionSplit(PositionSplit {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring an NFT collection has been approved by the owner for tokenizing vesting
+    pub fn validate_nft_collection_approved(&self, nft_contract: Address) -> Result<(), Errors> {
+        if !self.approved_nft_collections.get(nft_contract) {
+            return Err(Errors::NftCollectionNotApproved(NftCollectionNotApproved {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring an NFT token ID has not been blocked by the owner from tokenizing vesting
+    pub fn validate_token_id_not_blocked(&self, token_id: U256) -> Result<(), Errors> {
+        if self.blocked_token_ids.get(token_id) {
+            return Err(Errors::BlockedTokenId(BlockedTokenId {}))
+        }
+
+        Ok(())
+    }
+
+    /// Function ensuring msg.sender is the owner of a ERC721 token or holds a balance of an ERC-1155 token
+    pub fn validate_sender_owns_nft(&mut self, nft_contract: Address, token_id: U256) -> Result<(), Errors> {
+        if self.nft_is_1155.get() {
+            let balance = IERC1155::new(nft_contract).balance_of(&*self, msg::sender(), token_id).unwrap_or_default();
+            if balance == U256::ZERO {
+                return Err(Errors::OnlyOwner(OnlyOwner {}))
+            }
+
+            return Ok(())
+        }
+
+        let owner = match IERC721::new(nft_contract).owner_of(self, token_id) {
+            Ok(owner) => owner,
+            Err(_) => Address::default()
+        };
+
+        if owner != msg::sender() {
+            return Err(Errors::OnlyOwner(OnlyOwner {}))
+        }
+
+        Ok(())
+    }
+
+    /// Logic for recording a purchase of tokens by the sender, collecting payment and releasing any instant unlock
+    ///
+    /// Follows checks-effects-interactions: every check and storage write for the purchase is completed by `record_purchase`
+    /// before the first ERC20 call, and nothing after that point writes to storage or depends on what the calls return
+    /// beyond failing the whole transaction. The one exception is crediting what was actually received, which has to
+    /// measure the payment before the purchase can be sized and relies on the reentrancy lock instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Number of whole tokens being purchased
+    /// * `use_alt` - Whether the purchase is paid for in the alternate currency
+    /// * `referrer` - The address that referred the buyer if any
+    pub fn process_purchase(&mut self, amount: U256, use_alt: bool, referrer: Option<Address>) -> Result<(), Errors> {
+        // Checks: no need to proceed if purchases are not currently possible
+        self.validate_purchases_open()?;
+
+        if self.credit_actual_received.get() && !use_alt {
+            return self.process_purchase_crediting_received(amount, referrer)
+        }
+
+        // Effects: validate and record the purchase in full
+        let (cost, instant_unlock_amount) = self.record_purchase(msg::sender(), amount, use_alt)?;
+        let currency = if use_alt { self.currency_alt.get() } else { self.currency.get() };
+        if let Some(referrer) = referrer {
+            self.record_referral(referrer, cost)?;
+        }
+
+        // Interactions: take payment before releasing anything
+        self.collect_payment(msg::sender(), currency, cost, referrer)?;
+        self.release_instant_unlock(msg::sender(), instant_unlock_amount)
+    }
+
+    /// Logic for a purchase in the currency that credits the sender with the tokens paid for by the currency that actually
+    /// arrived, so that currencies taking a fee on transfer or rebasing currencies can be accepted
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Number of whole tokens requested which sets how much currency is pulled from the sender
+    /// * `referrer` - The address that referred the buyer if any
+    pub fn process_purchase_crediting_received(&mut self, amount: U256, referrer: Option<Address>) -> Result<(), Errors> {
+        // Effects: write the requested purchase so that storage is committed before the currency is called
+        let currency = self.currency.get();
+        let balance_before = self.balance_of(currency, contract::address())?;
+        let (requested_cost, requested_instant_unlock_amount, purchase_id) = self.write_purchase(msg::sender(), amount, false)?;
+
+        // Interactions: pull the payment into the smart contract and measure what arrived
+        self.safe_transfer_from(currency, msg::sender(), contract::address(), requested_cost)?;
+        let received = self.balance_of(currency, contract::address())?.saturating_sub(balance_before);
+
+        // Settle the purchase on the tokens that the received currency pays for, which is never more than requested
+        let credited_amount = self.tokens_for_currency(received)?.min(amount);
+        if credited_amount == U256::ZERO {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        let (cost, instant_unlock_amount) = if credited_amount == amount {
+            (requested_cost, requested_instant_unlock_amount)
+        } else {
+            self.reduce_purchase(msg::sender(), amount, credited_amount)?
+        };
+        self.log_purchase(msg::sender(), purchase_id, credited_amount, cost, false)?;
+        if let Some(referrer) = referrer {
+            self.record_referral(referrer, received)?;
+        }
+
+        // Forward everything received including any remainder too small to pay for more tokens
+        self.collect_payment(contract::address(), currency, received, referrer)?;
+        self.release_instant_unlock(msg::sender(), instant_unlock_amount)
+    }
+
+    /// Logic for recording purchases on behalf of several recipients, collecting the combined payment from the sender once
+    ///
+    /// # Arguments
+    ///
+    /// * `recipients` - The addresses that will own each purchased allocation
+    /// * `amounts` - Number of whole tokens being purchased for the recipient at the same index
+    pub fn process_batch_purchase(&mut self, recipients: Vec<Address>, amounts: Vec<U256>) -> Result<(), Errors> {
+        if recipients.len() != amounts.len() {
+            return Err(Errors::LengthMismatch(LengthMismatch {}))
+        }
+
+        if recipients.is_empty() {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        // No need to proceed if purchases are not currently possible
+        self.validate_purchases_open()?;
+
+        // Record every allocation under its recipient before any tokens move
+        let mut total_cost = U256::ZERO;
+        let mut instant_unlock_amounts = Vec::with_capacity(recipients.len());
+        for (recipient, amount) in recipients.iter().zip(amounts) {
+            self.validate_address(*recipient)?;
+            let (cost, instant_unlock_amount) = self.record_purchase(*recipient, amount, false)?;
+            total_cost += cost;
+            instant_unlock_amounts.push(instant_unlock_amount);
+        }
+
+        // Take payment for all recipients at once before releasing anything
+        self.collect_payment(msg::sender(), self.currency.get(), total_cost, None)?;
+
+        for (recipient, instant_unlock_amount) in recipients.into_iter().zip(instant_unlock_amounts) {
+            self.release_instant_unlock(recipient, instant_unlock_amount)?;
+        }
+
+        Ok(())
+    }
+
+    /// Logic for recording and logging a purchase of tokens against a user returning the cost and the amount unlocked instantly
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address that will own the purchased tokens
+    /// * `amount` - Number of whole tokens being purchased
+    /// * `use_alt` - Whether the purchase is paid for in the alternate currency
+    pub fn record_purchase(&mut self, user: Address, amount: U256, use_alt: bool) -> Result<(U256, U256), Errors> {
+        let (cost, instant_unlock_amount, purchase_id) = self.write_purchase(user, amount, use_alt)?;
+        self.log_purchase(user, purchase_id, amount, cost, use_alt)?;
+        Ok((cost, instant_unlock_amount))
+    }
+
+    /// Logic for writing a purchase of tokens against a user to storage returning the cost, the amount unlocked instantly
+    /// and the ID given to the purchase
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address that will own the purchased tokens
+    /// * `amount` - Number of whole tokens being purchased
+    /// * `use_alt` - Whether the purchase is paid for in the alternate currency
+    pub fn write_purchase(&mut self, user: Address, amount: U256, use_alt: bool) -> Result<(U256, U256, U256), Errors> {
+        let cost = self.validate_purchase(user, amount, use_alt)?;
+
+        let purchase_amount = self.purchase_amount_in_base_units(amount)?;
+        let total_tokens_purchased = self.total_tokens_purchased.get().checked_add(purchase_amount).ok_or(Errors::MathOverflow(MathOverflow {}))?;
+
+        // New buyers take up one of the places in the sale
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        if tokens_purchased_by_user == U256::ZERO {
+            self.unique_buyers.set(self.unique_buyers.get() + U256::from(1));
+        }
+
+        // Record how many tokens user is buying and when they bought it. Top ups of vesting positions are kept as separate lots
+        // vesting from when they were bought so that earlier lots carry on vesting from the first purchase
+        // Snapshot the vesting length and price on the first purchase so that later changes only apply to future buyers
+        let current_time = U256::from(block::timestamp());
+        if tokens_purchased_by_user == U256::ZERO {
+            self.user_vesting_length.setter(user).set(self.total_vesting_length_in_seconds.get());
+            self.user_purchase_price.setter(user).set(self.price_per_token.get());
+            self.tokens_purchased_at.setter(user).set(current_time);
+        } else if self.user_vesting_length.get(user) != U256::ZERO {
+            self.top_up_amounts.setter(user).push(purchase_amount);
+            self.top_ups_purchased_at.setter(user).push(current_time);
+        } else {
+            self.tokens_purchased_at.setter(user).set(current_time);
+        }
+
+        // Any portion of the purchase unlocked instantly is released straight away and counted as claimed. The last claim
+        // timestamp is left alone so that the instant unlock does not hold back the first claim by `min_claim_interval`
+        let instant_unlock_amount = self.instant_unlock_tokens(tokens_purchased_by_user + purchase_amount) - self.instant_unlock_tokens(tokens_purchased_by_user);
+        if instant_unlock_amount != U256::ZERO {
+            let tokens_claimed_by_user = self.tokens_claimed.get(user);
+            self.tokens_claimed.setter(user).set(tokens_claimed_by_user + instant_unlock_amount);
+            self.total_tokens_claimed.set(self.total_tokens_claimed.get() + instant_unlock_amount);
+        }
+
+        self.tokens_purchased.setter(user).set(tokens_purchased_by_user + purchase_amount);
+        self.total_tokens_purchased.set(total_tokens_purchased);
+
+        // Keep the takings of each currency apart so that refunds pay back what was paid
+        if use_alt {
+            let total_currency_alt_raised = self.total_currency_alt_raised.get().checked_add(cost).ok_or(Errors::MathOverflow(MathOverflow {}))?;
+            self.total_currency_alt_raised.set(total_currency_alt_raised);
+            let tokens_purchased_with_alt = self.tokens_purchased_with_alt.get(user);
+            self.tokens_purchased_with_alt.setter(user).set(tokens_purchased_with_alt + purchase_amount);
+        } else {
+            let total_currency_raised = self.total_currency_raised.get().checked_add(cost).ok_or(Errors::MathOverflow(MathOverflow {}))?;
+            self.total_currency_raised.set(total_currency_raised);
+        }
+
+        // Give every purchase a unique ID so that it can be tracked individually
+        let purchase_id = self.purchase_counter.get() + U256::from(1);
+        self.purchase_counter.set(purchase_id);
+
+        Ok((cost, instant_unlock_amount, purchase_id))
+    }
+
+    /// Logic for shrinking a purchase in the currency just written by `write_purchase` to the tokens that were paid for,
+    /// returning the cost and the amount unlocked instantly of what remains of the purchase
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address that owns the purchased tokens
+    /// * `amount` - Number of whole tokens that were written
+    /// * `credited_amount` - Number of whole tokens that were paid for which is less than `amount`
+    pub fn reduce_purchase(&mut self, user: Address, amount: U256, credited_amount: U256) -> Result<(U256, U256), Errors> {
+        let purchase_amount = self.purchase_amount_in_base_units(amount)?;
+        let credited_purchase_amount = self.purchase_amount_in_base_units(credited_amount)?;
+        let shortfall = purchase_amount - credited_purchase_amount;
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        let tokens_purchased_before = tokens_purchased_by_user - purchase_amount;
+
+        // A top up of a vesting position is the most recent lot while a first purchase is held by the position itself
+        if tokens_purchased_before != U256::ZERO && self.user_vesting_length.get(user) != U256::ZERO {
+            let top_ups = self.top_up_amounts.get(user).len();
+            if let Some(mut top_up_amount) = self.top_up_amounts.setter(user).setter(top_ups - 1) {
+                let top_up = top_up_amount.get();
+                top_up_amount.set(top_up - shortfall);
+            }
+        }
+
+        // Hand back the part of the instant unlock counted as claimed for tokens that were not paid for
+        let instant_unlock_amount = self.instant_unlock_tokens(tokens_purchased_before + credited_purchase_amount) - self.instant_unlock_tokens(tokens_purchased_before);
+        let instant_unlock_shortfall = self.instant_unlock_tokens(tokens_purchased_by_user) - self.instant_unlock_tokens(tokens_purchased_before) - instant_unlock_amount;
+        let tokens_claimed_by_user = self.tokens_claimed.get(user);
+        self.tokens_claimed.setter(user).set(tokens_claimed_by_user - instant_unlock_shortfall);
+        self.total_tokens_claimed.set(self.total_tokens_claimed.get() - instant_unlock_shortfall);
+
+        self.tokens_purchased.setter(user).set(tokens_purchased_by_user - shortfall);
+        self.total_tokens_purchased.set(self.total_tokens_purchased.get() - shortfall);
+
+        let cost = self.quote_cost(credited_amount)?;
+        let cost_shortfall = self.quote_cost(amount)? - cost;
+        self.total_currency_raised.set(self.total_currency_raised.get() - cost_shortfall);
+
+        Ok((cost, instant_unlock_amount))
+    }
+
+    /// Logic for logging a purchase written to storage and how far the sale has progressed
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address that owns the purchased tokens
+    /// * `purchase_id` - The ID given to the purchase
+    /// * `amount` - Number of whole tokens purchased
+    /// * `cost` - Cost of the purchase
+    /// * `use_alt` - Whether the purchase was paid for in the alternate currency
+    pub fn log_purchase(&self, user: Address, purchase_id: U256, amount: U256, cost: U256, use_alt: bool) -> Result<(), Errors> {
+        evm::log(TokensPurchased {
+            user,
+            purchaseId: purchase_id,
+            amount,
+            cost,
+            pricePerToken: if use_alt { self.price_per_token_alt.get() } else { self.price_per_token.get() },
+            purchasedAt: U256::from(block::timestamp())
+        });
+
+        evm::log(SaleProgress {
+            purchased: self.total_tokens_purchased.get(),
+            remaining: self.tokens_remaining()?
+        });
+
+        Ok(())
+    }
+
+    /// Logic for checking that a purchase by the sender on behalf of a user would be accepted without changing any state,
+    /// returning the cost of the purchase
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address that will own the purchased tokens
+    /// * `amount` - Number of whole tokens being purchased
+    pub fn validate_purchase(&self, user: Address, amount: U256, use_alt: bool) -> Result<U256, Errors> {
+        if use_alt && self.currency_alt.get() == Address::ZERO {
+            return Err(Errors::AltCurrencyNotConfigured(AltCurrencyNotConfigured {}))
+        }
+
+        self.validate_is_allowlisted(user)?;
+        self.validate_not_blacklisted(msg::sender())?;
+        self.validate_not_blacklisted(user)?;
+
+        // Top ups would otherwise hand the new tokens to whoever holds the NFT controlling the position
+        if self.vesting_tokenized.get(user) {
+            return Err(Errors::AlreadyTokenized(AlreadyTokenized {}))
+        }
+
+        // Check if global limit has been reached, scaling whole tokens up to the decimals of the token if needed
+        let purchase_amount = self.purchase_amount_in_base_units(amount)?;
+        if purchase_amount == U256::ZERO {
+            return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+        }
+
+        let total_tokens_purchased = self.total_tokens_purchased.get().checked_add(purchase_amount).ok_or(Errors::MathOverflow(MathOverflow {}))?;
+        if total_tokens_purchased > self.total_tokens_available.get() {
+            return Err(Errors::SoldOut(SoldOut {}))
+        }
+
+        // calculate cost, never handing out an allocation for free
+        let cost = if use_alt { self.quote_cost_alt(amount)? } else { self.quote_cost(amount)? };
+        if cost == U256::ZERO {
+            return Err(Errors::ZeroCost(ZeroCost {}))
+        }
+
+        // Check the funding target has not been reached which is denominated in the primary currency
+        let max_currency_raised = self.max_currency_raised.get();
+        if !use_alt && max_currency_raised != U256::ZERO {
+            let total_currency_raised = self.total_currency_raised.get().checked_add(cost).ok_or(Errors::MathOverflow(MathOverflow {}))?;
+            if total_currency_raised > max_currency_raised {
+                return Err(Errors::RaiseCapReached(RaiseCapReached {}))
+            }
+        }
+
+        // Top ups are only possible when the sale was configured to allow them
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        if tokens_purchased_by_user != U256::ZERO && !self.allow_multiple_purchases.get() {
+            return Err(Errors::OnlyOnePurchase(OnlyOnePurchase {}))
+        }
+
+        // Each top up of a vesting position vests from when it was bought so the number of lots claims add up is capped
+        if tokens_purchased_by_user != U256::ZERO
+            && self.user_vesting_length.get(user) != U256::ZERO
+            && self.top_up_amounts.get(user).len() >= MAX_TOP_UPS {
+            return Err(Errors::TooManyTopUps(TooManyTopUps {}))
+        }
+
+        // New buyers need a place in the sale if the number of buyers is capped
+        let max_buyers = self.max_buyers.get();
+        if tokens_purchased_by_user == U256::ZERO && max_buyers != U256::ZERO && self.unique_buyers.get() >= max_buyers {
+            return Err(Errors::BuyerCapReached(BuyerCapReached {}))
+        }
+
+        Ok(cost)
+    }
+
+    /// Logic for pulling the cost of a purchase from the payer, paying any fee to the fee recipient, any referral reward to
+    /// the referrer and the rest to the treasury
+    ///
+    /// Only reads storage so it must be called after the purchase has been recorded
+    ///
+    /// # Arguments
+    ///
+    /// * `payer` - The Ethereum wallet address paying for the purchase or this smart contract if it already holds the payment
+    /// * `currency` - The ERC20 the purchase is paid in
+    /// * `cost` - Total cost of the purchase in the currency
+    /// * `referrer` - The address that referred the buyer if any
+    pub fn collect_payment(
+        &mut self,
+        payer: Address,
+        currency: Address,
+        cost: U256,
+        referrer: Option<Address>
+    ) -> Result<(), Errors> {
+        // Settle the split and where it goes before making any external call
+        let referral_bps = if referrer.is_some() { self.referral_bps.get() } else { U256::ZERO };
+        let (fee, reward, proceeds) = split_payment(cost, self.fee_bps.get(), referral_bps)?;
+        let fee_recipient = self.fee_recipient.get();
+        let treasury = self.treasury.get();
+
+        if let Some(referrer) = referrer {
+            evm::log(ReferralRewarded {
+                referrer,
+                buyer: msg::sender(),
+                cost,
+                reward
+            });
+
+            if reward != U256::ZERO {
+                self.pay_out(currency, payer, referrer, reward)?;
+            }
+        }
+
+        if fee != U256::ZERO {
+            evm::log(FeeCollected {
+                recipient: fee_recipient,
+                amount: fee
+            });
+
+            self.pay_out(currency, payer, fee_recipient, fee)?;
+        }
+
+        if proceeds == U256::ZERO {
+            return Ok(())
+        }
+
+        self.pay_out(currency, payer, treasury, proceeds)
+    }
+
+    /// Logic for moving part of a payment to where it is owed, either straight from the buyer or out of currency the smart
+    /// contract has already received
+    ///
+    /// # Arguments
+    ///
+    /// * `currency` - The ERC20 the payment is made in
+    /// * `payer` - The address paying which is this smart contract if it already holds the payment
+    /// * `recipient` - The address receiving the currency
+    /// * `amount` - Amount of currency being paid
+    pub fn pay_out(&mut self, currency: Address, payer: Address, recipient: Address, amount: U256) -> Result<(), Errors> {
+        if payer == contract::address() {
+            return self.safe_transfer(currency, recipient, amount)
+        }
+
+        self.safe_transfer_from_exact(currency, payer, recipient, amount)
+    }
+
+    /// Logic for adding the cost of a referred purchase to the volume of its referrer
+    ///
+    /// # Arguments
+    ///
+    /// * `referrer` - The address that referred the buyer
+    /// * `cost` - Cost of the referred purchase in the currency
+    pub fn record_referral(&mut self, referrer: Address, cost: U256) -> Result<(), Errors> {
+        let referral_volume = self.referral_volume.get(referrer).checked_add(cost).ok_or(Errors::MathOverflow(MathOverflow {}))?;
+        self.referral_volume.setter(referrer).set(referral_volume);
+        Ok(())
+    }
+
+    /// Logic for sending a user the tokens unlocked instantly by a purchase
+    ///
+    /// The instant unlock is already counted as claimed by `record_purchase` so this only performs the transfer
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address that purchased the tokens
+    /// * `instant_unlock_amount` - Number of tokens unlocked by the purchase which may be zero
+    pub fn release_instant_unlock(&mut self, user: Address, instant_unlock_amount: U256) -> Result<(), Errors> {
+        if instant_unlock_amount == U256::ZERO {
+            return Ok(())
+        }
+
+        // Log the instant unlock and conclude the transaction
+        evm::log(TokensClaimed {
+            user,
+            recipient: user,
+            amount: instant_unlock_amount
+        });
+
+        self.safe_transfer(self.token.get(), user, instant_unlock_amount)
+    }
+
+    /// Function returning whether a user holds a vesting position with tokens left to claim. Fully claimed positions have
+    /// their timestamps cleared so nothing about their schedule can be derived from storage any more
+    pub fn has_open_vesting_position(&self, user: Address) -> bool {
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        tokens_purchased_by_user != U256::ZERO
+            && self.user_vesting_length.get(user) != U256::ZERO
+            && self.tokens_claimed.get(user) != tokens_purchased_by_user
+    }
+
+    /// Logic for working out when the vesting of a user starts which is when they first purchased unless every buyer vests
+    /// from the end of the sale, in which case it is the later of the two
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens
+    pub fn effective_vesting_start(&self, user: Address) -> U256 {
+        self.lot_vesting_start(self.tokens_purchased_at.get(user))
+    }
+
+    /// Logic for working out when tokens bought at a timestamp start vesting which is when they were bought unless every
+    /// buyer vests from the end of the sale, in which case it is the later of the two
+    ///
+    /// # Arguments
+    ///
+    /// * `purchased_at` - The timestamp of the purchase
+    pub fn lot_vesting_start(&self, purchased_at: U256) -> U256 {
+        if !self.vest_from_sale_end.get() {
+            return purchased_at
+        }
+
+        purchased_at.max(self.sale_end.get())
+    }
+
+    /// Logic for listing the lots making up the position of a user, oldest first, as the tokens in each lot and when the lot
+    /// starts vesting. The first purchase is the first lot and each top up of a vesting position is a lot of its own
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens
+    pub fn purchase_lots(&self, user: Address) -> Vec<(U256, U256)> {
+        let top_up_amounts = self.top_up_amounts.get(user);
+        let top_ups_purchased_at = self.top_ups_purchased_at.get(user);
+
+        let mut lots = vec![(self.tokens_purchased.get(user), self.effective_vesting_start(user))];
+        for index in 0..top_up_amounts.len() {
+            let amount = top_up_amounts.get(index).unwrap_or_default();
+            lots[0].0 -= amount;
+            lots.push((amount, self.lot_vesting_start(top_ups_purchased_at.get(index).unwrap_or_default())));
+        }
+
+        lots
+    }
+
+    /// Logic for working out when the most recent lot of a user starts vesting which is when their whole position has
+    /// vested one vesting length later
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens
+    pub fn latest_vesting_start(&self, user: Address) -> U256 {
+        let top_ups_purchased_at = self.top_ups_purchased_at.get(user);
+        match top_ups_purchased_at.len() {
+            0 => self.effective_vesting_start(user),
+            top_ups => self.lot_vesting_start(top_ups_purchased_at.get(top_ups - 1).unwrap_or_default())
+        }
+    }
+
+    /// Logic for adding up how many of the tokens purchased by a user have vested at a timestamp, with each lot vesting from
+    /// when it was bought and counting only its instant unlock until its own cliff has passed
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens
+    /// * `timestamp` - The timestamp at which vesting is measured
+    pub fn vested_tokens_at(&self, user: Address, timestamp: U256) -> U256 {
+        let total_vesting_length_in_seconds = self.vesting_length_of(user);
+        let cliff_length_in_seconds = self.cliff_length_in_seconds.get();
+        let release_interval_in_seconds = self.release_interval_in_seconds.get();
+
+        // The instant unlock of each lot is what the lot added to the instant unlock of the position when it was bought
+        let mut tokens_in_earlier_lots = U256::ZERO;
+        let mut total_vested_tokens = U256::ZERO;
+        for (lot_tokens, vesting_start) in self.purchase_lots(user) {
+            let instant_unlocked_tokens = self.instant_unlock_tokens(tokens_in_earlier_lots + lot_tokens) - self.instant_unlock_tokens(tokens_in_earlier_lots);
+            tokens_in_earlier_lots += lot_tokens;
+
+            total_vested_tokens += if timestamp < vesting_start + cliff_length_in_seconds {
+                instant_unlocked_tokens
+            } else {
+                vested_tokens(
+                    lot_tokens,
+                    instant_unlocked_tokens,
+                    timestamp - vesting_start,
+                    total_vesting_length_in_seconds,
+                    release_interval_in_seconds
+                )
+            };
+        }
+
+        total_vested_tokens
+    }
+
+    /// Logic for calculating how many purchased tokens a user can claim right now
+    ///
+    /// When vesting is enabled, nothing is claimable before the cliff and afterwards the tokens vested linearly since each lot
+    /// of the position started vesting that have not yet been claimed are released with everything remaining released at
+    /// the end of the most recent lot
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens
+    pub fn claimable_tokens(&self, user: Address) -> U256 {
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        let tokens_claimed_by_user = self.tokens_claimed.get(user);
+
+        // Without vesting, everything that has been purchased is unlocked
+        let total_vesting_length_in_seconds = self.vesting_length_of(user);
+        if total_vesting_length_in_seconds == U256::ZERO {
+            return tokens_purchased_by_user - tokens_claimed_by_user
+        }
+
+        if tokens_claimed_by_user == tokens_purchased_by_user {
+            return U256::ZERO
+        }
+
+        // Check whether the cliff of the first purchase has passed
+        let current_time = U256::from(block::timestamp());
+        if current_time < self.effective_vesting_start(user) + self.cliff_length_in_seconds.get() {
+            return U256::ZERO
+        }
+
+        // All remaining tokens are released once the most recent lot has vested, including in the exact second it ends. One
+        // second earlier the elapsed time of that lot is strictly less than the vesting length so the linear amount below is
+        // always short of the purchase and nothing can be released twice across the boundary
+        let last_token_claim_at = self.latest_vesting_start(user) + total_vesting_length_in_seconds;
+        if current_time >= last_token_claim_at {
+            return tokens_purchased_by_user - tokens_claimed_by_user
+        }
+
+        // Amount to release is everything vested across the lots of the position less what has already been claimed. The
+        // vested amount rounds down and is measured against everything already claimed, so claims made before the end plus
+        // the final claim add up to exactly the purchase and never more
+        self.vested_tokens_at(user, current_time).saturating_sub(tokens_claimed_by_user)
+    }
+
+    /// Logic for performing a claim of tokens if the tokens are vested, releasing everything vested that has not yet been
+    /// claimed unless a smaller amount is requested so that the total claimed by the end of vesting is the same however
+    /// often the user claims
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens
+    /// * `recipient` - The Ethereum wallet address which will receive unlocked tokens which can be different from the user
+    /// * `requested_amount` - Number of tokens to release out of what is claimable or `None` to release all of it
+    pub fn claim_tokens_from_user(
+        &mut self, 
+        user: Address, 
+        recipient: Address,
+        requested_amount: Option<U256>
+    ) -> Result<U256, Errors> {
+        // Check whether tokens are vested by anyone purchasing, pointing buyers of unvested sales to the unlocked claim
+        if self.total_vesting_length_in_seconds.get() == U256::ZERO {
+            return Err(Errors::UseUnlockedClaim(UseUnlockedClaim {}))
+        }
+
+        // Nothing can be claimed once the sold tokens have been moved out in an emergency
+        self.validate_not_drained()?;
+
+        // Frozen addresses can neither have their tokens claimed nor receive them
+        self.validate_not_blacklisted(user)?;
+        self.validate_not_blacklisted(recipient)?;
+
+        // Check whether the user purchased any tokens
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        if tokens_purchased_by_user == U256::ZERO {
+            return Err(Errors::NoTokensVested(NoTokensVested {}))
+        }
+
+        // Check they have not claimed everything
+        let tokens_claimed_by_user = self.tokens_claimed.get(user);
+        if tokens_claimed_by_user == tokens_purchased_by_user {
+            return Err(Errors::AllTokensClaimed(AllTokensClaimed {}))
+        }
+
+        // Nothing is released until the cliff has passed
+        let current_time = U256::from(block::timestamp());
+        if current_time < self.effective_vesting_start(user) + self.cliff_length_in_seconds.get() {
+            return Err(Errors::CliffNotReached(CliffNotReached {}))
+        }
+
+        // Discourage spamming a position with dust claims
+        if !claim_interval_elapsed(current_time, self.tokens_claimed_at.get(user), self.min_claim_interval.get()) {
+            return Err(Errors::ClaimTooSoon(ClaimTooSoon {}))
+        }
+
+        // Work out how much to release, never more than is currently claimable
+        let claimable_amount = self.claimable_tokens(user);
+        let amount = match requested_amount {
+            Some(amount) if amount == U256::ZERO => {
+                return Err(Errors::ZeroValueArgumentInjected(ZeroValueArgumentInjected {}))
+            },
+            Some(amount) if amount > claimable_amount => {
+                return Err(Errors::ClaimAmountTooHigh(ClaimAmountTooHigh {}))
+            },
+            Some(amount) => amount,
+            None => claimable_amount
+        };
+
+        // Update the claim amount and last claim timestamp
+        self.tokens_claimed.setter(user).set(tokens_claimed_by_user + amount);
+        self.tokens_claimed_at.setter(user).set(current_time);
+        self.total_tokens_claimed.set(self.total_tokens_claimed.get() + amount);
+        self.close_fully_claimed_position(user);
+
+        // Log the amount of tokens received and distinguish between who paid and who is receiving the tokens
+        evm::log(TokensClaimed {
+            user,
+            recipient,
+            amount
+        });
+
+        // Transfer the unlocked tokens to the target recipient
+        self.safe_transfer(self.token.get(), recipient, amount)?;
+
+        Ok(amount)
+    }
+
+    /// Logic for releasing all purchased tokens that have not been claimed when the sale has no vesting
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens
+    /// * `recipient` - The Ethereum wallet address which will receive the tokens which can be different from the user
+    pub fn claim_unlocked_tokens_from_user(
+        &mut self,
+        user: Address,
+        recipient: Address
+    ) -> Result<U256, Errors> {
+        // This function is only for token sales that have no vesting, pointing buyers of vested sales to the vested claim
+        if self.total_vesting_length_in_seconds.get() != U256::ZERO {
+            return Err(Errors::UseVestedClaim(UseVestedClaim {}))
+        }
+
+        // Nothing can be claimed once the sold tokens have been moved out in an emergency
+        self.validate_not_drained()?;
+
+        // Frozen addresses can neither have their tokens claimed nor receive them
+        self.validate_not_blacklisted(user)?;
+        self.validate_not_blacklisted(recipient)?;
+
+        let tokens_purchased = self.tokens_purchased.get(user);
+        if tokens_purchased == U256::ZERO {
+            return Err(Errors::NoTokensPurchased(NoTokensPurchased {}))
+        }
+
+        // Ensure the user has something left to claim since their last purchase
+        let tokens_claimed = self.tokens_claimed.get(user);
+        if tokens_claimed == tokens_purchased {
+            return Err(Errors::AllTokensClaimed(AllTokensClaimed {}))
+        }
+
+        // Record the claim in state
+        let amount = tokens_purchased - tokens_claimed;
+        self.tokens_claimed.setter(user).set(tokens_purchased);
+        self.total_tokens_claimed.set(self.total_tokens_claimed.get() + amount);
+        self.tokens_claimed_at.setter(user).set(U256::from(block::timestamp()));
+        self.close_fully_claimed_position(user);
+
+        // Log the amount of tokens sent and conclude the transaction
+        evm::log(TokensClaimed {
+            user,
+            recipient,
+            amount
+        });
+
+        // Send the recipient all the tokens that the user purchased and has not yet claimed
+        self.safe_transfer(self.token.get(), recipient, amount)?;
+
+        Ok(amount)
+    }
+
+    /// Logic for recording whether a user is on the purchase allowlist
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The address being added or removed
+    /// * `allowed` - Whether the address is allowed to purchase when the allowlist is enabled
+    pub fn update_allowlist(&mut self, user: Address, allowed: bool) -> Result<(), Errors> {
+        self.validate_address(user)?;
+        self.allowlisted.setter(user).set(allowed);
+
+        evm::log(AllowlistUpdated {
+            user,
+            allowed
+        });
+
+        Ok(())
+    }
+
+    /// Logic for transferring tokens held by the smart contract, tolerating tokens that do not return a value
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The address of the ERC20 being transferred
+    /// * `recipient` - The address receiving the tokens
+    /// * `amount` - Number of tokens being transferred
+    pub fn safe_transfer(&mut self, token: Address, recipient: Address, amount: U256) -> Result<(), Errors> {
+        let calldata = transferCall { to: recipient, amount }.abi_encode();
+        self.call_optional_return(token, &calldata)
+    }
+
+    /// Logic for pulling tokens that have been approved to the smart contract, tolerating tokens that do not return a value
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The address of the ERC20 being transferred
+    /// * `from` - The address the tokens are taken from
+    /// * `recipient` - The address receiving the tokens
+    /// * `amount` - Number of tokens being transferred
+    pub fn safe_transfer_from(
+        &mut self,
+        token: Address,
+        from: Address,
+        recipient: Address,
+        amount: U256
+    ) -> Result<(), Errors> {
+        let calldata = transferFromCall { from, to: recipient, amount }.abi_encode();
+        self.call_optional_return(token, &calldata)
+    }
+
+    /// Logic for pulling tokens that have been approved to the smart contract while checking that the recipient received the
+    /// full amount, rejecting tokens that charge a fee on transfer since the sale would silently be under paid
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The address of the ERC20 being transferred
+    /// * `from` - The address the tokens are taken from
+    /// * `recipient` - The address receiving the tokens
+    /// * `amount` - Number of tokens being transferred
+    pub fn safe_transfer_from_exact(
+        &mut self,
+        token: Address,
+        from: Address,
+        recipient: Address,
+        amount: U256
+    ) -> Result<(), Errors> {
+        // Moving tokens to the same address never changes its balance so there is nothing to measure
+        if from == recipient {
+            return self.safe_transfer_from(token, from, recipient, amount)
+        }
+
+        let balance_before = self.balance_of(token, recipient)?;
+        self.safe_transfer_from(token, from, recipient, amount)?;
+        let balance_after = self.balance_of(token, recipient)?;
+
+        if balance_after < balance_before + amount {
+            return Err(Errors::FeeOnTransferUnsupported(FeeOnTransferUnsupported {}))
+        }
+
+        Ok(())
+    }
+
+    /// Logic for reading the ERC20 balance of an account
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The address of the ERC20 being queried
+    /// * `account` - The address whose balance is returned
+    pub fn balance_of(&self, token: Address, account: Address) -> Result<U256, Errors> {
+        let calldata = balanceOfCall { account }.abi_encode();
+        let return_data = match call::static_call(self, token, &calldata) {
+            Ok(return_data) => return_data,
+            Err(_) => return Err(Errors::TransferFailed(TransferFailed {}))
+        };
+
+        match balanceOfCall::abi_decode_returns(&return_data, true) {
+            Ok(balance) => Ok(balance._0),
+            Err(_) => Err(Errors::TransferFailed(TransferFailed {}))
+        }
+    }
+
+    /// Logic for calling an ERC20 where an empty return from a contract counts as success and only an explicit `false` or
+    /// a revert counts as failure, in the same way as OpenZeppelin's SafeERC20 (USDT for example returns nothing)
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The address of the ERC20 being called
+    /// * `calldata` - The ABI encoded transfer call
+    pub fn call_optional_return(&mut self, token: Address, calldata: &[u8]) -> Result<(), Errors> {
+        let return_data = match call::call(Call::new_in(self), token, calldata) {
+            Ok(return_data) => return_data,
+            Err(_) => return Err(Errors::TransferFailed(TransferFailed {}))
+        };
+
+        let transfer_success = if return_data.is_empty() {
+            token.has_code()
+        } else {
+            matches!(transferCall::abi_decode_returns(&return_data, true), Ok(transferReturn { _0: true }))
+        };
+
+        if !transfer_success {
+            return Err(Errors::TransferFailed(TransferFailed {}))
+        }
+
+        Ok(())
+    }
+
+    /// Logic for refunding a user that has not claimed any tokens, returning their allocation to the pool of tokens available
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens and receives the refund
+    pub fn refund_user(&mut self, user: Address) -> Result<(U256, U256), Errors> {
+        if !self.refunds_enabled.get() {
+            return Err(Errors::RefundsNotEnabled(RefundsNotEnabled {}))
+        }
+
+        self.validate_not_blacklisted(user)?;
+
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        if tokens_purchased_by_user == U256::ZERO {
+            return Err(Errors::NoTokensPurchased(NoTokensPurchased {}))
+        }
+
+        if self.tokens_claimed.get(user) != U256::ZERO {
+            return Err(Errors::TokensAlreadyClaimed(TokensAlreadyClaimed {}))
+        }
+
+        // The holder of the NFT controls a tokenized position so the buyer can no longer give it up
+        if self.vesting_tokenized.get(user) {
+            return Err(Errors::AlreadyTokenized(AlreadyTokenized {}))
+        }
+
+        // Work out what the user paid in each currency from the price snapshotted at purchase, the alternate price being fixed
+        // at initialization
+        let tokens_purchased_with_alt = self.tokens_purchased_with_alt.get(user);
+        let amount = ((tokens_purchased_by_user - tokens_purchased_with_alt) * self.user_purchase_price.get(user)) / self.token_unit();
+        let amount_alt = (tokens_purchased_with_alt * self.price_per_token_alt.get()) / self.token_unit();
+        let refund_reserve = self.refund_reserve.get();
+        let refund_reserve_alt = self.refund_reserve_alt.get();
+        if amount > refund_reserve || amount_alt > refund_reserve_alt {
+            return Err(Errors::InsufficientRefundReserve(InsufficientRefundReserve {}))
+        }
+
+        // Clear the position and return the tokens to the pool
+        self.refund_reserve.set(refund_reserve - amount);
+        self.refund_reserve_alt.set(refund_reserve_alt - amount_alt);
+        self.total_currency_raised.set(self.total_currency_raised.get() - amount);
+        self.total_currency_alt_raised.set(self.total_currency_alt_raised.get() - amount_alt);
+        self.tokens_purchased_with_alt.setter(user).set(U256::ZERO);
+        self.total_tokens_purchased.set(self.total_tokens_purchased.get() - tokens_purchased_by_user);
+        self.unique_buyers.set(self.unique_buyers.get() - U256::from(1));
+        self.tokens_purchased.setter(user).set(U256::ZERO);
+        self.tokens_purchased_at.setter(user).set(U256::ZERO);
+        self.user_vesting_length.setter(user).set(U256::ZERO);
+        self.accelerated_vesting_length.setter(user).set(U256::ZERO);
+        self.user_purchase_price.setter(user).set(U256::ZERO);
+        self.top_up_amounts.setter(user).erase();
+        self.top_ups_purchased_at.setter(user).erase();
+
+        // Log the refund and conclude the transaction
+        evm::log(Refunded {
+            user,
+            tokens: tokens_purchased_by_user,
+            amount,
+            amountAlt: amount_alt
+        });
+
+        if amount != U256::ZERO {
+            self.safe_transfer(self.currency.get(), user, amount)?;
+        }
+
+        if amount_alt != U256::ZERO {
+            self.safe_transfer(self.currency_alt.get(), user, amount_alt)?;
+        }
+
+        Ok((amount, amount_alt))
+    }
+
+    /// Logic for calculating how many tokens the holder of an NFT with a share of a split position can claim right now
+    ///
+    /// Each share receives its pro-rata portion of everything the position has released since it was split, so that all
+    /// shares are fully claimable once vesting ends
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens and split their position
+    /// * `token_id` - The token holding the share
+    pub fn claimable_tokens_by_nft_share(&self, user: Address, token_id: U256) -> U256 {
+        let share = self.nft_claim_shares.getter(user).get(token_id);
+        if share == U256::ZERO {
+            return U256::ZERO
+        }
+
+        // Everything released by the position since the split regardless of which share claimed it
+        let tokens_claimed_at_split = self.tokens_claimed_at_split.get(user);
+        let released_tokens = self.tokens_claimed.get(user) + self.claimable_tokens(user) - tokens_claimed_at_split;
+        let remaining_tokens_at_split = self.tokens_purchased.get(user) - tokens_claimed_at_split;
+
+        let unlocked_share = (share * released_tokens) / remaining_tokens_at_split;
+        unlocked_share.saturating_sub(self.nft_claim_shares_claimed.getter(user).get(token_id))
+    }
+
+    /// Logic for performing a claim of the vested tokens belonging to one share of a split position
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens and split their position
+    /// * `token_id` - The token holding the share being claimed
+    /// * `recipient` - The Ethereum wallet address which will receive unlocked tokens
+    pub fn claim_tokens_from_nft_share(
+        &mut self,
+        user: Address,
+        token_id: U256,
+        recipient: Address
+    ) -> Result<U256, Errors> {
+        // Nothing can be claimed once the sold tokens have been moved out in an emergency
+        self.validate_not_drained()?;
+
+        // Frozen addresses can neither have their tokens claimed nor receive them
+        self.validate_not_blacklisted(user)?;
+        self.validate_not_blacklisted(recipient)?;
+
+        // Check the token holds a share that has not been fully claimed
+        let share = self.nft_claim_shares.getter(user).get(token_id);
+        if share == U256::ZERO {
+            return Err(Errors::NoTokensVested(NoTokensVested {}))
+        }
+
+        let share_claimed = self.nft_claim_shares_claimed.getter(user).get(token_id);
+        if share_claimed == share {
+            return Err(Errors::AllTokensClaimed(AllTokensClaimed {}))
+        }
+
+        // Nothing is released until the cliff has passed
+        let current_time = U256::from(block::timestamp());
+        if current_time < self.effective_vesting_start(user) + self.cliff_length_in_seconds.get() {
+            return Err(Errors::CliffNotReached(CliffNotReached {}))
+        }
+
+        // The shares of a position are rate limited together so that splitting a position does not multiply its claims
+        if !claim_interval_elapsed(current_time, self.tokens_claimed_at.get(user), self.min_claim_interval.get()) {
+            return Err(Errors::ClaimTooSoon(ClaimTooSoon {}))
+        }
+
+        // Update the claim amount of the share as well as the position
+        let amount = self.claimable_tokens_by_nft_share(user, token_id);
+        self.nft_claim_shares_claimed.setter(user).setter(token_id).set(share_claimed + amount);
+        let tokens_claimed_by_user = self.tokens_claimed.get(user);
+        self.tokens_claimed.setter(user).set(tokens_claimed_by_user + amount);
+        self.tokens_claimed_at.setter(user).set(current_time);
+        self.total_tokens_claimed.set(self.total_tokens_claimed.get() + amount);
+        self.close_fully_claimed_position(user);
+
+        // Log the amount of tokens received and distinguish between who paid and who is receiving the tokens
+        evm::log(TokensClaimed {
+            user,
+            recipient,
+            amount
+        });
+
+        self.safe_transfer(self.token.get(), recipient, amount)?;
+
+        Ok(amount)
+    }
+
+    /// Logic for approving the smart contract to spend the currency of the sender using an EIP-2612 permit
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Amount of currency being approved
+    /// * `deadline` - Timestamp after which the permit signature is no longer valid
+    /// * `v` - Recovery byte of the permit signature
+    /// * `r` - First 32 bytes of the permit signature
+    /// * `s` - Second 32 bytes of the permit signature
+    pub fn permit_currency(&mut self, value: U256, deadline: U256, v: u8, r: B256, s: B256) -> Result<(), Errors> {
+        let calldata = permitCall {
+            owner: msg::sender(),
+            spender: contract::address(),
+            value,
+            deadline,
+            v,
+            r,
+            s
+        }.abi_encode();
+
+        let currency = self.currency.get();
+        if call::call(Call::new_in(self), currency, &calldata).is_err() {
+            return Err(Errors::PermitFailed(PermitFailed {}))
+        }
+
+        Ok(())
+    }
+
+    /// Logic for validating and storing the configuration of the sale shared by both ways of initializing
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Every setting of the sale as described on `init`
+    pub fn initialize(&mut self, config: SaleConfig) -> Result<(), Errors> {
+        // Perform required validation
+        self.validate_initialization()?;
+        self.validate_price_per_token(config.price_per_token)?;
+        self.validate_address(config.token)?;
+        self.validate_address(config.currency)?;
+        self.validate_total_tokens_for_sale(config.total_tokens_available)?;
+        self.validate_vesting_length(config.total_vesting_length_in_seconds)?;
+        self.validate_nft_claim(config.nft_claim, config.total_vesting_length_in_seconds)?;
+        self.validate_token_decimals(config.token_decimals)?;
+        self.validate_even_supply(config.total_tokens_available, config.token_decimals)?;
+        self.validate_sale_window(config.sale_start, config.sale_end)?;
+        self.validate_cliff_length(config.cliff_length_in_seconds, config.total_vesting_length_in_seconds)?;
+        self.validate_instant_unlock_bps(config.instant_unlock_bps, config.total_vesting_length_in_seconds)?;
+        self.validate_fee(config.fee_bps, config.fee_recipient)?;
+        self.validate_alt_currency(config.currency_alt, config.price_per_token_alt, config.currency)?;
+        if config.require_prefunded {
+            self.validate_prefunded(config.token, config.total_tokens_available)?;
+        }
+
+        // Setup the smart contract by configuring storage
+        self.initialized.set(true);
+        self.init_version.set(U8::from(INIT_VERSION));
+        self.storage_layout_version.set(U8::from(STORAGE_LAYOUT_VERSION));
+        self.owner.set(msg::sender());
+        self.token.set(config.token);
+        self.token_decimals.set(U8::from(config.token_decimals));
+        self.currency.set(config.currency);
+        self.price_per_token.set(config.price_per_token);
+        self.total_tokens_available.set(config.total_tokens_available);
+        self.total_vesting_length_in_seconds.set(config.total_vesting_length_in_seconds);
+        self.nft_claim.set(config.nft_claim);
+        if config.nft_claim != Address::ZERO {
+            self.approved_nft_collections.setter(config.nft_claim).set(true);
+        }
+        self.sale_start.set(config.sale_start);
+        self.sale_end.set(config.sale_end);
+        self.allowlist_enabled.set(config.allowlist_enabled);
+        self.cliff_length_in_seconds.set(config.cliff_length_in_seconds);
+        self.instant_unlock_bps.set(config.instant_unlock_bps);
+        self.treasury.set(if config.treasury == Address::ZERO { msg::sender() } else { config.treasury });
+        self.fee_bps.set(config.fee_bps);
+        self.fee_recipient.set(config.fee_recipient);
+        self.allow_multiple_purchases.set(config.allow_multiple_purchases);
+        self.currency_alt.set(config.currency_alt);
+        if config.currency_alt != Address::ZERO {
+            self.price_per_token_alt.set(config.price_per_token_alt);
+        }
+
+        Ok(())
+    }
+
+    /// Logic for marking a position as complete when a claim has released the last of its tokens
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens
+    pub fn close_fully_claimed_position(&mut self, user: Address) {
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        if self.tokens_claimed.get(user) != tokens_purchased_by_user {
+            return
+        }
+
+        evm::log(PositionFullyClaimed {
+            user,
+            total: tokens_purchased_by_user
+        });
+
+        self.prune_fully_claimed_position(user);
+    }
+
+    /// Logic for clearing the timestamps and tokenization of a position once everything purchased has been claimed
+    ///
+    /// The purchased and claimed totals are kept so that the position still counts as purchased and cannot be claimed again
+    /// and the vesting length is kept as the terms the position was sold on while any acceleration is dropped
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens
+    pub fn prune_fully_claimed_position(&mut self, user: Address) {
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        if tokens_purchased_by_user == U256::ZERO || self.tokens_claimed.get(user) != tokens_purchased_by_user {
+            return
+        }
+
+        self.tokens_purchased_at.setter(user).set(U256::ZERO);
+        self.tokens_claimed_at.setter(user).set(U256::ZERO);
+        self.vesting_tokenized.setter(user).set(false);
+        self.nft_claim_token_id.setter(user).set(U256::ZERO);
+        self.user_nft_collection.setter(user).set(Address::ZERO);
+        self.vesting_split.setter(user).set(false);
+        self.tokens_claimed_at_split.setter(user).set(U256::ZERO);
+        self.nft_share_token_ids.setter(user).erase();
+        self.top_up_amounts.setter(user).erase();
+        self.top_ups_purchased_at.setter(user).erase();
+        self.accelerated_vesting_length.setter(user).set(U256::ZERO);
+    }
+
+    /// Logic for sweeping the unclaimed tokens of a fully vested position to the treasury once the claim grace period is over
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user whose position is abandoned
+    pub fn sweep_abandoned_position(&mut self, user: Address) -> Result<(), Errors> {
+        let claim_grace_period = self.claim_grace_period.get();
+        if claim_grace_period == U256::ZERO {
+            return Err(Errors::SweepingNotEnabled(SweepingNotEnabled {}))
+        }
+
+        let tokens_purchased_by_user = self.tokens_purchased.get(user);
+        if tokens_purchased_by_user == U256::ZERO {
+            return Err(Errors::NoTokensPurchased(NoTokensPurchased {}))
+        }
+
+        // Shares of a split position are claimed separately by each NFT so the position cannot be swept as a whole
+        self.validate_not_split(user)?;
+
+        let tokens_claimed_by_user = self.tokens_claimed.get(user);
+        if tokens_claimed_by_user == tokens_purchased_by_user {
+            return Err(Errors::AllTokensClaimed(AllTokensClaimed {}))
+        }
+
+        // Without vesting everything is claimable from the start of the position
+        let vesting_end = self.vesting_end(user);
+        let fully_vested_at = if vesting_end == U256::ZERO { self.effective_vesting_start(user) } else { vesting_end };
+
+        if U256::from(block::timestamp()) < fully_vested_at + claim_grace_period {
+            return Err(Errors::GracePeriodNotOver(GracePeriodNotOver {}))
+        }
+
+        // Count the remainder as claimed so that the position is closed
+        let amount = tokens_purchased_by_user - tokens_claimed_by_user;
+        self.tokens_claimed.setter(user).set(tokens_purchased_by_user);
+        self.tokens_claimed_at.setter(user).set(U256::from(block::timestamp()));
+        self.total_tokens_claimed.set(self.total_tokens_claimed.get() + amount);
+        self.prune_fully_claimed_position(user);
+
+        // Log the sweep and conclude the transaction
+        let treasury = self.treasury.get();
+        evm::log(AbandonedTokensSwept {
+            user,
+            treasury,
+            amount
+        });
+
+        self.safe_transfer(self.token.get(), treasury, amount)
+    }
+
+    /// Function returning whether a claim pushed to a user by the owner would go ahead, so that users that cannot be paid
+    /// are skipped before anything is written rather than failing the whole distribution
+    pub fn can_distribute_to(&self, user: Address) -> bool {
+        if self.vesting_tokenized.get(user) || self.blacklisted.get(user) || self.claimable_tokens(user) == U256::ZERO {
+            return false
+        }
+
+        // Only vested claims are rate limited
+        if self.total_vesting_length_in_seconds.get() == U256::ZERO {
+            return true
+        }
+
+        claim_interval_elapsed(U256::from(block::timestamp()), self.tokens_claimed_at.get(user), self.min_claim_interval.get())
+    }
+
+    /// Logic for handing control of a user's vesting position to an NFT
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Ethereum wallet address of the user that purchased tokens
+    /// * `nft_contract` - The approved NFT collection that the token belongs to
+    /// * `token_id` - The token that can claim vested tokens regardless of its future owner
+    pub fn tokenize_vesting(&mut self, user: Address, nft_contract: Address, token_id: U256) -> Result<(), Errors> {
+        // Validate whether it is possible to enable tokenized vesting
+        self.validate_can_tokenize(user)?;
+        self.validate_nft_collection_approved(nft_contract)?;
+        self.validate_token_id_not_blocked(token_id)?;
+
+        // Record the NFT that tokenized the vesting so that its owner can start claiming tokens
+        self.any_position_tokenized.set(true);
+        self.vesting_tokenized.setter(user).set(true);
+        self.user_nft_collection.setter(user).set(nft_contract);
+        self.nft_claim_token_id.setter(user).set(token_id);
+
+        // Log the vesting being enabled and conclude the transaction
+        evm::log(TokenizedVestingEnabled {
+            user,
+            nft_token_id: token_id
+        });
+
+        Ok(())
+    }
+}
+
+// Calculations shared by the methods above that do not touch storage
+/// Logic for calculating how many of an allocation have vested a given time after vesting started, counting any instant
+/// unlock as vested from the start and all of it as vested once the vesting length has passed
+///
+/// Multiplies before dividing so that small allocations over long vesting lengths do not round down to nothing
+///
+/// # Arguments
+///
+/// * `purchased` - Number of tokens in the allocation
+/// * `instant_unlocked` - Number of those tokens that unlocked instantly at purchase
+/// * `elapsed` - Seconds since vesting started
+/// * `vesting_length` - Seconds over which the allocation vests linearly
+/// * `release_interval` - Length of each step in which vested tokens are released or zero if released continuously
+fn vested_tokens(purchased: U256, instant_unlocked: U256, elapsed: U256, vesting_length: U256, release_interval: U256) -> U256 {
+    if vesting_length == U256::ZERO || elapsed >= vesting_length {
+        return purchased
+    }
+
+    // With stepped vesting only fully elapsed release intervals count
+    let mut counted = elapsed;
+    if release_interval != U256::ZERO {
+        counted -= counted % release_interval;
+    }
+
+    instant_unlocked + (((purchased - instant_unlocked) * counted) / vesting_length)
+}
+
+/// Logic for calculating the cost of a purchase amount at a price per whole token, rounding up when the amount is in base
+/// units so that no part of a token is ever handed out for free
+///
+/// # Arguments
+///
+/// * `amount` - Number of tokens being purchased in the unit given by `token_unit`
+/// * `price_per_token` - Price in the currency per whole token
+/// * `token_unit` - Number of units of `amount` in one whole token which is one for whole token purchases
+fn purchase_cost(amount: U256, price_per_token: U256, token_unit: U256) -> Result<U256, Errors> {
+    let cost = amount.checked_mul(price_per_token).ok_or(Errors::MathOverflow(MathOverflow {}))?;
+    if token_unit == U256::from(1) {
+        return Ok(cost)
+    }
+
+    Ok(cost.checked_add(token_unit - U256::from(1)).ok_or(Errors::MathOverflow(MathOverflow {}))? / token_unit)
+}
+
+/// Logic for counting down to the next release of a stepped vesting schedule, which is the next step boundary counted from
+/// the start of vesting but no earlier than the end of the cliff, with the last release landing on the end of vesting
+///
+/// # Arguments
+///
+/// * `current_time` - The timestamp being counted from
+/// * `vesting_start` - When the position starts vesting
+/// * `cliff_length` - Seconds after the start of vesting before anything is released
+/// * `release_interval` - Length of each step in which vested tokens are released which must not be zero
+/// * `vesting_end` - When everything in the position has vested
+fn seconds_until_next_step(
+    current_time: U256,
+    vesting_start: U256,
+    cliff_length: U256,
+    release_interval: U256,
+    vesting_end: U256
+) -> U256 {
+    if current_time >= vesting_end {
+        return U256::ZERO
+    }
+
+    let elapsed = current_time.saturating_sub(vesting_start);
+    let next_step = vesting_start + elapsed - (elapsed % release_interval) + release_interval;
+    next_step.max(vesting_start + cliff_length).min(vesting_end) - current_time
+}
+
+/// Logic for deciding whether a number of base units is an exact number of whole tokens
+///
+/// # Arguments
+///
+/// * `amount` - Number of base units of the token
+/// * `token_decimals` - Decimals of the token
+fn is_whole_token_amount(amount: U256, token_decimals: u8) -> bool {
+    amount % U256::from(10).pow(U256::from(token_decimals)) == U256::ZERO
+}
+
+/// Logic for splitting the cost of a purchase into the fee, the referral reward and the proceeds left for the treasury,
+/// rounding the fee and reward down so that the three always add up to the cost
+///
+/// # Arguments
+///
+/// * `cost` - Total cost of the purchase in the currency
+/// * `fee_bps` - Basis points of the cost paid as a fee
+/// * `referral_bps` - Basis points of the cost paid to the referrer or zero if the purchase was not referred
+fn split_payment(cost: U256, fee_bps: U256, referral_bps: U256) -> Result<(U256, U256, U256), Errors> {
+    let basis_points = U256::from(BASIS_POINTS);
+    let fee = cost.checked_mul(fee_bps).ok_or(Errors::MathOverflow(MathOverflow {}))? / basis_points;
+    let reward = cost.checked_mul(referral_bps).ok_or(Errors::MathOverflow(MathOverflow {}))? / basis_points;
+    let proceeds = cost
+        .checked_sub(fee)
+        .and_then(|remaining| remaining.checked_sub(reward))
+        .ok_or(Errors::MathOverflow(MathOverflow {}))?;
+
+    Ok((fee, reward, proceeds))
+}
+
+/// Logic for deciding whether enough time has passed since a position was last claimed for it to be claimed again
+///
+/// # Arguments
+///
+/// * `current_time` - The timestamp being checked
+/// * `tokens_claimed_at` - When the position was last claimed or zero if it never was
+/// * `min_claim_interval` - Seconds that must pass between claims or zero for no limit
+fn claim_interval_elapsed(current_time: U256, tokens_claimed_at: U256, min_claim_interval: U256) -> bool {
+    tokens_claimed_at == U256::ZERO || current_time.saturating_sub(tokens_claimed_at) >= min_claim_interval
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u(value: u64) -> U256 {
+        U256::from(value)
+    }
+
+    #[test]
+    fn vested_tokens_follow_the_linear_schedule() {
+        let length = u(1_000);
+        assert_eq!(vested_tokens(u(1_000), u(100), U256::ZERO, length, U256::ZERO), u(100));
+        assert_eq!(vested_tokens(u(1_000), u(100), u(250), length, U256::ZERO), u(325));
+        assert_eq!(vested_tokens(u(1_000), u(100), u(999), length, U256::ZERO), u(999));
+        assert_eq!(vested_tokens(u(1_000), u(100), u(2_000), length, U256::ZERO), u(1_000));
+
+        // Small allocations over long lengths still vest part way through
+        assert_eq!(vested_tokens(u(3), U256::ZERO, u(500), length, U256::ZERO), u(1));
+    }
+
+    #[test]
+    fn stepped_vesting_only_counts_whole_intervals() {
+        let length = u(1_000);
+        let interval = u(250);
+        assert_eq!(vested_tokens(u(1_000), U256::ZERO, u(249), length, interval), U256::ZERO);
+        assert_eq!(vested_tokens(u(1_000), U256::ZERO, u(250), length, interval), u(250));
+        assert_eq!(vested_tokens(u(1_000), U256::ZERO, u(740), length, interval), u(500));
+        assert_eq!(vested_tokens(u(1_000), U256::ZERO, u(1_000), length, interval), u(1_000));
+    }
+
+    #[test]
+    fn vesting_math_floors_once_without_a_precision_factor() {
+        let length = U256::from(MAX_VESTING_LENGTH);
+
+        // A single base unit stays locked until the very end of the longest vesting
+        assert_eq!(vested_tokens(u(1), U256::ZERO, length - u(1), length, U256::ZERO), U256::ZERO);
+        assert_eq!(vested_tokens(u(1), U256::ZERO, length, length, U256::ZERO), u(1));
+
+        // Allocations smaller than the vesting length still vest part way through rather than rounding to nothing
+        assert_eq!(vested_tokens(u(1_000), U256::ZERO, length / u(2), length, U256::ZERO), u(500));
+        assert_eq!(vested_tokens(u(7), U256::ZERO, U256::from(MIN_VESTING_LENGTH), U256::from(MIN_VESTING_LENGTH * 3), U256::ZERO), u(2));
+
+        // Supplies far beyond any real token keep full precision
+        let allocation = U256::from(10).pow(u(60)) + u(1);
+        assert_eq!(vested_tokens(allocation, U256::ZERO, u(1), length, U256::ZERO), allocation / length);
+        assert_eq!(vested_tokens(allocation, U256::ZERO, length - u(1), length, U256::ZERO), allocation * (length - u(1)) / length);
+    }
+
+    #[test]
+    fn without_vesting_everything_is_vested() {
+        assert_eq!(vested_tokens(u(1_000), U256::ZERO, U256::ZERO, U256::ZERO, U256::ZERO), u(1_000));
+    }
+
+    #[test]
+    fn claims_are_rate_limited_from_the_last_claim() {
+        // Positions that were never claimed and sales without a limit can always be claimed
+        assert!(claim_interval_elapsed(u(1_000), U256::ZERO, u(3_600)));
+        assert!(claim_interval_elapsed(u(1_000), u(1_000), U256::ZERO));
+
+        assert!(!claim_interval_elapsed(u(4_599), u(1_000), u(3_600)));
+        assert!(claim_interval_elapsed(u(4_600), u(1_000), u(3_600)));
+    }
+
+    #[test]
+    fn payment_split_adds_up_to_the_cost() {
+        // 2.5% fee and 1% referral reward
+        assert_eq!(split_payment(u(1_000_000), u(250), u(100)).ok(), Some((u(25_000), u(10_000), u(965_000))));
+
+        // Rounding down leaves any dust with the treasury
+        assert_eq!(split_payment(u(999), u(250), u(100)).ok(), Some((u(24), u(9), u(966))));
+
+        // No fee and no referral sends everything to the treasury
+        assert_eq!(split_payment(u(999), U256::ZERO, U256::ZERO).ok(), Some((U256::ZERO, U256::ZERO, u(999))));
+    }
+
+    #[test]
+    fn payment_split_reverts_instead_of_wrapping() {
+        assert!(matches!(split_payment(U256::MAX, u(250), U256::ZERO), Err(Errors::MathOverflow(_))));
+        assert!(matches!(split_payment(U256::MAX, U256::ZERO, u(100)), Err(Errors::MathOverflow(_))));
+
+        // Shares adding up to more than the cost cannot underflow the proceeds
+        assert!(matches!(split_payment(u(1_000), u(6_000), u(6_000)), Err(Errors::MathOverflow(_))));
+    }
+
+    #[test]
+    fn whole_token_purchases_cost_the_price_per_token() {
+        assert_eq!(purchase_cost(u(3), u(1_000), u(1)).ok(), Some(u(3_000)));
+    }
+
+    #[test]
+    fn fractional_purchases_round_the_cost_up() {
+        // Half a token of an 18 decimal token at 1000 per token
+        let token_unit = U256::from(10).pow(u(18));
+        assert_eq!(purchase_cost(token_unit / u(2), u(1_000), token_unit).ok(), Some(u(500)));
+
+        // A single base unit still costs something
+        assert_eq!(purchase_cost(u(1), u(1_000), token_unit).ok(), Some(u(1)));
+    }
+
+    #[test]
+    fn near_max_purchases_revert_instead_of_wrapping() {
+        assert!(matches!(purchase_cost(U256::MAX, u(2), u(1)), Err(Errors::MathOverflow(_))));
+        assert!(matches!(purchase_cost(U256::MAX / u(2) + u(1), u(2), u(1)), Err(Errors::MathOverflow(_))));
+
+        // Rounding up the largest representable cost would also overflow
+        assert!(matches!(purchase_cost(U256::MAX, u(1), u(1_000)), Err(Errors::MathOverflow(_))));
+        assert_eq!(purchase_cost(U256::MAX, u(1), u(1)).ok(), Some(U256::MAX));
+    }
+
+    #[test]
+    fn supply_must_be_whole_tokens() {
+        let token_unit = U256::from(10).pow(u(18));
+        assert!(is_whole_token_amount(token_unit * u(1_000), 18));
+        assert!(!is_whole_token_amount(token_unit * u(1_000) + u(1), 18));
+
+        // Rescuing part of a token from an even supply leaves it uneven
+        assert!(!is_whole_token_amount(token_unit * u(1_000) - token_unit / u(2), 18));
+
+        // Tokens without decimals are always whole
+        assert!(is_whole_token_amount(u(7), 0));
+    }
+
+    #[test]
+    fn countdown_runs_to_the_next_step() {
+        // Vesting from 1000 for 1000 seconds released every 100 seconds without a cliff
+        let (start, interval, end) = (u(1_000), u(100), u(2_000));
+        assert_eq!(seconds_until_next_step(u(1_000), start, U256::ZERO, interval, end), u(100));
+        assert_eq!(seconds_until_next_step(u(1_001), start, U256::ZERO, interval, end), u(99));
+        assert_eq!(seconds_until_next_step(u(1_150), start, U256::ZERO, interval, end), u(50));
+        assert_eq!(seconds_until_next_step(u(1_199), start, U256::ZERO, interval, end), u(1));
+        assert_eq!(seconds_until_next_step(u(1_200), start, U256::ZERO, interval, end), u(100));
+
+        // Before vesting starts the first step is a whole interval after the start
+        assert_eq!(seconds_until_next_step(u(900), start, U256::ZERO, interval, end), u(200));
+    }
+
+    #[test]
+    fn countdown_waits_for_the_cliff() {
+        // A 350 second cliff releases the first three steps together when it ends
+        let (start, cliff, interval, end) = (u(1_000), u(350), u(100), u(2_000));
+        assert_eq!(seconds_until_next_step(u(1_000), start, cliff, interval, end), u(350));
+        assert_eq!(seconds_until_next_step(u(1_250), start, cliff, interval, end), u(100));
+        assert_eq!(seconds_until_next_step(u(1_350), start, cliff, interval, end), u(50));
+
+        // A cliff shorter than a step changes nothing
+        assert_eq!(seconds_until_next_step(u(1_000), start, u(50), interval, end), u(100));
+    }
+
+    #[test]
+    fn countdown_stops_at_the_end_of_vesting() {
+        // The last step is cut short by the end of vesting
+        let (start, interval, end) = (u(1_000), u(300), u(2_000));
+        assert_eq!(seconds_until_next_step(u(1_950), start, U256::ZERO, interval, end), u(50));
+        assert_eq!(seconds_until_next_step(u(2_000), start, U256::ZERO, interval, end), U256::ZERO);
+        assert_eq!(seconds_until_next_step(u(5_000), start, U256::ZERO, interval, end), U256::ZERO);
+    }
+
+    #[test]
+    fn first_claim_after_an_instant_unlock_is_not_rate_limited() {
+        // A purchase with an instant unlock leaves the last claim timestamp at zero
+        assert!(claim_interval_elapsed(u(1_000), U256::ZERO, u(86_400)));
+
+        // A second claim straight after the first is held back until the interval passes
+        assert!(!claim_interval_elapsed(u(1_001), u(1_000), u(86_400)));
+        assert!(claim_interval_elapsed(u(87_400), u(1_000), u(86_400)));
+    }
+
+    /// Solidity interface exported for the sale
+    #[cfg(feature = "export-abi")]
+    fn abi() -> String {
+        struct Abi;
+        impl core::fmt::Display for Abi {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                <TokenSaleWithTokenizedVesting as stylus_sdk::abi::GenerateAbi>::fmt_abi(f)
+            }
+        }
+
+        Abi.to_string()
+    }
+
+    #[cfg(feature = "export-abi")]
+    #[test]
+    fn configuration_getters_are_exported() {
+        let abi = abi();
+        for getter in [
+            "function token() external view returns (address);",
+            "function currency() external view returns (address);",
+            "function pricePerToken() external view returns (uint256);",
+            "function totalTokensAvailable() external view returns (uint256);",
+            "function totalVestingLengthInSeconds() external view returns (uint256);",
+            "function nftClaim() external view returns (address);",
+            "function owner() external view returns (address);",
+            "function totalTokensPurchased() external view returns (uint256);"
+        ] {
+            assert!(abi.contains(getter), "missing `{getter}`");
+        }
+    }
+
+    #[cfg(feature = "export-abi")]
+    #[test]
+    fn initialization_state_is_exported() {
+        assert!(abi().contains("function isInitialized() external view returns (bool);"));
+    }
+
+    /// Tests driving the sale end to end against the in-memory host, which only exists when the host functions are not
+    /// stubbed out for exporting the ABI
+    #[cfg(not(feature = "export-abi"))]
+    mod sale {
+        use super::*;
+        use crate::test_host::{self as host, MockToken, ReceivedClaim, CONTRACT, NOW, SENDER};
+        use alloy_primitives::address;
+        use alloy_sol_types::SolValue;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use std::sync::MutexGuard;
+
+        const TOKEN: Address = address!("0000000000000000000000000000000000000701");
+        const CURRENCY: Address = address!("0000000000000000000000000000000000000702");
+        const NFT: Address = address!("0000000000000000000000000000000000000703");
+        const TREASURY: Address = address!("0000000000000000000000000000000000000704");
+        const OTHER_TOKEN: Address = address!("0000000000000000000000000000000000000705");
+        const OTHER_NFT: Address = address!("0000000000000000000000000000000000000706");
+        const ALICE: Address = address!("0000000000000000000000000000000000000a11");
+        const BOB: Address = address!("0000000000000000000000000000000000000b0b");
+
+        /// Calldata of the sale's own methods for batching through `multicall`
+        mod calls {
+            alloy_sol_types::sol! {
+                function purchaseTokens(uint256 amount) external;
+                function enableTokenizedVesting(address nft_contract, uint256 token_id) external;
+                function totalTokensPurchased() external view returns (uint256);
+                function nftClaim() external view returns (address);
+            }
+        }
+
+        const PRICE: u64 = 1_000;
+        const SUPPLY: u64 = 1_000;
+        const VESTING: u64 = 1_000_000;
+
+        /// Currency held by the sender before buying anything
+        const BUDGET: u64 = 1_000_000_000;
+
+        /// Amount of the sold token in base units for a number of whole tokens
+        fn tokens(whole: u64) -> U256 {
+            U256::from(whole) * U256::from(10).pow(u(18))
+        }
+
+        fn config() -> SaleConfig {
+            SaleConfig {
+                token: TOKEN,
+                currency: CURRENCY,
+                price_per_token: u(PRICE),
+                total_tokens_available: tokens(SUPPLY),
+                total_vesting_length_in_seconds: U256::ZERO,
+                nft_claim: Address::ZERO,
+                token_decimals: 18,
+                sale_start: U256::ZERO,
+                sale_end: U256::ZERO,
+                allowlist_enabled: false,
+                cliff_length_in_seconds: U256::ZERO,
+                instant_unlock_bps: U256::ZERO,
+                treasury: TREASURY,
+                fee_bps: U256::ZERO,
+                fee_recipient: Address::ZERO,
+                allow_multiple_purchases: false,
+                currency_alt: Address::ZERO,
+                price_per_token_alt: U256::ZERO,
+                require_prefunded: false
+            }
+        }
+
+        fn vesting_config() -> SaleConfig {
+            SaleConfig {
+                total_vesting_length_in_seconds: u(VESTING),
+                nft_claim: NFT,
+                ..config()
+            }
+        }
+
+        /// Runs a call as a transaction of its own, rolling back everything it wrote when it fails as the chain would. The
+        /// sale is replaced too since it caches the fields it has read
+        fn transact<T, E>(
+            sale: &mut TokenSaleWithTokenizedVesting,
+            call: impl FnOnce(&mut TokenSaleWithTokenizedVesting) -> Result<T, E>
+        ) -> Result<T, E> {
+            let snapshot = host::snapshot();
+            let result = call(sale);
+            if result.is_err() {
+                host::restore(snapshot);
+                *sale = unsafe { TokenSaleWithTokenizedVesting::new(U256::ZERO, 0) };
+            }
+            result
+        }
+
+        /// Moves a position back in time as though it was purchased a number of seconds ago
+        fn vest_for(sale: &mut TokenSaleWithTokenizedVesting, user: Address, seconds: u64) {
+            sale.tokens_purchased_at.setter(user).set(u(NOW - seconds));
+        }
+
+        /// Moves one top up of a position back in time as though it was bought a number of seconds ago
+        fn top_up_vest_for(sale: &mut TokenSaleWithTokenizedVesting, user: Address, index: usize, seconds: u64) {
+            if let Some(mut purchased_at) = sale.top_ups_purchased_at.setter(user).setter(index) {
+                purchased_at.set(u(NOW - seconds));
+            }
+        }
+
+        /// Deploys the token, the currency and an NFT collection, funds the sale and the sender and initializes the sale,
+        /// returning a guard that must be held for the rest of the test
+        fn deploy(config: SaleConfig) -> (MutexGuard<'static, ()>, TokenSaleWithTokenizedVesting) {
+            let (guard, mut sale) = uninitialized(config.total_tokens_available);
+            assert!(sale.init_with_config(config).is_ok());
+            (guard, sale)
+        }
+
+        /// Deploys and funds everything like `deploy` but leaves the sale to be initialized by the test
+        fn uninitialized(supply: U256) -> (MutexGuard<'static, ()>, TokenSaleWithTokenizedVesting) {
+            let guard = host::reset();
+            host::deploy_token(TOKEN, MockToken::default());
+            host::deploy_token(CURRENCY, MockToken::default());
+            host::deploy_nft(NFT);
+            host::mint(TOKEN, CONTRACT, supply);
+            host::mint(CURRENCY, SENDER, u(BUDGET));
+
+            (guard, unsafe { TokenSaleWithTokenizedVesting::new(U256::ZERO, 0) })
+        }
+
+        #[test]
+        fn whole_token_purchases_are_scaled_by_the_token_decimals() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert_eq!(sale.tokens_purchased.get(SENDER), tokens(5));
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(5 * PRICE));
+
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(5)));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(5));
+        }
+
+        #[test]
+        fn six_decimal_tokens_are_scaled_by_their_own_decimals() {
+            let (_chain, mut sale) = deploy(SaleConfig {
+                token_decimals: 6,
+                total_tokens_available: u(SUPPLY * 1_000_000),
+                ..config()
+            });
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.tokens_purchased.get(SENDER), u(100_000_000));
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(100 * PRICE));
+        }
+
+        #[test]
+        fn decimals_beyond_what_can_be_scaled_are_rejected() {
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let result = sale.init_with_config(SaleConfig { token_decimals: 37, ..config() });
+            assert!(matches!(result, Err(Errors::TooManyDecimals(_))));
+            assert!(!sale.is_initialized());
+        }
+
+        #[test]
+        fn only_the_nominated_owner_can_accept_ownership() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.transfer_ownership(ALICE).is_ok());
+            assert_eq!(sale.pending_owner.get(), ALICE);
+
+            assert!(matches!(sale.accept_ownership(), Err(Errors::OnlyPendingOwner(_))));
+            assert_eq!(sale.owner(), SENDER);
+        }
+
+        #[test]
+        fn accepted_ownership_hands_over_owner_only_functions() {
+            // Alice deployed the sale and nominated the sender
+            let (_chain, mut sale) = deploy(config());
+            sale.owner.set(ALICE);
+            sale.pending_owner.set(SENDER);
+            assert!(matches!(sale.transfer_ownership(ALICE), Err(Errors::OnlyOwner(_))));
+
+            assert!(sale.accept_ownership().is_ok());
+            assert_eq!(sale.owner(), SENDER);
+            assert_eq!(sale.pending_owner.get(), Address::ZERO);
+            let handovers = host::events::<OwnershipTransferred>();
+            assert_eq!((handovers[0].previous, handovers[0].next), (ALICE, SENDER));
+
+            assert!(sale.transfer_ownership(ALICE).is_ok());
+        }
+
+        #[test]
+        fn getters_return_the_configuration() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+
+            assert_eq!(sale.token(), TOKEN);
+            assert_eq!(sale.currency(), CURRENCY);
+            assert_eq!(sale.price_per_token(), u(PRICE));
+            assert_eq!(sale.total_tokens_available(), tokens(SUPPLY));
+            assert_eq!(sale.total_vesting_length_in_seconds(), U256::ZERO);
+            assert_eq!(sale.nft_claim(), Address::ZERO);
+            assert_eq!(sale.owner(), SENDER);
+            assert_eq!(sale.total_tokens_purchased(), tokens(5));
+        }
+
+        #[test]
+        fn purchases_and_unlocked_claims_halt_while_paused() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert!(sale.pause().is_ok());
+
+            assert!(matches!(sale.purchase_tokens(u(5)), Err(Errors::EnforcedPause(_))));
+            assert!(matches!(sale.claim_unlocked_tokens(), Err(Errors::EnforcedPause(_))));
+
+            assert!(sale.unpause().is_ok());
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(10)));
+        }
+
+        #[test]
+        fn vested_claims_halt_while_paused() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert!(sale.pause().is_ok());
+            assert!(matches!(sale.claim_tokens(), Err(Errors::EnforcedPause(_))));
+            assert!(sale.unpause().is_ok());
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+
+            host::set_nft_owner(NFT, u(1), SENDER);
+            assert!(sale.enable_tokenized_vesting(NFT, u(1)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            assert!(sale.pause().is_ok());
+            assert!(matches!(sale.claim_tokens_by_nft(SENDER), Err(Errors::EnforcedPause(_))));
+            assert!(sale.unpause().is_ok());
+            assert_eq!(sale.claim_tokens_by_nft(SENDER).ok(), Some(tokens(25)));
+        }
+
+        #[test]
+        fn purchases_are_only_accepted_inside_the_sale_window() {
+            let window = |sale_start: u64, sale_end: u64| SaleConfig { sale_start: u(sale_start), sale_end: u(sale_end), ..config() };
+
+            let (chain, mut sale) = deploy(window(NOW + 1, NOW + 100));
+            assert!(matches!(sale.purchase_tokens(u(5)), Err(Errors::SaleNotStarted(_))));
+            drop(chain);
+
+            // Both bounds are inclusive
+            let (chain, mut sale) = deploy(window(NOW, NOW + 100));
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            drop(chain);
+
+            let (chain, mut sale) = deploy(window(NOW - 100, NOW));
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            drop(chain);
+
+            let (_chain, mut sale) = deploy(window(NOW - 100, NOW - 1));
+            assert!(matches!(sale.purchase_tokens(u(5)), Err(Errors::SaleEnded(_))));
+        }
+
+        #[test]
+        fn either_bound_of_the_sale_window_can_be_left_open() {
+            let (chain, mut sale) = deploy(SaleConfig { sale_start: u(NOW - 100), ..config() });
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            drop(chain);
+
+            let (_chain, mut sale) = deploy(SaleConfig { sale_end: u(NOW + 100), ..config() });
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+        }
+
+        #[test]
+        fn sale_window_must_end_after_it_starts() {
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let result = sale.init_with_config(SaleConfig { sale_start: u(NOW), sale_end: u(NOW), ..config() });
+            assert!(matches!(result, Err(Errors::InvalidSaleWindow(_))));
+        }
+
+        #[test]
+        fn owner_recovers_exactly_the_unsold_tokens_once_the_sale_ends() {
+            let (_chain, mut sale) = deploy(SaleConfig { sale_end: u(NOW), ..config() });
+            assert!(sale.purchase_tokens(u(300)).is_ok());
+            assert!(matches!(sale.withdraw_unsold_tokens(TREASURY), Err(Errors::SaleNotEnded(_))));
+
+            sale.sale_end.set(u(NOW - 1));
+            assert!(sale.withdraw_unsold_tokens(TREASURY).is_ok());
+            assert_eq!(host::balance(TOKEN, TREASURY), tokens(700));
+            assert_eq!(host::balance(TOKEN, CONTRACT), tokens(300));
+            assert!(matches!(sale.withdraw_unsold_tokens(TREASURY), Err(Errors::NoUnsoldTokens(_))));
+
+            // Buyers can still claim everything they purchased
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(300)));
+        }
+
+        #[test]
+        fn unsold_tokens_of_an_unbounded_sale_can_be_withdrawn_at_any_time() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(300)).is_ok());
+            assert!(sale.withdraw_unsold_tokens(TREASURY).is_ok());
+            assert_eq!(host::balance(TOKEN, TREASURY), tokens(700));
+            assert_eq!(sale.total_tokens_available(), tokens(300));
+        }
+
+        #[test]
+        fn top_ups_before_the_sale_ends_vest_together_from_the_end_of_the_sale() {
+            let (_chain, mut sale) = deploy(SaleConfig {
+                allow_multiple_purchases: true,
+                sale_end: u(NOW + 1_000),
+                ..vesting_config()
+            });
+            assert!(sale.set_vest_from_sale_end(true).is_ok());
+
+            // The first lot was bought some time before the second
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, 500);
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.tokens_purchased.get(SENDER), tokens(200));
+            assert_eq!(sale.claimable_amount(SENDER), U256::ZERO);
+
+            // A quarter of the way through vesting after the sale ended both lots have released a quarter
+            vest_for(&mut sale, SENDER, VESTING / 4 + 500);
+            top_up_vest_for(&mut sale, SENDER, 0, VESTING / 4);
+            sale.sale_end.set(u(NOW - VESTING / 4));
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(50)));
+
+            vest_for(&mut sale, SENDER, VESTING + 500);
+            top_up_vest_for(&mut sale, SENDER, 0, VESTING);
+            sale.sale_end.set(u(NOW - VESTING));
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(150)));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(200));
+        }
+
+        #[test]
+        fn top_ups_vest_from_when_they_were_bought_alongside_earlier_purchases() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..vesting_config() });
+
+            // The second 100 tokens are bought half way through the vesting of the first 100 which keep their start
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.tokens_purchased_at.get(SENDER), u(NOW - VESTING / 2));
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(50)));
+
+            // A quarter of the vesting length later the first lot has vested three quarters and the top up a quarter
+            vest_for(&mut sale, SENDER, VESTING * 3 / 4);
+            top_up_vest_for(&mut sale, SENDER, 0, VESTING / 4);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(50)));
+            assert_eq!(sale.vesting_start(SENDER), u(NOW - VESTING * 3 / 4));
+            assert_eq!(sale.vesting_end(SENDER), u(NOW + VESTING * 3 / 4));
+
+            // Once the first lot has fully vested only the top up keeps vesting until a vesting length after it was bought
+            vest_for(&mut sale, SENDER, VESTING * 5 / 4);
+            top_up_vest_for(&mut sale, SENDER, 0, VESTING * 3 / 4);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(75)));
+
+            vest_for(&mut sale, SENDER, VESTING * 3 / 2);
+            top_up_vest_for(&mut sale, SENDER, 0, VESTING);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(200));
+        }
+
+        #[test]
+        fn top_ups_after_the_cliff_only_hold_back_their_own_tokens() {
+            let (_chain, mut sale) = deploy(SaleConfig {
+                allow_multiple_purchases: true,
+                cliff_length_in_seconds: u(VESTING / 4),
+                ..vesting_config()
+            });
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+
+            // The first lot is past its cliff while the top up waits for its own
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(50)));
+            vest_for(&mut sale, SENDER, VESTING * 3 / 4 - 1);
+            top_up_vest_for(&mut sale, SENDER, 0, VESTING / 4 - 1);
+            assert_eq!(sale.claimable_amount(SENDER), tokens(75) - tokens(100) * u(1) / u(VESTING) - tokens(50));
+
+            vest_for(&mut sale, SENDER, VESTING * 3 / 4);
+            top_up_vest_for(&mut sale, SENDER, 0, VESTING / 4);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(50)));
+        }
+
+        #[test]
+        fn fully_claimed_positions_can_be_topped_up_with_a_fresh_lot() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..vesting_config() });
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(100)));
+            assert_eq!(sale.tokens_purchased_at.get(SENDER), U256::ZERO);
+
+            // The pruned first lot counts as vested so nothing of the new lot is released early
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.claimable_amount(SENDER), U256::ZERO);
+            top_up_vest_for(&mut sale, SENDER, 0, VESTING / 2);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(50)));
+        }
+
+        #[test]
+        fn vesting_positions_take_a_bounded_number_of_top_ups() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..vesting_config() });
+            for _ in 0..=MAX_TOP_UPS {
+                assert!(sale.purchase_tokens(u(1)).is_ok());
+            }
+
+            assert!(matches!(sale.purchase_tokens(u(1)), Err(Errors::TooManyTopUps(_))));
+            assert_eq!(sale.purchase_lots(SENDER).len(), MAX_TOP_UPS + 1);
+        }
+
+        #[test]
+        fn unlocked_positions_accumulate_top_ups() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(100)));
+            assert!(sale.purchase_tokens(u(50)).is_ok());
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(50)));
+            assert_eq!(sale.total_tokens_purchased(), tokens(150));
+        }
+
+        #[test]
+        fn single_purchase_sales_reject_top_ups() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert!(matches!(sale.purchase_tokens(u(1)), Err(Errors::OnlyOnePurchase(_))));
+        }
+
+        #[test]
+        fn enabled_allowlist_only_admits_allowlisted_buyers() {
+            let (_chain, mut sale) = deploy(SaleConfig { allowlist_enabled: true, ..config() });
+            assert!(matches!(sale.purchase_tokens(u(5)), Err(Errors::NotAllowlisted(_))));
+
+            assert!(sale.set_allowlist(SENDER, true).is_ok());
+            assert!(sale.is_allowlisted(SENDER));
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+        }
+
+        #[test]
+        fn disabled_allowlist_admits_everyone() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(!sale.allowlist_enabled());
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+        }
+
+        #[test]
+        fn allowlist_can_be_updated_in_batches() {
+            let (_chain, mut sale) = deploy(SaleConfig { allowlist_enabled: true, ..config() });
+            assert!(sale.set_allowlist_batch(vec![ALICE, BOB, SENDER], true).is_ok());
+            assert!(sale.is_allowlisted(ALICE) && sale.is_allowlisted(BOB) && sale.is_allowlisted(SENDER));
+
+            assert!(sale.set_allowlist_batch(vec![BOB, SENDER], false).is_ok());
+            assert!(sale.is_allowlisted(ALICE) && !sale.is_allowlisted(BOB) && !sale.is_allowlisted(SENDER));
+            assert!(matches!(sale.purchase_tokens(u(5)), Err(Errors::NotAllowlisted(_))));
+
+            // A zero address anywhere in the batch reverts the whole update
+            assert!(matches!(sale.set_allowlist_batch(vec![BOB, Address::ZERO], true), Err(Errors::ZeroValueArgumentInjected(_))));
+        }
+
+        #[test]
+        fn nothing_is_claimable_before_the_cliff() {
+            let (_chain, mut sale) = deploy(SaleConfig { cliff_length_in_seconds: u(VESTING / 4), ..vesting_config() });
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+
+            vest_for(&mut sale, SENDER, VESTING / 4 - 1);
+            assert_eq!(sale.claimable_amount(SENDER), U256::ZERO);
+            assert!(matches!(sale.claim_tokens(), Err(Errors::CliffNotReached(_))));
+        }
+
+        #[test]
+        fn linear_release_since_purchase_unlocks_at_the_cliff() {
+            let (_chain, mut sale) = deploy(SaleConfig { cliff_length_in_seconds: u(VESTING / 4), ..vesting_config() });
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert_eq!(sale.claimable_amount(SENDER), tokens(25));
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+        }
+
+        #[test]
+        fn cliff_must_end_before_vesting_does() {
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let result = sale.init_with_config(SaleConfig { cliff_length_in_seconds: u(VESTING), ..vesting_config() });
+            assert!(matches!(result, Err(Errors::InvalidCliffLength(_))));
+        }
+
+        #[test]
+        fn instant_unlock_is_paid_at_purchase_and_the_rest_vests() {
+            let (_chain, mut sale) = deploy(SaleConfig { instant_unlock_bps: u(2_000), ..vesting_config() });
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(20));
+            assert_eq!(sale.claimable_amount(SENDER), U256::ZERO);
+
+            // Half way through, half of the remaining 80 has vested
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(40)));
+
+            vest_for(&mut sale, SENDER, VESTING);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(40)));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(100));
+        }
+
+        #[test]
+        fn instant_unlock_must_be_a_percentage_of_a_vesting_sale() {
+            let (chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let result = sale.init_with_config(SaleConfig { instant_unlock_bps: u(10_001), ..vesting_config() });
+            assert!(matches!(result, Err(Errors::InvalidPercentage(_))));
+            drop(chain);
+
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let result = sale.init_with_config(SaleConfig { instant_unlock_bps: u(2_000), ..config() });
+            assert!(matches!(result, Err(Errors::InvalidPercentage(_))));
+        }
+
+        /// Makes a token call back into the sale with `reenter` whenever it moves tokens, recording whether every reentrant
+        /// call was rejected by the reentrancy guard
+        fn reenter_on_transfer(
+            token: Address,
+            reenter: fn(&mut TokenSaleWithTokenizedVesting) -> Result<(), Errors>
+        ) -> Rc<RefCell<Vec<bool>>> {
+            let rejected = Rc::new(RefCell::new(Vec::new()));
+            let calls = Rc::clone(&rejected);
+            host::with_token(token, |mock| {
+                mock.on_transfer = Some(Rc::new(move || {
+                    let mut sale = unsafe { TokenSaleWithTokenizedVesting::new(U256::ZERO, 0) };
+                    calls.borrow_mut().push(matches!(reenter(&mut sale), Err(Errors::Reentrancy(_))));
+                }));
+            });
+            rejected
+        }
+
+        #[test]
+        fn currency_reentering_a_purchase_is_rejected() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            let rejected = reenter_on_transfer(CURRENCY, |sale| sale.purchase_tokens(u(1)));
+
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert_eq!(*rejected.borrow(), vec![true]);
+            assert_eq!(sale.tokens_purchased.get(SENDER), tokens(5));
+            assert!(!sale.locked.get());
+        }
+
+        #[test]
+        fn token_reentering_an_unlocked_claim_is_rejected() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            let rejected = reenter_on_transfer(TOKEN, |sale| sale.claim_unlocked_tokens().map(|_| ()));
+
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(5)));
+            assert_eq!(*rejected.borrow(), vec![true]);
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(5));
+        }
+
+        #[test]
+        fn token_reentering_vested_claims_is_rejected() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            let rejected = reenter_on_transfer(TOKEN, |sale| sale.claim_tokens().map(|_| ()));
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+
+            host::set_nft_owner(NFT, u(1), SENDER);
+            assert!(sale.enable_tokenized_vesting(NFT, u(1)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            let rejected_by_nft = reenter_on_transfer(TOKEN, |sale| sale.claim_tokens_by_nft(SENDER).map(|_| ()));
+            assert_eq!(sale.claim_tokens_by_nft(SENDER).ok(), Some(tokens(25)));
+
+            assert_eq!(*rejected.borrow(), vec![true]);
+            assert_eq!(*rejected_by_nft.borrow(), vec![true]);
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(50));
+        }
+
+        #[test]
+        fn disabling_tokenized_vesting_restores_direct_claims() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            host::set_nft_owner(NFT, u(1), SENDER);
+            assert!(sale.enable_tokenized_vesting(NFT, u(1)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert!(matches!(sale.claim_tokens(), Err(Errors::AlreadyTokenized(_))));
+
+            assert!(sale.disable_tokenized_vesting().is_ok());
+            assert_eq!(host::events::<TokenizedVestingDisabled>()[0].nft_token_id, u(1));
+            assert!(matches!(sale.claim_tokens_by_nft(SENDER), Err(Errors::NotTokenized(_))));
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+        }
+
+        #[test]
+        fn only_the_holder_of_the_nft_can_disable_tokenized_vesting() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            host::set_nft_owner(NFT, u(1), SENDER);
+            assert!(sale.enable_tokenized_vesting(NFT, u(1)).is_ok());
+
+            // The buyer sold the NFT on
+            host::set_nft_owner(NFT, u(1), ALICE);
+            assert!(matches!(sale.disable_tokenized_vesting(), Err(Errors::OnlyOwner(_))));
+            assert!(sale.vesting_tokenized.get(SENDER));
+        }
+
+        #[test]
+        fn token_id_zero_can_tokenize_and_claim() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            host::set_nft_owner(NFT, U256::ZERO, SENDER);
+            assert!(sale.enable_tokenized_vesting(NFT, U256::ZERO).is_ok());
+            assert!(sale.vesting_tokenized.get(SENDER));
+            assert!(matches!(sale.claim_tokens(), Err(Errors::AlreadyTokenized(_))));
+
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert_eq!(sale.claim_tokens_by_nft(SENDER).ok(), Some(tokens(25)));
+            assert_eq!(host::events::<NftClaim>()[0].tokenId, U256::ZERO);
+        }
+
+        #[test]
+        fn price_can_be_updated_before_the_first_purchase() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.set_price_per_token(u(2 * PRICE)).is_ok());
+            let update = &host::events::<PriceUpdated>()[0];
+            assert_eq!((update.old, update.new), (u(PRICE), u(2 * PRICE)));
+
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(10 * PRICE));
+        }
+
+        #[test]
+        fn price_cannot_change_once_tokens_are_purchased() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert!(matches!(sale.set_price_per_token(u(2 * PRICE)), Err(Errors::TokensAlreadyPurchased(_))));
+            assert_eq!(sale.price_per_token(), u(PRICE));
+        }
+
+        #[test]
+        fn price_cannot_be_set_to_zero() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(matches!(sale.set_price_per_token(U256::ZERO), Err(Errors::ZeroValueArgumentInjected(_))));
+        }
+
+        #[test]
+        fn vesting_info_reports_the_whole_position() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert_eq!(sale.vesting_info(SENDER), (U256::ZERO, U256::ZERO, U256::ZERO, U256::ZERO, U256::ZERO));
+
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert!(sale.claim_tokens().is_ok());
+            host::set_nft_owner(NFT, u(7), SENDER);
+            assert!(sale.enable_tokenized_vesting(NFT, u(7)).is_ok());
+
+            assert_eq!(sale.vesting_info(SENDER), (tokens(100), u(NOW - VESTING / 4), tokens(25), u(NOW), u(7)));
+        }
+
+        #[test]
+        fn zero_amount_purchases_are_rejected() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(matches!(sale.purchase_tokens(U256::ZERO), Err(Errors::ZeroValueArgumentInjected(_))));
+            assert_eq!(sale.unique_buyers(), U256::ZERO);
+        }
+
+        #[test]
+        fn dust_purchases_are_never_free() {
+            // A single base unit of an 18 decimal token at 1000 per token would cost nothing without rounding up
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.set_fractional_purchases(true).is_ok());
+            assert_eq!(sale.quote_cost(u(1)).ok(), Some(u(1)));
+
+            assert!(sale.purchase_tokens(u(1)).is_ok());
+            assert_eq!(sale.tokens_purchased.get(SENDER), u(1));
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(1));
+        }
+
+        #[test]
+        fn tokens_returning_nothing_can_be_paid_and_claimed() {
+            let (_chain, mut sale) = deploy(config());
+            host::with_token(CURRENCY, |mock| mock.returns_nothing = true);
+            host::with_token(TOKEN, |mock| mock.returns_nothing = true);
+
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(5 * PRICE));
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(5)));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(5));
+        }
+
+        #[test]
+        fn tokens_returning_false_fail_the_transfer() {
+            let (_chain, mut sale) = deploy(config());
+            host::with_token(CURRENCY, |mock| {
+                mock.returns_false = true;
+                mock.balances.clear();
+            });
+
+            assert!(matches!(sale.purchase_tokens(u(5)), Err(Errors::TransferFailed(_))));
+        }
+
+        #[test]
+        fn transfers_of_accounts_without_code_fail() {
+            let (_chain, mut sale) = deploy(config());
+            let calldata = transferCall { to: SENDER, amount: u(1) }.abi_encode();
+            assert!(matches!(sale.call_optional_return(ALICE, &calldata), Err(Errors::TransferFailed(_))));
+            assert!(sale.call_optional_return(TOKEN, &calldata).is_ok());
+        }
+
+        #[test]
+        fn batch_purchases_record_each_recipient_and_charge_the_buyer_once() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(3), u(7)]).is_ok());
+
+            assert_eq!(sale.tokens_purchased.get(ALICE), tokens(3));
+            assert_eq!(sale.tokens_purchased.get(BOB), tokens(7));
+            assert_eq!(sale.tokens_purchased.get(SENDER), U256::ZERO);
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(10 * PRICE));
+
+            let purchases: Vec<_> = host::events::<TokensPurchased>().iter().map(|purchase| (purchase.user, purchase.cost)).collect();
+            assert_eq!(purchases, vec![(ALICE, u(3 * PRICE)), (BOB, u(7 * PRICE))]);
+        }
+
+        #[test]
+        fn batch_purchases_need_an_amount_for_every_recipient() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(matches!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(3)]), Err(Errors::LengthMismatch(_))));
+            assert!(matches!(sale.purchase_tokens_for(Vec::new(), Vec::new()), Err(Errors::ZeroValueArgumentInjected(_))));
+        }
+
+        #[test]
+        fn batch_purchases_apply_the_sale_rules_to_each_recipient() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(matches!(sale.purchase_tokens_for(vec![ALICE, ALICE], vec![u(3), u(3)]), Err(Errors::OnlyOnePurchase(_))));
+            assert!(matches!(sale.purchase_tokens_for(vec![BOB], vec![u(SUPPLY + 1)]), Err(Errors::SoldOut(_))));
+        }
+
+        #[test]
+        fn claims_return_the_amount_transferred_and_logged() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            let claimed = sale.claim_tokens().ok();
+
+            host::set_nft_owner(NFT, u(1), SENDER);
+            assert!(sale.enable_tokenized_vesting(NFT, u(1)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            let claimed_by_nft = sale.claim_tokens_by_nft(SENDER).ok();
+
+            assert_eq!((claimed, claimed_by_nft), (Some(tokens(25)), Some(tokens(25))));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(50));
+            let logged: Vec<_> = host::events::<TokensClaimed>().iter().map(|claim| claim.amount).collect();
+            assert_eq!(logged, vec![tokens(25), tokens(25)]);
+        }
+
+        #[test]
+        fn unlocked_claims_return_the_amount_transferred_and_logged() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(5)));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(5));
+            assert_eq!(host::events::<TokensClaimed>()[0].amount, tokens(5));
+        }
+
+        #[test]
+        fn vested_claims_can_be_sent_to_another_wallet() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+
+            assert_eq!(sale.claim_tokens_to(ALICE).ok(), Some(tokens(25)));
+            assert_eq!(sale.claimed_by(SENDER), tokens(25));
+            assert_eq!(host::balance(TOKEN, ALICE), tokens(25));
+            assert_eq!(host::balance(TOKEN, SENDER), U256::ZERO);
+
+            let claim = &host::events::<TokensClaimed>()[0];
+            assert_eq!((claim.user, claim.recipient), (SENDER, ALICE));
+        }
+
+        #[test]
+        fn unlocked_claims_can_be_sent_to_another_wallet() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert!(matches!(sale.claim_unlocked_tokens_to(Address::ZERO), Err(Errors::ZeroValueArgumentInjected(_))));
+
+            assert_eq!(sale.claim_unlocked_tokens_to(ALICE).ok(), Some(tokens(5)));
+            assert_eq!(sale.claimed_by(SENDER), tokens(5));
+            assert_eq!(host::balance(TOKEN, ALICE), tokens(5));
+            assert!(matches!(sale.claim_unlocked_tokens(), Err(Errors::AllTokensClaimed(_))));
+        }
+
+        #[test]
+        fn tiny_allocations_vest_part_way_through_a_year() {
+            let year = 31_536_000;
+            let (_chain, mut sale) = deploy(SaleConfig { total_vesting_length_in_seconds: u(year), ..vesting_config() });
+            assert!(sale.set_fractional_purchases(true).is_ok());
+            assert!(sale.purchase_tokens(u(3)).is_ok());
+
+            vest_for(&mut sale, SENDER, year / 3);
+            assert_eq!(sale.claim_tokens().ok(), Some(u(1)));
+            vest_for(&mut sale, SENDER, year / 3 * 2);
+            assert_eq!(sale.claim_tokens().ok(), Some(u(1)));
+            vest_for(&mut sale, SENDER, year);
+            assert_eq!(sale.claim_tokens().ok(), Some(u(1)));
+        }
+
+        #[test]
+        fn many_small_claims_add_up_to_one_claim_at_the_end() {
+            // Awkward allocations claimed at pseudo random times lose nothing to rounding along the way
+            for (allocation, seed) in [(1_000_003u64, 1u64), (999_999_937, 7), (12_345, 42), (7, 99)] {
+                let (chain, mut sale) = deploy(vesting_config());
+                assert!(sale.set_fractional_purchases(true).is_ok());
+                assert!(sale.purchase_tokens(u(allocation)).is_ok());
+
+                let (mut elapsed, mut state, mut claimed) = (0, seed, U256::ZERO);
+                while elapsed < VESTING {
+                    state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                    elapsed = (elapsed + 1 + (state >> 33) % (VESTING / 20)).min(VESTING);
+                    vest_for(&mut sale, SENDER, elapsed);
+                    claimed += sale.claim_tokens().unwrap_or_default();
+
+                    // However often it was claimed, the position has released exactly what one claim now would release
+                    let vested = vested_tokens(u(allocation), U256::ZERO, u(elapsed), u(VESTING), U256::ZERO);
+                    assert_eq!(claimed, vested);
+                }
+
+                assert_eq!(claimed, u(allocation));
+                assert_eq!(host::balance(TOKEN, SENDER), u(allocation));
+                drop(chain);
+
+                let (_chain, mut sale) = deploy(vesting_config());
+                assert!(sale.set_fractional_purchases(true).is_ok());
+                assert!(sale.purchase_tokens(u(allocation)).is_ok());
+                vest_for(&mut sale, SENDER, VESTING);
+                assert_eq!(sale.claim_tokens().ok(), Some(claimed));
+            }
+        }
+
+        #[test]
+        fn tokens_remaining_falls_with_each_purchase_and_is_logged() {
+            let (_chain, mut sale) = deploy(config());
+            assert_eq!(sale.tokens_remaining().ok(), Some(tokens(SUPPLY)));
+
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(300), u(200)]).is_ok());
+            assert_eq!(sale.tokens_remaining().ok(), Some(tokens(500)));
+
+            let progress: Vec<_> = host::events::<SaleProgress>().iter().map(|progress| (progress.purchased, progress.remaining)).collect();
+            assert_eq!(progress, vec![(tokens(300), tokens(700)), (tokens(500), tokens(500))]);
+        }
+
+        #[test]
+        fn later_cohorts_vest_over_the_new_length_while_earlier_buyers_keep_theirs() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(100)]).is_ok());
+            assert!(sale.set_vesting_length(u(2 * VESTING)).is_ok());
+            assert!(sale.purchase_tokens_for(vec![BOB], vec![u(100)]).is_ok());
+
+            assert_eq!(sale.vesting_length_of(ALICE), u(VESTING));
+            assert_eq!(sale.vesting_length_of(BOB), u(2 * VESTING));
+
+            // Both cohorts have been vesting for the original length
+            vest_for(&mut sale, ALICE, VESTING);
+            vest_for(&mut sale, BOB, VESTING);
+            assert_eq!(sale.claimable_amount(ALICE), tokens(100));
+            assert_eq!(sale.claimable_amount(BOB), tokens(50));
+        }
+
+        #[test]
+        fn vesting_cannot_be_switched_off_for_future_buyers() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(matches!(sale.set_vesting_length(U256::ZERO), Err(Errors::VestingNotEnabled(_))));
+            assert!(matches!(sale.set_vesting_length(u(60)), Err(Errors::VestingLengthTooShort(_))));
+        }
+
+        #[test]
+        fn refunds_pay_back_buyers_and_return_their_allocation() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert!(matches!(sale.refund(), Err(Errors::RefundsNotEnabled(_))));
+
+            assert!(sale.enable_refunds().is_ok());
+            assert!(matches!(sale.refund(), Err(Errors::InsufficientRefundReserve(_))));
+
+            assert!(sale.deposit_refund_reserve(u(5 * PRICE)).is_ok());
+            assert_eq!(host::balance(CURRENCY, CONTRACT), u(5 * PRICE));
+            assert_eq!(sale.refund().ok(), Some((u(5 * PRICE), U256::ZERO)));
+
+            assert_eq!(host::balance(CURRENCY, CONTRACT), U256::ZERO);
+            assert_eq!(sale.refund_reserve(), U256::ZERO);
+            assert_eq!(sale.tokens_purchased.get(SENDER), U256::ZERO);
+            assert_eq!(sale.tokens_remaining().ok(), Some(tokens(SUPPLY)));
+            assert_eq!(sale.total_currency_raised(), U256::ZERO);
+            assert_eq!(sale.unique_buyers(), U256::ZERO);
+            assert!(matches!(sale.refund(), Err(Errors::NoTokensPurchased(_))));
+        }
+
+        #[test]
+        fn refunds_are_rejected_once_anything_was_claimed() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert!(sale.enable_refunds().is_ok());
+            assert!(sale.deposit_refund_reserve(u(100 * PRICE)).is_ok());
+
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert!(sale.claim_tokens().is_ok());
+            assert!(matches!(sale.refund(), Err(Errors::TokensAlreadyClaimed(_))));
+            assert_eq!(sale.refund_reserve(), u(100 * PRICE));
+        }
+
+        #[test]
+        fn instant_unlocks_count_as_claimed_for_refunds() {
+            let (_chain, mut sale) = deploy(SaleConfig { instant_unlock_bps: u(1_000), ..vesting_config() });
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert!(sale.enable_refunds().is_ok());
+            assert!(sale.deposit_refund_reserve(u(100 * PRICE)).is_ok());
+            assert!(matches!(sale.refund(), Err(Errors::TokensAlreadyClaimed(_))));
+        }
+
+        #[test]
+        fn closing_the_sale_stops_purchases_but_not_claims() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(40)]).is_ok());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert!(sale.close_sale().is_ok());
+            assert_eq!(host::events::<SaleClosed>()[0].totalPurchased, tokens(140));
+
+            assert!(matches!(sale.purchase_tokens_for(vec![BOB], vec![u(1)]), Err(Errors::SaleIsClosed(_))));
+            assert!(matches!(sale.close_sale(), Err(Errors::SaleIsClosed(_))));
+
+            vest_for(&mut sale, SENDER, VESTING);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(100)));
+        }
+
+        #[test]
+        fn split_positions_are_claimed_pro_rata_by_each_nft() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            host::set_nft_owner(NFT, u(1), SENDER);
+            host::set_nft_owner(NFT, u(2), ALICE);
+            assert!(sale.split_tokenized_vesting(NFT, vec![u(1), u(2)], vec![tokens(60), tokens(40)]).is_ok());
+            assert_eq!(sale.share_token_ids_of(SENDER), vec![u(1), u(2)]);
+            assert!(matches!(sale.claim_tokens_by_nft(SENDER), Err(Errors::PositionSplit(_))));
+
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert_eq!(sale.claim_tokens_by_nft_share(SENDER, u(1)).ok(), Some(tokens(15)));
+            assert!(matches!(sale.claim_tokens_by_nft_share(SENDER, u(2)), Err(Errors::OnlyOwner(_))));
+
+            // The holder of the second share claims on their own schedule
+            host::set_nft_owner(NFT, u(2), SENDER);
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            assert_eq!(sale.claimable_amount_by_nft_share(SENDER, u(1)), tokens(15));
+            assert_eq!(sale.claim_tokens_by_nft_share(SENDER, u(2)).ok(), Some(tokens(20)));
+
+            vest_for(&mut sale, SENDER, VESTING);
+            assert_eq!(sale.claim_tokens_by_nft_share(SENDER, u(1)).ok(), Some(tokens(45)));
+            assert_eq!(sale.claim_tokens_by_nft_share(SENDER, u(2)).ok(), Some(tokens(20)));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(100));
+            assert_eq!(sale.claimed_by(SENDER), tokens(100));
+        }
+
+        #[test]
+        fn splits_must_cover_exactly_what_is_left_to_claim() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert!(sale.claim_tokens().is_ok());
+
+            let split = |sale: &mut TokenSaleWithTokenizedVesting, amounts: Vec<U256>| {
+                transact(sale, |sale| sale.split_tokenized_vesting(NFT, vec![u(1), u(2)], amounts))
+            };
+            assert!(matches!(split(&mut sale, vec![tokens(60), tokens(40)]), Err(Errors::InvalidSplit(_))));
+            assert!(matches!(split(&mut sale, vec![tokens(75)]), Err(Errors::LengthMismatch(_))));
+
+            assert!(split(&mut sale, vec![tokens(45), tokens(30)]).is_ok());
+            assert_eq!(sale.nft_claim_share(SENDER, u(1)), (tokens(45), U256::ZERO));
+        }
+
+        #[test]
+        fn vesting_sales_need_a_deployed_nft_contract() {
+            let (chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let result = sale.init_with_config(SaleConfig { nft_claim: ALICE, ..vesting_config() });
+            assert!(matches!(result, Err(Errors::NotAContract(_))));
+            drop(chain);
+
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let result = sale.init_with_config(SaleConfig { nft_claim: Address::ZERO, ..vesting_config() });
+            assert!(matches!(result, Err(Errors::ZeroValueArgumentInjected(_))));
+        }
+
+        #[test]
+        fn sales_without_vesting_ignore_the_nft_contract() {
+            let (_chain, sale) = deploy(SaleConfig { nft_claim: ALICE, ..config() });
+            assert!(sale.is_initialized());
+        }
+
+        #[test]
+        fn can_tokenize_mirrors_enabling_tokenized_vesting() {
+            let (chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert!(!sale.can_tokenize(SENDER));
+            drop(chain);
+
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(!sale.can_tokenize(SENDER));
+
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert!(sale.can_tokenize(SENDER));
+
+            host::set_nft_owner(NFT, u(1), SENDER);
+            assert!(sale.enable_tokenized_vesting(NFT, u(1)).is_ok());
+            assert!(!sale.can_tokenize(SENDER));
+
+            assert!(sale.disable_tokenized_vesting().is_ok());
+            assert!(sale.can_tokenize(SENDER));
+
+            vest_for(&mut sale, SENDER, VESTING);
+            assert!(sale.claim_tokens().is_ok());
+            assert!(!sale.can_tokenize(SENDER));
+            assert!(matches!(sale.enable_tokenized_vesting(NFT, u(1)), Err(Errors::AllTokensClaimed(_))));
+        }
+
+        #[test]
+        fn unrelated_tokens_sent_by_mistake_can_be_rescued() {
+            let (_chain, mut sale) = deploy(config());
+            host::deploy_token(OTHER_TOKEN, MockToken::default());
+            host::mint(OTHER_TOKEN, CONTRACT, u(50));
+
+            assert!(sale.rescue_tokens(OTHER_TOKEN, TREASURY, u(50)).is_ok());
+            assert_eq!(host::balance(OTHER_TOKEN, TREASURY), u(50));
+            assert_eq!(sale.total_tokens_available(), tokens(SUPPLY));
+        }
+
+        #[test]
+        fn sold_tokens_can_only_be_rescued_up_to_the_unsold_surplus() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(300)).is_ok());
+            assert!(matches!(sale.rescue_tokens(TOKEN, TREASURY, tokens(701)), Err(Errors::RescueExceedsSurplus(_))));
+
+            assert!(sale.rescue_tokens(TOKEN, TREASURY, tokens(700)).is_ok());
+            assert_eq!(host::balance(TOKEN, TREASURY), tokens(700));
+            assert_eq!(sale.tokens_remaining().ok(), Some(U256::ZERO));
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(300)));
+        }
+
+        #[test]
+        fn sold_tokens_backing_unclaimed_positions_cannot_be_rescued_from_an_underfunded_sale() {
+            let (_chain, mut sale) = uninitialized(tokens(400));
+            assert!(sale.init_with_config(config()).is_ok());
+            assert!(sale.purchase_tokens(u(300)).is_ok());
+
+            // Only the 100 tokens held beyond the 300 owed to the buyer are surplus even though 700 are unsold
+            assert!(matches!(sale.rescue_tokens(TOKEN, TREASURY, tokens(101)), Err(Errors::RescueExceedsSurplus(_))));
+            assert!(sale.rescue_tokens(TOKEN, TREASURY, tokens(100)).is_ok());
+            assert_eq!(sale.total_tokens_available(), tokens(SUPPLY - 100));
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(300)));
+        }
+
+        #[test]
+        fn sold_tokens_sent_after_withdrawing_the_unsold_supply_can_be_rescued() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(300)).is_ok());
+            assert!(sale.withdraw_unsold_tokens(TREASURY).is_ok());
+            host::mint(TOKEN, CONTRACT, tokens(50));
+
+            assert!(matches!(sale.rescue_tokens(TOKEN, ALICE, tokens(51)), Err(Errors::RescueExceedsSurplus(_))));
+            assert!(sale.rescue_tokens(TOKEN, ALICE, tokens(50)).is_ok());
+            assert_eq!(host::balance(TOKEN, ALICE), tokens(50));
+            assert_eq!(sale.total_tokens_available(), tokens(300));
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(300)));
+        }
+
+        #[test]
+        fn currency_cannot_be_rescued_while_refunds_are_owed() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert!(sale.enable_refunds().is_ok());
+            assert!(sale.deposit_refund_reserve(u(5 * PRICE)).is_ok());
+            assert!(matches!(sale.rescue_tokens(CURRENCY, TREASURY, u(5 * PRICE)), Err(Errors::RefundsOwed(_))));
+        }
+
+        #[test]
+        fn purchases_stop_at_the_raise_cap() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.set_max_currency_raised(u(10 * PRICE)).is_ok());
+
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(6)]).is_ok());
+            assert!(matches!(sale.purchase_tokens_for(vec![BOB], vec![u(5)]), Err(Errors::RaiseCapReached(_))));
+            assert!(sale.purchase_tokens_for(vec![BOB], vec![u(4)]).is_ok());
+            assert_eq!(sale.total_currency_raised(), u(10 * PRICE));
+            assert!(matches!(sale.purchase_tokens(u(1)), Err(Errors::RaiseCapReached(_))));
+        }
+
+        #[test]
+        fn refunds_free_up_room_under_the_raise_cap() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            assert!(sale.set_max_currency_raised(u(10 * PRICE)).is_ok());
+            assert!(sale.purchase_tokens(u(10)).is_ok());
+            assert!(sale.enable_refunds().is_ok());
+            assert!(sale.deposit_refund_reserve(u(10 * PRICE)).is_ok());
+            assert!(sale.refund().is_ok());
+
+            assert_eq!(sale.total_currency_raised(), U256::ZERO);
+            assert!(sale.purchase_tokens(u(10)).is_ok());
+        }
+
+        #[test]
+        fn purchase_event_carries_the_cost_price_and_time() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(7)).is_ok());
+
+            let purchase = &host::events::<TokensPurchased>()[0];
+            assert_eq!(purchase.user, SENDER);
+            assert_eq!(purchase.amount, u(7));
+            assert_eq!(purchase.cost, u(7) * u(PRICE));
+            assert_eq!(purchase.pricePerToken, u(PRICE));
+            assert_eq!(purchase.purchasedAt, u(NOW));
+        }
+
+        #[test]
+        fn quote_matches_the_currency_pulled_by_a_purchase() {
+            for fractional in [false, true] {
+                let (_chain, mut sale) = deploy(config());
+                assert!(sale.set_fractional_purchases(fractional).is_ok());
+                let amount = if fractional { tokens(7) / u(3) } else { u(7) };
+                let quote = sale.quote_cost(amount).ok();
+
+                assert!(sale.purchase_tokens(amount).is_ok());
+                assert_eq!(quote, Some(u(BUDGET) - host::balance(CURRENCY, SENDER)));
+            }
+        }
+
+        #[test]
+        fn buyers_tokenize_with_any_approved_collection() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            host::deploy_nft(OTHER_NFT);
+            assert!(sale.set_approved_nft_collection(OTHER_NFT, true).is_ok());
+            assert!(sale.is_approved_nft_collection(NFT) && sale.is_approved_nft_collection(OTHER_NFT));
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+
+            // Claims follow the collection the buyer chose even when another collection has the same token ID
+            host::set_nft_owner(OTHER_NFT, u(1), SENDER);
+            host::set_nft_owner(NFT, u(1), ALICE);
+            assert!(sale.enable_tokenized_vesting(OTHER_NFT, u(1)).is_ok());
+            assert_eq!(sale.nft_collection_of(SENDER), OTHER_NFT);
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert_eq!(sale.claim_tokens_by_nft(SENDER).ok(), Some(tokens(25)));
+
+            host::set_nft_owner(OTHER_NFT, u(1), ALICE);
+            assert!(matches!(sale.claim_tokens_by_nft(SENDER), Err(Errors::OnlyOwner(_))));
+        }
+
+        #[test]
+        fn collections_that_are_not_approved_cannot_tokenize() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            host::deploy_nft(OTHER_NFT);
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            host::set_nft_owner(OTHER_NFT, u(1), SENDER);
+            assert!(matches!(sale.enable_tokenized_vesting(OTHER_NFT, u(1)), Err(Errors::NftCollectionNotApproved(_))));
+
+            // Approval can be withdrawn again
+            assert!(sale.set_approved_nft_collection(NFT, false).is_ok());
+            host::set_nft_owner(NFT, u(1), SENDER);
+            assert!(matches!(sale.enable_tokenized_vesting(NFT, u(1)), Err(Errors::NftCollectionNotApproved(_))));
+            assert!(matches!(sale.set_approved_nft_collection(ALICE, true), Err(Errors::NotAContract(_))));
+        }
+
+        #[test]
+        fn partial_claims_leave_the_rest_claimable() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+
+            assert_eq!(sale.claim_partial(tokens(10)).ok(), Some(tokens(10)));
+            assert_eq!(sale.claimable_amount(SENDER), tokens(15));
+            assert!(matches!(sale.claim_partial(tokens(16)), Err(Errors::ClaimAmountTooHigh(_))));
+            assert!(matches!(sale.claim_partial(U256::ZERO), Err(Errors::ZeroValueArgumentInjected(_))));
+
+            assert_eq!(sale.claim_partial(tokens(15)).ok(), Some(tokens(15)));
+            assert_eq!(sale.claimable_amount(SENDER), U256::ZERO);
+
+            // Partial claims do not change how the rest of the position vests
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(50));
+        }
+
+        #[test]
+        fn vesting_end_is_the_purchase_time_plus_the_vesting_length() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert_eq!(sale.vesting_end(SENDER), U256::ZERO);
+
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.vesting_end(SENDER), u(NOW + VESTING));
+
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert_eq!(sale.vesting_end(SENDER), u(NOW - VESTING / 4 + VESTING));
+        }
+
+        #[test]
+        fn positions_without_vesting_have_no_vesting_end() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.vesting_end(SENDER), U256::ZERO);
+        }
+
+        #[test]
+        fn proceeds_land_at_the_treasury() {
+            let (_chain, mut sale) = deploy(config());
+            assert_eq!(sale.treasury(), TREASURY);
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(5)]).is_ok());
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(5 * PRICE));
+
+            assert!(sale.set_treasury(BOB).is_ok());
+            assert!(sale.purchase_tokens_for(vec![BOB], vec![u(2)]).is_ok());
+            assert_eq!(host::balance(CURRENCY, BOB), u(2 * PRICE));
+            assert_eq!(sale.owner(), SENDER);
+        }
+
+        #[test]
+        fn treasury_defaults_to_the_owner() {
+            let (_chain, mut sale) = deploy(SaleConfig { treasury: Address::ZERO, ..config() });
+            assert_eq!(sale.treasury(), SENDER);
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert_eq!(host::balance(CURRENCY, SENDER), u(BUDGET));
+        }
+
+        #[test]
+        fn only_the_owner_can_move_the_treasury() {
+            let (_chain, mut sale) = deploy(config());
+            sale.owner.set(ALICE);
+            assert!(matches!(sale.set_treasury(BOB), Err(Errors::OnlyOwner(_))));
+            assert_eq!(sale.treasury(), TREASURY);
+        }
+
+        #[test]
+        fn purchase_fee_is_split_from_the_proceeds() {
+            for (fee_bps, fee) in [(0u64, 0u64), (250, 250), (1, 1), (10_000, 10_000)] {
+                let (_chain, mut sale) = deploy(SaleConfig { fee_bps: u(fee_bps), fee_recipient: BOB, ..config() });
+                assert!(sale.purchase_tokens(u(10)).is_ok());
+
+                assert_eq!(host::balance(CURRENCY, BOB), u(fee));
+                assert_eq!(host::balance(CURRENCY, TREASURY), u(10 * PRICE - fee));
+                let collected: Vec<_> = host::events::<FeeCollected>().iter().map(|event| (event.recipient, event.amount)).collect();
+                assert_eq!(collected, if fee == 0 { Vec::new() } else { vec![(BOB, u(fee))] });
+            }
+        }
+
+        #[test]
+        fn purchase_fee_must_be_a_percentage_with_a_recipient() {
+            let (chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let result = sale.init_with_config(SaleConfig { fee_bps: u(10_001), fee_recipient: BOB, ..config() });
+            assert!(matches!(result, Err(Errors::InvalidPercentage(_))));
+            drop(chain);
+
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let result = sale.init_with_config(SaleConfig { fee_bps: u(250), ..config() });
+            assert!(matches!(result, Err(Errors::ZeroValueArgumentInjected(_))));
+        }
+
+        #[test]
+        fn multiple_purchase_mode_is_a_configuration_choice() {
+            let (chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            assert!(sale.allow_multiple_purchases());
+            assert!(sale.purchase_tokens_for(vec![ALICE, ALICE], vec![u(3), u(4)]).is_ok());
+            assert_eq!(sale.tokens_purchased.get(ALICE), tokens(7));
+            assert_eq!(sale.unique_buyers(), u(1));
+            drop(chain);
+
+            let (_chain, mut sale) = deploy(config());
+            assert!(!sale.allow_multiple_purchases());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(3), u(4)]).is_ok());
+            assert!(matches!(sale.purchase_tokens_for(vec![ALICE], vec![u(1)]), Err(Errors::OnlyOnePurchase(_))));
+        }
+
+        #[test]
+        fn sale_phase_follows_the_sale_through_each_phase() {
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            assert_eq!(sale.sale_phase(), SALE_PHASE_UNINITIALIZED);
+
+            assert!(sale.init_with_config(SaleConfig { sale_start: u(NOW + 1), sale_end: u(NOW + 100), ..config() }).is_ok());
+            assert_eq!(sale.sale_phase(), SALE_PHASE_NOT_STARTED);
+
+            sale.sale_start.set(u(NOW));
+            assert_eq!(sale.sale_phase(), SALE_PHASE_ACTIVE);
+
+            assert!(sale.purchase_tokens(u(SUPPLY)).is_ok());
+            assert_eq!(sale.sale_phase(), SALE_PHASE_SOLD_OUT);
+
+            sale.sale_end.set(u(NOW - 1));
+            assert_eq!(sale.sale_phase(), SALE_PHASE_ENDED);
+        }
+
+        #[test]
+        fn closed_sales_are_in_the_ended_phase() {
+            let (_chain, mut sale) = deploy(config());
+            assert_eq!(sale.sale_phase(), SALE_PHASE_ACTIVE);
+            assert!(sale.close_sale().is_ok());
+            assert_eq!(sale.sale_phase(), SALE_PHASE_ENDED);
+        }
+
+        #[test]
+        fn permit_grants_the_allowance_inside_the_purchase() {
+            let (_chain, mut sale) = deploy(config());
+            host::with_token(CURRENCY, |mock| mock.supports_permit = true);
+
+            assert!(sale.purchase_tokens_with_permit(u(5), u(NOW + 60), 27, B256::repeat_byte(1), B256::repeat_byte(2)).is_ok());
+            let permits = host::with_token(CURRENCY, |mock| mock.permits.clone());
+            assert_eq!(permits, vec![(SENDER, CONTRACT, u(5 * PRICE), u(NOW + 60))]);
+            assert_eq!(sale.tokens_purchased.get(SENDER), tokens(5));
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(5 * PRICE));
+        }
+
+        #[test]
+        fn currencies_without_permit_fail_the_purchase() {
+            let (_chain, mut sale) = deploy(config());
+            let result = sale.purchase_tokens_with_permit(u(5), u(NOW + 60), 27, B256::ZERO, B256::ZERO);
+            assert!(matches!(result, Err(Errors::PermitFailed(_))));
+            assert_eq!(sale.tokens_purchased.get(SENDER), U256::ZERO);
+        }
+
+        #[test]
+        fn increased_supply_is_backed_and_can_be_sold() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(SUPPLY)]).is_ok());
+            assert!(matches!(sale.purchase_tokens(u(1)), Err(Errors::SoldOut(_))));
+
+            host::mint(TOKEN, SENDER, tokens(100));
+            assert!(sale.increase_total_available(tokens(100)).is_ok());
+            assert_eq!(host::balance(TOKEN, CONTRACT), tokens(SUPPLY + 100));
+            let increase = &host::events::<SaleSupplyIncreased>()[0];
+            assert_eq!((increase.additional, increase.totalAvailable), (tokens(100), tokens(SUPPLY + 100)));
+
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert!(sale.is_fully_backed());
+            assert!(matches!(sale.purchase_tokens(u(1)), Err(Errors::SoldOut(_))));
+        }
+
+        #[test]
+        fn supply_increases_must_be_whole_tokens_paid_for_by_the_owner() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(matches!(sale.increase_total_available(tokens(1) / u(2)), Err(Errors::UnevenSupply(_))));
+
+            // The owner holds none of the token being sold
+            let result = transact(&mut sale, |sale| sale.increase_total_available(tokens(100)));
+            assert!(matches!(result, Err(Errors::TransferFailed(_))));
+            assert_eq!(sale.total_tokens_available(), tokens(SUPPLY));
+        }
+
+        #[test]
+        fn struct_and_positional_initialization_store_the_same_state() {
+            let config = || SaleConfig {
+                sale_start: u(NOW - 100),
+                sale_end: u(NOW + 100),
+                allowlist_enabled: true,
+                cliff_length_in_seconds: u(VESTING / 10),
+                instant_unlock_bps: u(500),
+                fee_bps: u(250),
+                fee_recipient: BOB,
+                allow_multiple_purchases: true,
+                currency_alt: OTHER_TOKEN,
+                price_per_token_alt: u(2 * PRICE),
+                require_prefunded: true,
+                ..vesting_config()
+            };
+
+            let (chain, sale) = deploy(config());
+            let from_struct = host::storage();
+            assert_eq!((sale.token(), sale.currency(), sale.currency_alt()), (TOKEN, CURRENCY, OTHER_TOKEN));
+            drop(chain);
+
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let SaleConfig {
+                token, currency, price_per_token, total_tokens_available, total_vesting_length_in_seconds, nft_claim,
+                token_decimals, sale_start, sale_end, allowlist_enabled, cliff_length_in_seconds, instant_unlock_bps,
+                treasury, fee_bps, fee_recipient, allow_multiple_purchases, currency_alt, price_per_token_alt, require_prefunded
+            } = config();
+            assert!(sale.init(
+                token, currency, price_per_token, total_tokens_available, total_vesting_length_in_seconds, nft_claim,
+                token_decimals, sale_start, sale_end, allowlist_enabled, cliff_length_in_seconds, instant_unlock_bps,
+                treasury, fee_bps, fee_recipient, allow_multiple_purchases, currency_alt, price_per_token_alt, require_prefunded
+            ).is_ok());
+            assert_eq!(host::storage(), from_struct);
+        }
+
+        #[test]
+        fn fully_claimed_positions_are_pruned_and_cannot_be_claimed_again() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            host::set_nft_owner(NFT, u(1), SENDER);
+            assert!(sale.enable_tokenized_vesting(NFT, u(1)).is_ok());
+            assert!(matches!(sale.prune_position(), Err(Errors::PositionNotFullyClaimed(_))));
+
+            vest_for(&mut sale, SENDER, VESTING);
+            assert_eq!(sale.claim_tokens_by_nft(SENDER).ok(), Some(tokens(100)));
+            assert_eq!(sale.tokens_purchased_at.get(SENDER), U256::ZERO);
+            assert_eq!(sale.tokens_claimed_at.get(SENDER), U256::ZERO);
+            assert!(!sale.vesting_tokenized.get(SENDER));
+            assert_eq!(sale.user_nft_collection.get(SENDER), Address::ZERO);
+            assert_eq!((sale.tokens_purchased.get(SENDER), sale.tokens_claimed.get(SENDER)), (tokens(100), tokens(100)));
+
+            // Pruning again is harmless and the position still counts as purchased and claimed
+            assert!(sale.prune_position().is_ok());
+            assert!(matches!(sale.claim_tokens(), Err(Errors::AllTokensClaimed(_))));
+            assert!(matches!(sale.purchase_tokens(u(1)), Err(Errors::OnlyOnePurchase(_))));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(100));
+        }
+
+        #[test]
+        fn only_buyers_can_prune_a_position() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(matches!(sale.prune_position(), Err(Errors::NoTokensPurchased(_))));
+        }
+
+        #[test]
+        fn total_raised_is_the_sum_of_every_purchase_cost() {
+            let (_chain, mut sale) = deploy(SaleConfig {
+                allow_multiple_purchases: true,
+                fee_bps: u(250),
+                fee_recipient: BOB,
+                ..config()
+            });
+            assert_eq!(sale.total_raised(), U256::ZERO);
+
+            assert!(sale.purchase_tokens(u(3)).is_ok());
+            assert!(sale.purchase_tokens(u(7)).is_ok());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(1), u(4)]).is_ok());
+
+            // The fee comes out of the cost so the whole cost counts towards the raise
+            assert_eq!(sale.total_raised(), u(15 * PRICE));
+            assert_eq!(sale.total_raised(), sale.total_currency_raised());
+            assert_eq!(host::balance(CURRENCY, TREASURY) + host::balance(CURRENCY, BOB), u(15 * PRICE));
+        }
+
+        #[test]
+        fn vesting_sales_are_deployed_with_their_nft_collection() {
+            let (chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let SaleConfig { token, currency, price_per_token, total_tokens_available, .. } = config();
+            let result = sale.init(
+                token, currency, price_per_token, total_tokens_available, u(VESTING), Address::ZERO, 18, U256::ZERO,
+                U256::ZERO, false, U256::ZERO, U256::ZERO, TREASURY, U256::ZERO, Address::ZERO, false, Address::ZERO,
+                U256::ZERO, false
+            );
+            assert!(matches!(result, Err(Errors::ZeroValueArgumentInjected(_))));
+            assert!(!sale.is_initialized());
+            drop(chain);
+
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert_eq!(sale.nft_claim(), NFT);
+            assert!(sale.is_approved_nft_collection(NFT));
+
+            // The collection cannot be cleared later either
+            assert!(matches!(sale.set_nft_claim(Address::ZERO), Err(Errors::ZeroValueArgumentInjected(_))));
+            assert_eq!(sale.nft_claim(), NFT);
+        }
+
+        #[test]
+        fn fee_on_transfer_currencies_are_rejected() {
+            let (_chain, mut sale) = deploy(config());
+            host::with_token(CURRENCY, |mock| mock.transfer_fee_bps = 100);
+
+            let result = transact(&mut sale, |sale| sale.purchase_tokens(u(10)));
+            assert!(matches!(result, Err(Errors::FeeOnTransferUnsupported(_))));
+            assert_eq!(sale.tokens_purchased.get(SENDER), U256::ZERO);
+            assert_eq!(sale.total_currency_raised(), U256::ZERO);
+            assert_eq!(host::balance(CURRENCY, SENDER), u(BUDGET));
+
+            // The same purchase goes through once the currency stops taking a fee
+            host::with_token(CURRENCY, |mock| mock.transfer_fee_bps = 0);
+            assert!(sale.purchase_tokens(u(10)).is_ok());
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(10 * PRICE));
+        }
+
+        #[test]
+        fn nft_holders_claim_every_position_they_control_at_once() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(100), u(50)]).is_ok());
+            assert!(sale.tokenize_vesting(ALICE, NFT, u(1)).is_ok());
+            assert!(sale.tokenize_vesting(BOB, NFT, u(2)).is_ok());
+            host::set_nft_owner(NFT, u(1), SENDER);
+            host::set_nft_owner(NFT, u(2), SENDER);
+            vest_for(&mut sale, ALICE, VESTING / 2);
+            vest_for(&mut sale, BOB, VESTING / 2);
+
+            assert_eq!(sale.claim_tokens_by_nft_batch(vec![ALICE, BOB]).ok(), Some(tokens(75)));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(75));
+            assert_eq!((sale.claimed_by(ALICE), sale.claimed_by(BOB)), (tokens(50), tokens(25)));
+            let claims: Vec<_> = host::events::<NftClaim>().iter().map(|claim| (claim.user, claim.tokenId, claim.amount)).collect();
+            assert_eq!(claims, vec![(ALICE, u(1), tokens(50)), (BOB, u(2), tokens(25))]);
+        }
+
+        #[test]
+        fn batch_nft_claims_revert_on_positions_the_sender_does_not_control() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(100), u(50)]).is_ok());
+            assert!(sale.tokenize_vesting(ALICE, NFT, u(1)).is_ok());
+            host::set_nft_owner(NFT, u(1), SENDER);
+            vest_for(&mut sale, ALICE, VESTING / 2);
+            vest_for(&mut sale, BOB, VESTING / 2);
+            assert!(matches!(sale.claim_tokens_by_nft_batch(vec![]), Err(Errors::ZeroValueArgumentInjected(_))));
+            assert!(matches!(sale.claim_tokens_by_nft_batch(vec![ALICE, BOB]), Err(Errors::NotTokenized(_))));
+
+            assert!(sale.tokenize_vesting(BOB, NFT, u(2)).is_ok());
+            host::set_nft_owner(NFT, u(2), ALICE);
+            assert!(matches!(sale.claim_tokens_by_nft_batch(vec![ALICE, BOB]), Err(Errors::OnlyOwner(_))));
+
+            // Nothing was released for the position the sender does control either
+            assert_eq!(sale.claimed_by(ALICE), U256::ZERO);
+            assert_eq!(host::balance(TOKEN, SENDER), U256::ZERO);
+        }
+
+        #[test]
+        fn is_initialized_reports_whether_init_has_run() {
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            assert!(!sale.is_initialized());
+            assert!(sale.init_with_config(config()).is_ok());
+            assert!(sale.is_initialized());
+
+            // A fresh view of the contract reads the same from storage
+            let sale = unsafe { TokenSaleWithTokenizedVesting::new(U256::ZERO, 0) };
+            assert!(sale.is_initialized());
+        }
+
+        #[test]
+        fn early_and_late_buyers_vesting_from_the_sale_end_finish_together() {
+            let (_chain, mut sale) = deploy(SaleConfig { sale_end: u(NOW + 1_000), ..vesting_config() });
+            assert!(sale.set_vest_from_sale_end(true).is_ok());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(100), u(100)]).is_ok());
+
+            // Alice bought well before Bob, who bought shortly before the sale ended half a vesting length ago
+            let sale_end = NOW - VESTING / 2;
+            sale.sale_end.set(u(sale_end));
+            vest_for(&mut sale, ALICE, VESTING / 2 + 900);
+            vest_for(&mut sale, BOB, VESTING / 2 + 100);
+            assert_eq!(sale.vesting_start(ALICE), sale.vesting_start(BOB));
+            assert_eq!(sale.vesting_end(ALICE), u(sale_end + VESTING));
+            assert_eq!(sale.vesting_end(BOB), u(sale_end + VESTING));
+            assert_eq!(sale.claimable_amount(ALICE), tokens(50));
+            assert_eq!(sale.claimable_amount(BOB), tokens(50));
+
+            // Once the vesting length has passed since the sale ended, both are fully vested in the same second
+            sale.sale_end.set(u(NOW - VESTING));
+            vest_for(&mut sale, ALICE, VESTING + 900);
+            vest_for(&mut sale, BOB, VESTING + 100);
+            assert_eq!(sale.claimable_amount(ALICE), tokens(100));
+            assert_eq!(sale.claimable_amount(BOB), tokens(100));
+            sale.sale_end.set(u(NOW - VESTING + 1));
+            assert!(sale.claimable_amount(ALICE) < tokens(100));
+            assert!(sale.claimable_amount(BOB) < tokens(100));
+        }
+
+        #[test]
+        fn without_vesting_from_the_sale_end_buyers_vest_from_their_purchase() {
+            let (_chain, mut sale) = deploy(SaleConfig { sale_end: u(NOW + 1_000), ..vesting_config() });
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(100), u(100)]).is_ok());
+            assert!(matches!(sale.set_vest_from_sale_end(true), Err(Errors::TokensAlreadyPurchased(_))));
+
+            sale.sale_end.set(u(NOW - VESTING / 2));
+            vest_for(&mut sale, ALICE, VESTING / 2 + 900);
+            vest_for(&mut sale, BOB, VESTING / 2 + 100);
+            assert!(sale.vesting_end(ALICE) < sale.vesting_end(BOB));
+            assert!(sale.claimable_amount(ALICE) > sale.claimable_amount(BOB));
+        }
+
+        #[test]
+        fn only_the_final_vested_claim_marks_the_position_fully_claimed() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+            assert!(host::events::<PositionFullyClaimed>().is_empty());
+
+            vest_for(&mut sale, SENDER, VESTING);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(50)));
+            let completed = host::events::<PositionFullyClaimed>();
+            assert_eq!(completed.len(), 1);
+            assert_eq!((completed[0].user, completed[0].total), (SENDER, tokens(100)));
+
+            assert!(matches!(sale.claim_tokens(), Err(Errors::AllTokensClaimed(_))));
+            assert_eq!(host::events::<PositionFullyClaimed>().len(), 1);
+        }
+
+        #[test]
+        fn unlocked_claims_mark_the_position_fully_claimed() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(5)));
+
+            // Topping up reopens the position, which is complete again once the top up is claimed
+            assert!(sale.purchase_tokens(u(3)).is_ok());
+            assert_eq!(sale.claim_unlocked_tokens().ok(), Some(tokens(3)));
+            let totals: Vec<_> = host::events::<PositionFullyClaimed>().iter().map(|completed| completed.total).collect();
+            assert_eq!(totals, vec![tokens(5), tokens(8)]);
+        }
+
+        #[test]
+        fn blacklisted_buyers_can_neither_purchase_nor_claim_until_unblocked() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![SENDER, ALICE], vec![u(100), u(10)]).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+
+            assert!(sale.set_blacklist(SENDER, true).is_ok());
+            assert!(sale.is_blacklisted(SENDER));
+            assert!(matches!(sale.claim_tokens(), Err(Errors::Blacklisted(_))));
+            assert!(matches!(sale.claim_tokens_for(SENDER), Err(Errors::Blacklisted(_))));
+            assert!(matches!(sale.purchase_tokens_for(vec![SENDER], vec![u(1)]), Err(Errors::Blacklisted(_))));
+
+            assert!(sale.set_blacklist(SENDER, false).is_ok());
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+            let updates: Vec<_> = host::events::<BlacklistUpdated>().iter().map(|update| (update.user, update.blocked)).collect();
+            assert_eq!(updates, vec![(SENDER, true), (SENDER, false)]);
+        }
+
+        #[test]
+        fn claims_cannot_be_sent_to_a_blacklisted_recipient() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert!(sale.set_blacklist(ALICE, true).is_ok());
+
+            assert!(matches!(sale.claim_tokens_to(ALICE), Err(Errors::Blacklisted(_))));
+            assert!(matches!(sale.purchase_tokens_for(vec![ALICE], vec![u(1)]), Err(Errors::Blacklisted(_))));
+            assert_eq!(sale.claim_tokens_to(BOB).ok(), Some(tokens(25)));
+        }
+
+        #[test]
+        fn only_the_owner_can_blacklist() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(matches!(sale.set_blacklist(Address::ZERO, true), Err(Errors::ZeroValueArgumentInjected(_))));
+            sale.owner.set(ALICE);
+            assert!(matches!(sale.set_blacklist(BOB, true), Err(Errors::OnlyOwner(_))));
+            assert!(!sale.is_blacklisted(BOB));
+        }
+
+        #[test]
+        fn buyer_cap_turns_away_new_buyers_but_not_top_ups() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            assert!(sale.set_max_buyers(u(2)).is_ok());
+            assert_eq!(sale.max_buyers(), u(2));
+
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB, ALICE], vec![u(1), u(2), u(3)]).is_ok());
+            assert_eq!(sale.unique_buyers(), u(2));
+            assert!(matches!(sale.purchase_tokens(u(1)), Err(Errors::BuyerCapReached(_))));
+
+            assert!(sale.purchase_tokens_for(vec![BOB], vec![u(5)]).is_ok());
+            assert_eq!(sale.tokens_purchased.get(BOB), tokens(7));
+            assert_eq!(sale.unique_buyers(), u(2));
+            assert!(matches!(sale.set_max_buyers(u(3)), Err(Errors::TokensAlreadyPurchased(_))));
+        }
+
+        #[test]
+        fn zero_buyer_cap_is_unlimited() {
+            let (_chain, mut sale) = deploy(config());
+            assert_eq!(sale.max_buyers(), U256::ZERO);
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB, SENDER], vec![u(1), u(1), u(1)]).is_ok());
+            assert_eq!(sale.unique_buyers(), u(3));
+        }
+
+        #[test]
+        fn stepped_release_only_unlocks_at_each_interval_boundary() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.set_release_interval(u(VESTING / 4)).is_ok());
+            assert_eq!(sale.release_interval_in_seconds(), u(VESTING / 4));
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+
+            vest_for(&mut sale, SENDER, VESTING / 4 - 1);
+            assert_eq!(sale.claimable_amount(SENDER), U256::ZERO);
+
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+
+            // Part way through the second interval nothing more has unlocked
+            vest_for(&mut sale, SENDER, VESTING / 2 - 1);
+            assert_eq!(sale.claimable_amount(SENDER), U256::ZERO);
+
+            vest_for(&mut sale, SENDER, 3 * VESTING / 4);
+            assert_eq!(sale.claimable_amount(SENDER), tokens(50));
+            vest_for(&mut sale, SENDER, VESTING);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(75)));
+        }
+
+        #[test]
+        fn release_interval_must_split_the_vesting_length_into_whole_steps() {
+            let (chain, mut sale) = deploy(vesting_config());
+            assert!(matches!(sale.set_release_interval(u(VESTING / 3)), Err(Errors::InvalidReleaseInterval(_))));
+            assert!(matches!(sale.set_release_interval(u(VESTING * 2)), Err(Errors::InvalidReleaseInterval(_))));
+            assert!(sale.set_release_interval(u(VESTING)).is_ok());
+            assert!(sale.set_release_interval(U256::ZERO).is_ok());
+            drop(chain);
+
+            let (_chain, mut sale) = deploy(config());
+            assert!(matches!(sale.set_release_interval(u(1)), Err(Errors::InvalidReleaseInterval(_))));
+        }
+
+        #[test]
+        fn initialization_cannot_run_twice() {
+            let (_chain, mut sale) = deploy(config());
+            assert_eq!(sale.init_version(), 1);
+            assert!(matches!(sale.init_with_config(config()), Err(Errors::AlreadyInitialized(_))));
+        }
+
+        #[test]
+        fn populated_storage_is_not_initialized_again() {
+            // Storage left behind by an earlier initialization whose `initialized` flag no longer lines up
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            sale.init_version.set(U8::from(1));
+            assert!(!sale.is_initialized());
+            assert!(matches!(sale.init_with_config(config()), Err(Errors::AlreadyInitialized(_))));
+        }
+
+        #[test]
+        fn tokens_for_currency_inverts_the_quote_and_rounds_down() {
+            let (_chain, sale) = deploy(config());
+            for amount in [0u64, 1, 7, SUPPLY] {
+                let cost = sale.quote_cost(u(amount)).ok().unwrap();
+                assert_eq!(sale.tokens_for_currency(cost).ok(), Some(u(amount)));
+            }
+
+            // Budgets between two multiples of the price only buy the whole tokens they cover
+            assert_eq!(sale.tokens_for_currency(u(PRICE - 1)).ok(), Some(U256::ZERO));
+            assert_eq!(sale.tokens_for_currency(u(7 * PRICE + PRICE / 2)).ok(), Some(u(7)));
+            assert!(sale.quote_cost(u(7)).ok().unwrap() <= u(7 * PRICE + PRICE / 2));
+        }
+
+        #[test]
+        fn relayers_claim_vested_tokens_to_the_buyer() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(100)]).is_ok());
+            vest_for(&mut sale, ALICE, VESTING / 4);
+
+            // The sender relays the claim for Alice
+            assert_eq!(sale.claim_tokens_for(ALICE).ok(), Some(tokens(25)));
+            assert_eq!(host::balance(TOKEN, ALICE), tokens(25));
+            assert_eq!(host::balance(TOKEN, SENDER), U256::ZERO);
+            assert_eq!(sale.claimed_by(ALICE), tokens(25));
+            let claim = &host::events::<TokensClaimed>()[0];
+            assert_eq!((claim.user, claim.recipient), (ALICE, ALICE));
+        }
+
+        #[test]
+        fn relayers_claim_unlocked_tokens_to_the_buyer() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(5)]).is_ok());
+            assert_eq!(sale.claim_tokens_for(ALICE).ok(), Some(tokens(5)));
+            assert_eq!(host::balance(TOKEN, ALICE), tokens(5));
+        }
+
+        #[test]
+        fn relayers_cannot_claim_tokenized_positions() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(100)]).is_ok());
+            assert!(sale.tokenize_vesting(ALICE, NFT, u(1)).is_ok());
+            vest_for(&mut sale, ALICE, VESTING / 4);
+            assert!(matches!(sale.claim_tokens_for(ALICE), Err(Errors::AlreadyTokenized(_))));
+            assert_eq!(host::balance(TOKEN, ALICE), U256::ZERO);
+        }
+
+        #[test]
+        fn emergency_withdrawal_only_works_while_paused() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(matches!(sale.emergency_withdraw(TREASURY), Err(Errors::ExpectedPause(_))));
+
+            assert!(sale.pause().is_ok());
+            sale.owner.set(ALICE);
+            assert!(matches!(sale.emergency_withdraw(TREASURY), Err(Errors::OnlyOwner(_))));
+            assert!(!sale.drained());
+            assert_eq!(host::balance(TOKEN, CONTRACT), tokens(SUPPLY));
+        }
+
+        #[test]
+        fn emergency_withdrawal_moves_everything_and_disables_claims() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+
+            assert!(sale.pause().is_ok());
+            assert!(sale.emergency_withdraw(TREASURY).is_ok());
+            assert!(sale.drained());
+            assert_eq!(host::balance(TOKEN, TREASURY), tokens(SUPPLY));
+            assert_eq!(host::balance(TOKEN, CONTRACT), U256::ZERO);
+            let withdrawal = &host::events::<EmergencyWithdrawal>()[0];
+            assert_eq!((withdrawal.recipient, withdrawal.amount), (TREASURY, tokens(SUPPLY)));
+
+            // Unpausing does not bring claims or purchases back
+            assert!(sale.unpause().is_ok());
+            assert!(matches!(sale.claim_tokens(), Err(Errors::ContractDrained(_))));
+            assert!(matches!(sale.claim_tokens_for(SENDER), Err(Errors::ContractDrained(_))));
+            assert!(matches!(sale.purchase_tokens_for(vec![ALICE], vec![u(1)]), Err(Errors::ContractDrained(_))));
+        }
+
+        #[test]
+        fn price_rises_after_the_quote_fail_the_protected_purchase() {
+            let (_chain, mut sale) = deploy(config());
+            let quoted = sale.quote_cost(u(10)).ok().unwrap();
+
+            // The price is raised before the purchase lands
+            assert!(sale.set_price_per_token(u(2 * PRICE)).is_ok());
+            assert!(matches!(sale.purchase_tokens_max_cost(u(10), quoted), Err(Errors::CostExceedsMax(_))));
+            assert_eq!(host::balance(CURRENCY, SENDER), u(BUDGET));
+            assert_eq!(sale.tokens_purchased.get(SENDER), U256::ZERO);
+
+            assert!(sale.purchase_tokens_max_cost(u(10), u(20 * PRICE)).is_ok());
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(20 * PRICE));
+        }
+
+        #[test]
+        fn backing_compares_the_balance_with_what_is_owed() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert!(sale.is_fully_backed());
+
+            // The owner only deposited part of what was sold
+            host::with_token(TOKEN, |mock| mock.balances.insert(CONTRACT, tokens(99)));
+            assert!(!sale.is_fully_backed());
+
+            // Claims reduce what is owed along with the balance
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert!(sale.claim_tokens().is_ok());
+            assert_eq!(sale.total_outstanding(), tokens(75));
+            assert!(!sale.is_fully_backed());
+
+            host::mint(TOKEN, CONTRACT, tokens(1));
+            assert!(sale.is_fully_backed());
+        }
+
+        #[test]
+        fn nft_claims_are_attributed_to_the_current_holder() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(100)]).is_ok());
+            assert!(sale.tokenize_vesting(ALICE, NFT, u(7)).is_ok());
+            host::set_nft_owner(NFT, u(7), ALICE);
+            vest_for(&mut sale, ALICE, VESTING / 4);
+            assert!(matches!(sale.claim_tokens_by_nft(ALICE), Err(Errors::OnlyOwner(_))));
+
+            // Alice sells the NFT to the sender who claims what has vested
+            host::set_nft_owner(NFT, u(7), SENDER);
+            assert_eq!(sale.claim_tokens_by_nft(ALICE).ok(), Some(tokens(25)));
+            let claims = host::events::<NftClaim>();
+            assert_eq!(claims.len(), 1);
+            assert_eq!((claims[0].user, claims[0].tokenId, claims[0].claimant, claims[0].amount), (ALICE, u(7), SENDER, tokens(25)));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(25));
+        }
+
+        #[test]
+        fn simulated_purchases_report_the_cost_and_whether_they_fit() {
+            let (_chain, mut sale) = deploy(config());
+            assert_eq!(sale.simulate_purchase(u(10)), (u(10 * PRICE), true));
+            assert_eq!(sale.simulate_purchase(u(SUPPLY + 1)), (u((SUPPLY + 1) * PRICE), false));
+
+            // Simulating leaves no trace
+            assert_eq!(sale.total_tokens_purchased(), U256::ZERO);
+            assert_eq!(host::balance(CURRENCY, SENDER), u(BUDGET));
+
+            // Once the sender has purchased, single purchase sales reject another
+            assert!(sale.purchase_tokens(u(10)).is_ok());
+            assert_eq!(sale.simulate_purchase(u(1)), (u(PRICE), false));
+        }
+
+        #[test]
+        fn simulated_purchases_fail_once_sold_out() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(SUPPLY)]).is_ok());
+            assert_eq!(sale.simulate_purchase(u(1)), (u(PRICE), false));
+        }
+
+        #[test]
+        fn accelerated_buyers_claim_more_sooner() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(100), u(100)]).is_ok());
+            vest_for(&mut sale, ALICE, VESTING / 4);
+            vest_for(&mut sale, BOB, VESTING / 4);
+
+            // Alice now vests over half the original length
+            assert!(sale.accelerate_vesting(ALICE, u(NOW + VESTING / 4)).is_ok());
+            assert_eq!(sale.vesting_end(ALICE), u(NOW + VESTING / 4));
+            assert_eq!(sale.vesting_length_of(ALICE), u(VESTING / 2));
+
+            // The terms Alice purchased on are still reported as they were
+            assert_eq!(sale.purchase_terms_of(ALICE), (u(PRICE), u(VESTING)));
+            assert_eq!(sale.claimable_amount(ALICE), tokens(50));
+            assert_eq!(sale.claimable_amount(BOB), tokens(25));
+            let accelerated = &host::events::<VestingAccelerated>()[0];
+            assert_eq!((accelerated.user, accelerated.oldEnd, accelerated.newEnd), (ALICE, u(NOW + 3 * VESTING / 4), u(NOW + VESTING / 4)));
+
+            assert_eq!(sale.claim_tokens_for(ALICE).ok(), Some(tokens(50)));
+            vest_for(&mut sale, ALICE, VESTING / 2);
+            assert_eq!(sale.claim_tokens_for(ALICE).ok(), Some(tokens(50)));
+        }
+
+        #[test]
+        fn vesting_can_only_be_brought_forward_by_the_owner() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(100)]).is_ok());
+            let end = NOW + VESTING;
+
+            assert!(matches!(sale.accelerate_vesting(ALICE, u(end)), Err(Errors::InvalidVestingEnd(_))));
+            assert!(matches!(sale.accelerate_vesting(ALICE, u(end + 1)), Err(Errors::InvalidVestingEnd(_))));
+            assert!(matches!(sale.accelerate_vesting(ALICE, u(NOW)), Err(Errors::InvalidVestingEnd(_))));
+            assert!(matches!(sale.accelerate_vesting(BOB, u(end - 1)), Err(Errors::NoTokensVested(_))));
+            assert_eq!(sale.vesting_end(ALICE), u(end));
+
+            sale.owner.set(BOB);
+            assert!(matches!(sale.accelerate_vesting(ALICE, u(end - 1)), Err(Errors::OnlyOwner(_))));
+        }
+
+        #[test]
+        fn claimed_totals_follow_linear_and_full_claims() {
+            let (_chain, mut sale) = deploy(SaleConfig { instant_unlock_bps: u(1_000), ..vesting_config() });
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(100), u(50)]).is_ok());
+
+            // The instant unlock is released as part of the purchase
+            assert_eq!((sale.claimed_by(ALICE), sale.claimed_by(BOB)), (tokens(10), tokens(5)));
+            assert_eq!(sale.total_claimed(), tokens(15));
+
+            vest_for(&mut sale, ALICE, VESTING / 2);
+            assert_eq!(sale.claim_tokens_for(ALICE).ok(), Some(tokens(45)));
+            assert_eq!(sale.claimed_by(ALICE), tokens(55));
+            assert_eq!(sale.total_claimed(), tokens(60));
+
+            vest_for(&mut sale, BOB, VESTING);
+            assert_eq!(sale.claim_tokens_for(BOB).ok(), Some(tokens(45)));
+            assert_eq!(sale.claimed_by(BOB), tokens(50));
+            assert_eq!(sale.total_claimed(), tokens(105));
+            assert_eq!(sale.total_outstanding(), tokens(45));
+        }
+
+        #[test]
+        fn blocked_token_ids_cannot_tokenize_vesting() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            host::set_nft_owner(NFT, u(1), SENDER);
+            host::set_nft_owner(NFT, u(2), SENDER);
+
+            assert!(sale.set_blocked_token_id(u(1), true).is_ok());
+            assert!(sale.is_blocked_token_id(u(1)));
+            assert!(matches!(sale.enable_tokenized_vesting(NFT, u(1)), Err(Errors::BlockedTokenId(_))));
+            assert!(!sale.vesting_tokenized.get(SENDER));
+
+            assert!(sale.enable_tokenized_vesting(NFT, u(2)).is_ok());
+            assert_eq!(sale.nft_claim_token_id.get(SENDER), u(2));
+        }
+
+        #[test]
+        fn unblocked_token_ids_can_tokenize_again() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            host::set_nft_owner(NFT, u(1), SENDER);
+            assert!(sale.set_blocked_token_id(u(1), true).is_ok());
+            assert!(sale.set_blocked_token_id(u(1), false).is_ok());
+            assert!(sale.enable_tokenized_vesting(NFT, u(1)).is_ok());
+
+            let updates: Vec<_> = host::events::<BlockedTokenIdUpdated>().iter().map(|update| update.blocked).collect();
+            assert_eq!(updates, vec![true, false]);
+        }
+
+        #[test]
+        fn purchases_landing_after_their_deadline_are_rejected() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            assert!(matches!(sale.purchase_tokens_before(u(5), u(NOW - 1)), Err(Errors::DeadlinePassed(_))));
+            assert_eq!(sale.tokens_purchased.get(SENDER), U256::ZERO);
+
+            // The deadline itself is still in time
+            assert!(sale.purchase_tokens_before(u(5), u(NOW)).is_ok());
+            assert!(sale.purchase_tokens_before(u(5), u(NOW + 60)).is_ok());
+            assert_eq!(sale.tokens_purchased.get(SENDER), tokens(10));
+        }
+
+        #[test]
+        fn nft_claim_can_be_replaced_until_a_position_is_tokenized() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            host::deploy_nft(OTHER_NFT);
+            assert!(matches!(sale.set_nft_claim(ALICE), Err(Errors::NotAContract(_))));
+
+            assert!(sale.set_nft_claim(OTHER_NFT).is_ok());
+            assert_eq!(sale.nft_claim(), OTHER_NFT);
+            assert!(sale.is_approved_nft_collection(OTHER_NFT));
+            assert!(!sale.is_approved_nft_collection(NFT));
+            let update = &host::events::<NftClaimUpdated>()[0];
+            assert_eq!((update.previous, update.next), (NFT, OTHER_NFT));
+
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            host::set_nft_owner(OTHER_NFT, u(1), SENDER);
+            assert!(sale.enable_tokenized_vesting(OTHER_NFT, u(1)).is_ok());
+            assert!(matches!(sale.set_nft_claim(NFT), Err(Errors::PositionsAlreadyTokenized(_))));
+            assert_eq!(sale.nft_claim(), OTHER_NFT);
+        }
+
+        #[test]
+        fn claims_either_side_of_the_vesting_end_release_exactly_the_purchase() {
+            let allocation = 1_000_003u64;
+            let (chain, mut sale) = deploy(vesting_config());
+            assert!(sale.set_fractional_purchases(true).is_ok());
+            assert!(sale.purchase_tokens(u(allocation)).is_ok());
+
+            // One second before the end the linear amount is still short of the purchase
+            vest_for(&mut sale, SENDER, VESTING - 1);
+            let before_end = sale.claim_tokens().ok().unwrap();
+            assert_eq!(before_end, u(allocation) * u(VESTING - 1) / u(VESTING));
+            assert!(before_end < u(allocation));
+
+            // In the exact second vesting ends the remainder is released and nothing is left a second later
+            vest_for(&mut sale, SENDER, VESTING);
+            assert_eq!(sale.claim_tokens().ok(), Some(u(allocation) - before_end));
+            vest_for(&mut sale, SENDER, VESTING + 1);
+            assert!(matches!(sale.claim_tokens(), Err(Errors::AllTokensClaimed(_))));
+            assert_eq!(host::balance(TOKEN, SENDER), u(allocation));
+            drop(chain);
+
+            for elapsed in [VESTING, VESTING + 1] {
+                let (_chain, mut sale) = deploy(vesting_config());
+                assert!(sale.set_fractional_purchases(true).is_ok());
+                assert!(sale.purchase_tokens(u(allocation)).is_ok());
+                vest_for(&mut sale, SENDER, elapsed);
+                assert_eq!(sale.claimable_amount(SENDER), u(allocation));
+                assert_eq!(sale.claim_tokens().ok(), Some(u(allocation)));
+            }
+        }
+
+        #[test]
+        fn purchases_are_priced_in_whichever_currency_the_buyer_picks() {
+            let (_chain, mut sale) = deploy(SaleConfig {
+                allow_multiple_purchases: true,
+                currency_alt: OTHER_TOKEN,
+                price_per_token_alt: u(2 * PRICE),
+                ..config()
+            });
+            host::deploy_token(OTHER_TOKEN, MockToken::default());
+            host::mint(OTHER_TOKEN, SENDER, u(BUDGET));
+
+            assert!(sale.purchase_tokens_with(u(3), false).is_ok());
+            assert!(sale.purchase_tokens_with(u(4), true).is_ok());
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(3 * PRICE));
+            assert_eq!(host::balance(OTHER_TOKEN, TREASURY), u(8 * PRICE));
+            assert_eq!((sale.total_currency_raised(), sale.total_currency_alt_raised()), (u(3 * PRICE), u(8 * PRICE)));
+            assert_eq!(sale.quote_cost_alt(u(4)).ok(), Some(u(8 * PRICE)));
+            assert_eq!(sale.tokens_purchased.get(SENDER), tokens(7));
+        }
+
+        #[test]
+        fn alternate_currency_must_be_configured_and_distinct() {
+            let (chain, mut sale) = deploy(config());
+            assert!(matches!(sale.purchase_tokens_with(u(1), true), Err(Errors::AltCurrencyNotConfigured(_))));
+            drop(chain);
+
+            let (chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let result = sale.init_with_config(SaleConfig { currency_alt: CURRENCY, price_per_token_alt: u(PRICE), ..config() });
+            assert!(matches!(result, Err(Errors::InvalidAltCurrency(_))));
+            drop(chain);
+
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            let result = sale.init_with_config(SaleConfig { currency_alt: OTHER_TOKEN, ..config() });
+            assert!(matches!(result, Err(Errors::ZeroValueArgumentInjected(_))));
+        }
+
+        #[test]
+        fn purchases_are_recorded_before_the_currency_is_called() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            let during_payment = Rc::new(RefCell::new(None));
+            let captured = Rc::clone(&during_payment);
+            host::with_token(CURRENCY, |mock| {
+                mock.on_transfer = Some(Rc::new(move || {
+                    let sale = unsafe { TokenSaleWithTokenizedVesting::new(U256::ZERO, 0) };
+                    let recorded = (sale.tokens_purchased.get(SENDER), sale.total_tokens_purchased(), sale.last_purchase_id());
+                    captured.borrow_mut().get_or_insert((recorded, host::storage(), host::events::<TokensPurchased>().len()));
+                }));
+            });
+
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            let (recorded, storage, purchases_logged) = during_payment.borrow_mut().take().unwrap();
+            assert_eq!(recorded, (tokens(5), tokens(5), u(1)));
+            assert_eq!(purchases_logged, 1);
+
+            // Releasing the reentrancy lock is the only write once the currency has been called
+            sale.locked.set(true);
+            assert_eq!(host::storage(), storage);
+            sale.locked.set(false);
+        }
+
+        #[test]
+        fn reentrant_currency_cannot_write_after_the_payment_is_taken() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            let during_payment = Rc::new(RefCell::new(None));
+            let captured = Rc::clone(&during_payment);
+            host::with_token(CURRENCY, |mock| {
+                mock.on_transfer = Some(Rc::new(move || {
+                    let mut sale = unsafe { TokenSaleWithTokenizedVesting::new(U256::ZERO, 0) };
+                    let locked = sale.locked.get();
+                    let rejected = matches!(sale.purchase_tokens_with_referrer(u(1), ALICE), Err(Errors::Reentrancy(_)));
+                    captured.borrow_mut().get_or_insert((locked, rejected, host::storage()));
+                }));
+            });
+
+            // The lock is held while the currency is called so its attempt to purchase again is turned away
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            let (locked, rejected, storage) = during_payment.borrow_mut().take().unwrap();
+            assert!(locked);
+            assert!(rejected);
+
+            // Nothing but releasing the lock is written once the currency has been called
+            sale.locked.set(true);
+            assert_eq!(host::storage(), storage);
+            sale.locked.set(false);
+            assert_eq!((sale.total_tokens_purchased(), sale.last_purchase_id()), (tokens(5), u(1)));
+        }
+
+        #[test]
+        fn partially_claimed_positions_migrate_to_a_new_wallet() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(100)]).is_ok());
+            vest_for(&mut sale, ALICE, VESTING / 4);
+            assert_eq!(sale.claim_tokens_for(ALICE).ok(), Some(tokens(25)));
+
+            assert!(sale.migrate_position(ALICE, BOB).is_ok());
+            assert_eq!((sale.tokens_purchased.get(BOB), sale.claimed_by(BOB)), (tokens(100), tokens(25)));
+            assert_eq!(sale.vesting_end(BOB), u(NOW - VESTING / 4 + VESTING));
+            assert_eq!((sale.tokens_purchased.get(ALICE), sale.claimed_by(ALICE)), (U256::ZERO, U256::ZERO));
+            let migrated = &host::events::<PositionMigrated>()[0];
+            assert_eq!((migrated.from, migrated.to, migrated.tokens), (ALICE, BOB, tokens(100)));
+
+            // The new wallet picks up the schedule where the old one left off
+            vest_for(&mut sale, BOB, VESTING / 2);
+            assert_eq!(sale.claim_tokens_for(BOB).ok(), Some(tokens(25)));
+            assert!(matches!(sale.claim_tokens_for(ALICE), Err(Errors::NoTokensVested(_))));
+        }
+
+        #[test]
+        fn positions_cannot_migrate_onto_another_position_or_out_of_an_nft() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(100), u(50)]).is_ok());
+            assert!(matches!(sale.migrate_position(ALICE, BOB), Err(Errors::TokensAlreadyPurchased(_))));
+            assert!(matches!(sale.migrate_position(SENDER, TREASURY), Err(Errors::NoTokensPurchased(_))));
+
+            assert!(sale.tokenize_vesting(ALICE, NFT, u(1)).is_ok());
+            assert!(matches!(sale.migrate_position(ALICE, TREASURY), Err(Errors::AlreadyTokenized(_))));
+
+            sale.owner.set(ALICE);
+            assert!(matches!(sale.migrate_position(BOB, TREASURY), Err(Errors::OnlyOwner(_))));
+            assert_eq!(sale.tokens_purchased.get(BOB), tokens(50));
+        }
+
+        #[test]
+        fn fractional_mode_buys_base_units_of_the_token() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.set_fractional_purchases(true).is_ok());
+            assert!(sale.fractional_purchases());
+
+            // One and a half tokens cost one and a half times the price
+            let one_and_a_half = tokens(3) / u(2);
+            assert_eq!(sale.quote_cost(one_and_a_half).ok(), Some(u(3 * PRICE / 2)));
+            assert!(sale.purchase_tokens(one_and_a_half).is_ok());
+            assert_eq!(sale.tokens_purchased.get(SENDER), one_and_a_half);
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(3 * PRICE / 2));
+            assert!(matches!(sale.set_fractional_purchases(false), Err(Errors::TokensAlreadyPurchased(_))));
+        }
+
+        #[test]
+        fn default_mode_buys_whole_tokens() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(!sale.fractional_purchases());
+            assert!(sale.purchase_tokens(u(2)).is_ok());
+            assert_eq!(sale.tokens_purchased.get(SENDER), tokens(2));
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(2 * PRICE));
+        }
+
+        #[test]
+        fn abandoned_positions_are_swept_once_the_grace_period_is_over() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.set_claim_grace_period(u(1_000)).is_ok());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(100)]).is_ok());
+            vest_for(&mut sale, ALICE, VESTING / 4);
+            assert_eq!(sale.claim_tokens_for(ALICE).ok(), Some(tokens(25)));
+
+            // Neither part way through vesting nor inside the grace period
+            vest_for(&mut sale, ALICE, VESTING / 2);
+            assert!(matches!(sale.sweep_abandoned(ALICE), Err(Errors::GracePeriodNotOver(_))));
+            vest_for(&mut sale, ALICE, VESTING + 999);
+            assert!(matches!(sale.sweep_abandoned(ALICE), Err(Errors::GracePeriodNotOver(_))));
+
+            vest_for(&mut sale, ALICE, VESTING + 1_000);
+            assert!(sale.sweep_abandoned(ALICE).is_ok());
+            assert_eq!(host::balance(TOKEN, TREASURY), tokens(75));
+            assert_eq!(sale.claimed_by(ALICE), tokens(100));
+            let swept = &host::events::<AbandonedTokensSwept>()[0];
+            assert_eq!((swept.user, swept.treasury, swept.amount), (ALICE, TREASURY, tokens(75)));
+            assert!(matches!(sale.claim_tokens_for(ALICE), Err(Errors::AllTokensClaimed(_))));
+            assert!(matches!(sale.sweep_abandoned(ALICE), Err(Errors::AllTokensClaimed(_))));
+        }
+
+        #[test]
+        fn sweeping_needs_a_grace_period_and_the_owner() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(100)]).is_ok());
+            vest_for(&mut sale, ALICE, 2 * VESTING);
+            assert!(matches!(sale.sweep_abandoned(ALICE), Err(Errors::SweepingNotEnabled(_))));
+
+            sale.owner.set(BOB);
+            assert!(matches!(sale.sweep_abandoned(ALICE), Err(Errors::OnlyOwner(_))));
+            assert_eq!(host::balance(TOKEN, TREASURY), U256::ZERO);
+        }
+
+        #[test]
+        fn vested_bps_tracks_progress_through_vesting() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert_eq!(sale.vested_bps(SENDER), U256::ZERO);
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+
+            assert_eq!(sale.vested_bps(SENDER), U256::ZERO);
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            assert_eq!(sale.vested_bps(SENDER), u(5_000));
+
+            // Progress is independent of what has been claimed
+            assert!(sale.claim_tokens().is_ok());
+            assert_eq!(sale.vested_bps(SENDER), u(5_000));
+            vest_for(&mut sale, SENDER, VESTING);
+            assert_eq!(sale.vested_bps(SENDER), U256::from(BASIS_POINTS));
+            vest_for(&mut sale, SENDER, 2 * VESTING);
+            assert_eq!(sale.vested_bps(SENDER), U256::from(BASIS_POINTS));
+
+            // Claiming everything clears the timestamps of the position but it stays fully vested
+            assert!(sale.claim_tokens().is_ok());
+            assert_eq!(sale.tokens_purchased_at.get(SENDER), U256::ZERO);
+            assert_eq!(sale.vested_bps(SENDER), U256::from(BASIS_POINTS));
+        }
+
+        #[test]
+        fn vested_bps_is_zero_without_vesting() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.vested_bps(SENDER), U256::ZERO);
+        }
+
+        #[test]
+        fn exact_budgets_buy_the_tokens_they_pay_for() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_for_exact_cost(u(7 * PRICE)).is_ok());
+            assert_eq!(sale.tokens_purchased.get(SENDER), tokens(7));
+            assert_eq!(host::balance(CURRENCY, SENDER), u(BUDGET - 7 * PRICE));
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(7 * PRICE));
+        }
+
+        #[test]
+        fn budgets_leaving_dust_are_rejected() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(matches!(sale.purchase_for_exact_cost(u(7 * PRICE + 1)), Err(Errors::InexactCost(_))));
+            assert!(matches!(sale.purchase_for_exact_cost(u(PRICE - 1)), Err(Errors::InexactCost(_))));
+            assert_eq!(sale.tokens_purchased.get(SENDER), U256::ZERO);
+            assert_eq!(host::balance(CURRENCY, SENDER), u(BUDGET));
+        }
+
+        #[test]
+        fn claims_inside_the_minimum_interval_are_rejected() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.set_min_claim_interval(u(86_400)).is_ok());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+
+            // More has vested but the last claim was only just made
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            sale.tokens_claimed_at.setter(SENDER).set(u(NOW - 86_399));
+            assert!(matches!(sale.claim_tokens(), Err(Errors::ClaimTooSoon(_))));
+
+            sale.tokens_claimed_at.setter(SENDER).set(u(NOW - 86_400));
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+        }
+
+        #[test]
+        fn claims_of_split_shares_share_the_minimum_interval_of_the_position() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.set_min_claim_interval(u(86_400)).is_ok());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            host::set_nft_owner(NFT, u(1), SENDER);
+            host::set_nft_owner(NFT, u(2), SENDER);
+            assert!(sale.split_tokenized_vesting(NFT, vec![u(1), u(2)], vec![tokens(60), tokens(40)]).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert_eq!(sale.claim_tokens_by_nft_share(SENDER, u(1)).ok(), Some(tokens(15)));
+
+            // Neither share can claim again straight away, including the one that has not claimed yet
+            assert!(matches!(sale.claim_tokens_by_nft_share(SENDER, u(1)), Err(Errors::ClaimTooSoon(_))));
+            assert!(matches!(sale.claim_tokens_by_nft_share(SENDER, u(2)), Err(Errors::ClaimTooSoon(_))));
+
+            sale.tokens_claimed_at.setter(SENDER).set(u(NOW - 86_400));
+            assert_eq!(sale.claim_tokens_by_nft_share(SENDER, u(2)).ok(), Some(tokens(10)));
+        }
+
+        #[test]
+        fn zero_minimum_interval_allows_back_to_back_claims() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert_eq!(sale.min_claim_interval(), U256::ZERO);
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            assert_eq!(sale.claim_tokens().ok(), Some(tokens(25)));
+        }
+
+        #[test]
+        fn pausers_can_pause_but_not_change_the_configuration() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.grant_role(SENDER, ROLE_PAUSER).is_ok());
+            let granted = &host::events::<RoleGranted>()[0];
+            assert_eq!((granted.account, granted.role), (SENDER, ROLE_PAUSER));
+
+            // Ownership moves to Alice, leaving the sender with only the pauser role
+            sale.owner.set(ALICE);
+            assert!(sale.pause().is_ok());
+            assert!(sale.unpause().is_ok());
+            assert!(matches!(sale.set_release_interval(u(VESTING / 4)), Err(Errors::MissingRole(_))));
+            assert!(matches!(sale.set_allowlist(BOB, true), Err(Errors::MissingRole(_))));
+            assert!(matches!(sale.set_treasury(BOB), Err(Errors::OnlyOwner(_))));
+            assert!(matches!(sale.grant_role(SENDER, ROLE_CONFIG_ADMIN), Err(Errors::OnlyOwner(_))));
+        }
+
+        #[test]
+        fn roles_are_granted_and_revoked_as_bitflags() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.grant_role(BOB, ROLE_PAUSER | ROLE_CONFIG_ADMIN).is_ok());
+            assert!(sale.grant_role(BOB, ROLE_ALLOWLIST_MANAGER).is_ok());
+            assert_eq!(sale.roles_of(BOB), ROLE_PAUSER | ROLE_ALLOWLIST_MANAGER | ROLE_CONFIG_ADMIN);
+
+            assert!(sale.revoke_role(BOB, ROLE_PAUSER).is_ok());
+            assert_eq!(sale.roles_of(BOB), ROLE_ALLOWLIST_MANAGER | ROLE_CONFIG_ADMIN);
+
+            assert!(matches!(sale.grant_role(BOB, 0), Err(Errors::InvalidRole(_))));
+            assert!(matches!(sale.grant_role(BOB, 8), Err(Errors::InvalidRole(_))));
+            assert!(matches!(sale.grant_role(Address::ZERO, ROLE_PAUSER), Err(Errors::ZeroValueArgumentInjected(_))));
+        }
+
+        #[test]
+        fn revoked_roles_no_longer_grant_access() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.grant_role(SENDER, ROLE_ALLOWLIST_MANAGER).is_ok());
+            assert!(sale.revoke_role(SENDER, ROLE_ALLOWLIST_MANAGER).is_ok());
+            sale.owner.set(ALICE);
+            assert!(matches!(sale.set_allowlist(BOB, true), Err(Errors::MissingRole(_))));
+        }
+
+        #[test]
+        fn tokenization_of_tells_untokenized_and_tokenized_positions_apart() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert_eq!(sale.tokenization_of(SENDER), (false, U256::ZERO));
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.tokenization_of(SENDER), (false, U256::ZERO));
+
+            // Token ID zero is reported as tokenized rather than mistaken for no token
+            host::set_nft_owner(NFT, U256::ZERO, SENDER);
+            assert!(sale.enable_tokenized_vesting(NFT, U256::ZERO).is_ok());
+            assert_eq!(sale.tokenization_of(SENDER), (true, U256::ZERO));
+
+            assert!(sale.disable_tokenized_vesting().is_ok());
+            assert_eq!(sale.tokenization_of(SENDER), (false, U256::ZERO));
+            host::set_nft_owner(NFT, u(9), SENDER);
+            assert!(sale.enable_tokenized_vesting(NFT, u(9)).is_ok());
+            assert_eq!(sale.tokenization_of(SENDER), (true, u(9)));
+        }
+
+        #[test]
+        fn erc1155_claims_are_gated_on_the_balance_of_the_token_id() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.set_nft_is_1155(true).is_ok());
+            assert!(sale.nft_is_1155());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+
+            assert!(sale.enable_tokenized_vesting(NFT, u(1)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+
+            // Owning the ERC721 token of the same ID counts for nothing in ERC-1155 mode
+            host::set_nft_owner(NFT, u(1), SENDER);
+            assert!(matches!(sale.claim_tokens_by_nft(SENDER), Err(Errors::OnlyOwner(_))));
+
+            host::set_1155_balance(NFT, SENDER, u(1), u(1));
+            assert_eq!(sale.claim_tokens_by_nft(SENDER).ok(), Some(tokens(25)));
+
+            host::set_1155_balance(NFT, SENDER, u(1), U256::ZERO);
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            assert!(matches!(sale.claim_tokens_by_nft(SENDER), Err(Errors::OnlyOwner(_))));
+            assert!(matches!(sale.set_nft_is_1155(false), Err(Errors::PositionsAlreadyTokenized(_))));
+        }
+
+        #[test]
+        fn near_max_supply_accounting_reverts_instead_of_wrapping() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            assert!(matches!(sale.purchase_tokens(U256::MAX), Err(Errors::MathOverflow(_))));
+
+            // Running totals close to the limit cannot wrap back round to a small number
+            sale.total_tokens_available.set(U256::MAX);
+            sale.total_tokens_purchased.set(U256::MAX - tokens(1));
+            assert!(matches!(sale.purchase_tokens(u(2)), Err(Errors::MathOverflow(_))));
+            assert_eq!(sale.simulate_purchase(u(2)), (u(2 * PRICE), false));
+
+            sale.total_tokens_available.set(tokens(SUPPLY));
+            assert!(matches!(sale.tokens_remaining(), Err(Errors::MathOverflow(_))));
+        }
+
+        #[test]
+        fn referrers_are_paid_a_share_of_the_proceeds() {
+            let (_chain, mut sale) = deploy(SaleConfig {
+                allow_multiple_purchases: true,
+                fee_bps: u(250),
+                fee_recipient: BOB,
+                ..config()
+            });
+            assert!(sale.set_referral_bps(u(500)).is_ok());
+
+            assert!(sale.purchase_tokens_with_referrer(u(10), ALICE).is_ok());
+            assert!(sale.purchase_tokens_with_referrer(u(30), ALICE).is_ok());
+            assert_eq!(sale.referral_volume(ALICE), u(40 * PRICE));
+
+            // The fee and the reward both come out of the cost, leaving the rest for the treasury
+            assert_eq!(host::balance(CURRENCY, ALICE), u(2 * PRICE));
+            assert_eq!(host::balance(CURRENCY, BOB), u(PRICE));
+            assert_eq!(host::balance(CURRENCY, TREASURY), u(37 * PRICE));
+            let rewarded = &host::events::<ReferralRewarded>()[0];
+            assert_eq!((rewarded.referrer, rewarded.buyer, rewarded.cost, rewarded.reward), (ALICE, SENDER, u(10 * PRICE), u(PRICE / 2)));
+        }
+
+        #[test]
+        fn buyers_cannot_refer_themselves() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.set_referral_bps(u(500)).is_ok());
+            assert!(matches!(sale.purchase_tokens_with_referrer(u(10), SENDER), Err(Errors::SelfReferral(_))));
+            assert!(matches!(sale.purchase_tokens_with_referrer(u(10), Address::ZERO), Err(Errors::ZeroValueArgumentInjected(_))));
+            assert_eq!(sale.referral_volume(SENDER), U256::ZERO);
+            assert_eq!(host::balance(CURRENCY, SENDER), u(BUDGET));
+        }
+
+        #[test]
+        fn referral_and_fee_shares_cannot_exceed_the_whole_cost() {
+            let (_chain, mut sale) = deploy(SaleConfig { fee_bps: u(9_000), fee_recipient: BOB, ..config() });
+            assert!(matches!(sale.set_referral_bps(u(1_001)), Err(Errors::InvalidPercentage(_))));
+            assert!(sale.set_referral_bps(u(1_000)).is_ok());
+        }
+
+        #[test]
+        fn countdown_reports_the_seconds_left_in_the_current_interval() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.set_release_interval(u(VESTING / 4)).is_ok());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+
+            for (elapsed, remaining) in [
+                (0, VESTING / 4),
+                (100_000, VESTING / 4 - 100_000),
+                (VESTING / 4 - 1, 1),
+                (VESTING / 4, VESTING / 4),
+                (3 * VESTING / 4 + 10, VESTING / 4 - 10),
+                (VESTING, 0),
+                (2 * VESTING, 0)
+            ] {
+                vest_for(&mut sale, SENDER, elapsed);
+                assert_eq!(sale.seconds_until_next_unlock(SENDER), u(remaining), "after {elapsed} seconds");
+            }
+        }
+
+        #[test]
+        fn countdown_is_zero_for_continuous_vesting() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 3);
+            assert_eq!(sale.seconds_until_next_unlock(SENDER), U256::ZERO);
+            assert_eq!(sale.seconds_until_next_unlock(ALICE), U256::ZERO);
+        }
+
+        #[test]
+        fn distribution_pushes_vested_tokens_and_skips_everyone_else() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB, TREASURY], vec![u(100), u(100), u(100)]).is_ok());
+            assert!(sale.tokenize_vesting(BOB, NFT, u(1)).is_ok());
+            vest_for(&mut sale, ALICE, VESTING / 4);
+            vest_for(&mut sale, BOB, VESTING / 4);
+            vest_for(&mut sale, TREASURY, VESTING / 2);
+            assert_eq!(sale.claim_tokens_for(TREASURY).ok(), Some(tokens(50)));
+
+            // Bob is tokenized, the treasury has just claimed and the sender never purchased
+            assert_eq!(sale.distribute_claims(vec![ALICE, BOB, TREASURY, SENDER]).ok(), Some(tokens(25)));
+            assert_eq!(host::balance(TOKEN, ALICE), tokens(25));
+            assert_eq!(host::balance(TOKEN, BOB), U256::ZERO);
+            let released: Vec<_> = host::events::<TokensClaimed>().iter().map(|claim| (claim.user, claim.recipient, claim.amount)).collect();
+            assert_eq!(released, vec![(TREASURY, TREASURY, tokens(50)), (ALICE, ALICE, tokens(25))]);
+        }
+
+        #[test]
+        fn distribution_releases_unlocked_purchases() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(3), u(4)]).is_ok());
+            assert_eq!(sale.distribute_claims(vec![ALICE, BOB]).ok(), Some(tokens(7)));
+            assert_eq!((host::balance(TOKEN, ALICE), host::balance(TOKEN, BOB)), (tokens(3), tokens(4)));
+
+            // Nobody has anything left so a second round releases nothing without reverting
+            assert_eq!(sale.distribute_claims(vec![ALICE, BOB]).ok(), Some(U256::ZERO));
+        }
+
+        #[test]
+        fn only_the_owner_distributes_claims() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(3)]).is_ok());
+            sale.owner.set(BOB);
+            assert!(matches!(sale.distribute_claims(vec![ALICE]), Err(Errors::OnlyOwner(_))));
+            assert_eq!(host::balance(TOKEN, ALICE), U256::ZERO);
+        }
+
+        #[test]
+        fn prefunded_sales_need_the_whole_supply_deposited() {
+            let (chain, mut sale) = uninitialized(tokens(SUPPLY) - u(1));
+            let result = sale.init_with_config(SaleConfig { require_prefunded: true, ..config() });
+            assert!(matches!(result, Err(Errors::InsufficientBacking(_))));
+            assert!(!sale.is_initialized());
+            drop(chain);
+
+            let (_chain, mut sale) = uninitialized(tokens(SUPPLY));
+            assert!(sale.init_with_config(SaleConfig { require_prefunded: true, ..config() }).is_ok());
+            assert!(sale.is_fully_backed());
+        }
+
+        #[test]
+        fn sales_that_do_not_require_prefunding_can_be_funded_later() {
+            let (_chain, mut sale) = uninitialized(U256::ZERO);
+            assert!(sale.init_with_config(config()).is_ok());
+            assert_eq!(host::balance(TOKEN, CONTRACT), U256::ZERO);
+        }
+
+        #[test]
+        fn storage_gap_stays_untouched_by_the_fields_around_it() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..vesting_config() });
+            assert!(sale.set_treasury(BOB).is_ok());
+            assert!(sale.set_blacklist(ALICE, true).is_ok());
+            assert!(sale.set_min_claim_interval(u(60)).is_ok());
+            assert!(sale.purchase_tokens(u(10)).is_ok());
+            assert!(sale.pause().is_ok());
+
+            assert_eq!(sale.storage_gap.len(), 43);
+            assert!((0..sale.storage_gap.len()).all(|index| sale.storage_gap.get(index) == Some(U256::ZERO)));
+            assert_eq!((sale.treasury(), sale.min_claim_interval(), sale.total_tokens_purchased()), (BOB, u(60), tokens(10)));
+            assert!(sale.is_blacklisted(ALICE) && sale.paused());
+            assert_eq!(sale.storage_layout_version(), STORAGE_LAYOUT_VERSION);
+        }
+
+        #[test]
+        fn storage_written_with_another_layout_is_rejected() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.validate_storage_layout_version().is_ok());
+
+            sale.storage_layout_version.set(U8::from(STORAGE_LAYOUT_VERSION + 1));
+            assert!(matches!(sale.validate_storage_layout_version(), Err(Errors::StorageLayoutMismatch(_))));
+            assert!(matches!(sale.purchase_tokens(u(1)), Err(Errors::StorageLayoutMismatch(_))));
+        }
+
+        #[test]
+        fn cancelled_purchases_free_their_supply_for_other_buyers() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(SUPPLY)).is_ok());
+            assert!(matches!(sale.purchase_tokens_for(vec![ALICE], vec![u(1)]), Err(Errors::SoldOut(_))));
+            assert!(matches!(sale.cancel_purchase(), Err(Errors::RefundsNotEnabled(_))));
+
+            assert!(sale.enable_refunds().is_ok());
+            assert!(sale.deposit_refund_reserve(u(SUPPLY * PRICE)).is_ok());
+            assert_eq!(sale.cancel_purchase().ok(), Some((u(SUPPLY * PRICE), U256::ZERO)));
+            assert_eq!(host::balance(CURRENCY, SENDER), u(BUDGET - SUPPLY * PRICE));
+            assert_eq!(sale.tokens_purchased.get(SENDER), U256::ZERO);
+            assert_eq!(sale.tokens_remaining().ok(), Some(tokens(SUPPLY)));
+            let refunded = &host::events::<Refunded>()[0];
+            assert_eq!((refunded.user, refunded.tokens, refunded.amount), (SENDER, tokens(SUPPLY), u(SUPPLY * PRICE)));
+
+            // Alice takes the freed supply and the sender is free to buy again
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(SUPPLY - 1)]).is_ok());
+            assert!(sale.purchase_tokens(u(1)).is_ok());
+            assert_eq!(sale.tokens_remaining().ok(), Some(U256::ZERO));
+        }
+
+        #[test]
+        fn unlocked_claims_on_vesting_sales_point_to_the_vested_claim() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(matches!(sale.claim_unlocked_tokens(), Err(Errors::UseVestedClaim(_))));
+
+            // The guidance does not depend on the state of the position
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert!(matches!(sale.claim_unlocked_tokens(), Err(Errors::UseVestedClaim(_))));
+            vest_for(&mut sale, SENDER, VESTING);
+            assert!(matches!(sale.claim_unlocked_tokens_to(ALICE), Err(Errors::UseVestedClaim(_))));
+            assert!(sale.claim_tokens().is_ok());
+            assert!(matches!(sale.claim_unlocked_tokens(), Err(Errors::UseVestedClaim(_))));
+        }
+
+        #[test]
+        fn vested_claims_on_unlocked_sales_point_to_the_unlocked_claim() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(matches!(sale.claim_tokens(), Err(Errors::UseUnlockedClaim(_))));
+
+            assert!(sale.purchase_tokens(u(5)).is_ok());
+            assert!(matches!(sale.claim_tokens(), Err(Errors::UseUnlockedClaim(_))));
+            assert!(matches!(sale.claim_tokens_to(ALICE), Err(Errors::UseUnlockedClaim(_))));
+            assert!(sale.claim_unlocked_tokens().is_ok());
+            assert!(matches!(sale.claim_tokens(), Err(Errors::UseUnlockedClaim(_))));
+            assert!(matches!(sale.claim_unlocked_tokens(), Err(Errors::AllTokensClaimed(_))));
+        }
+
+        #[test]
+        fn positions_keep_the_terms_they_were_purchased_on() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.purchase_terms_of(SENDER), (u(PRICE), u(VESTING)));
+
+            // The configuration drifts after the purchase
+            assert!(sale.set_vesting_length(u(VESTING / 2)).is_ok());
+            sale.price_per_token.set(u(3 * PRICE));
+            assert_eq!(sale.purchase_terms_of(SENDER), (u(PRICE), u(VESTING)));
+
+            vest_for(&mut sale, SENDER, VESTING / 2);
+            assert_eq!(sale.vesting_end(SENDER), u(NOW + VESTING / 2));
+            assert_eq!(sale.vested_bps(SENDER), u(5_000));
+            assert_eq!(sale.claimable_amount(SENDER), tokens(50));
+
+            // Refunds pay back what was actually paid rather than the current price
+            assert!(sale.enable_refunds().is_ok());
+            assert!(sale.deposit_refund_reserve(u(300 * PRICE)).is_ok());
+            assert_eq!(sale.refund().ok(), Some((u(100 * PRICE), U256::ZERO)));
+        }
+
+        #[test]
+        fn multicall_purchases_and_tokenizes_in_one_transaction() {
+            use calls::{enableTokenizedVestingCall, nftClaimCall, purchaseTokensCall, totalTokensPurchasedCall};
+
+            let (_chain, mut sale) = deploy(vesting_config());
+            let batch = vec![
+                Bytes(purchaseTokensCall { amount: u(100) }.abi_encode()),
+                Bytes(enableTokenizedVestingCall { nft_contract: NFT, token_id: u(1) }.abi_encode()),
+                Bytes(totalTokensPurchasedCall {}.abi_encode()),
+                Bytes(nftClaimCall {}.abi_encode())
+            ];
+
+            let results = sale.multicall(batch).ok().unwrap();
+            assert_eq!(results.len(), 4);
+            assert!(results[0].0.is_empty() && results[1].0.is_empty());
+            assert_eq!(results[2].0, tokens(100).abi_encode());
+            assert_eq!(results[3].0, NFT.abi_encode());
+            assert!(sale.vesting_tokenized.get(SENDER));
+        }
+
+        #[test]
+        fn multicall_reverts_every_call_when_one_fails() {
+            use calls::{enableTokenizedVestingCall, purchaseTokensCall};
+
+            let (_chain, mut sale) = deploy(vesting_config());
+            let batch = vec![
+                Bytes(purchaseTokensCall { amount: u(100) }.abi_encode()),
+                Bytes(enableTokenizedVestingCall { nft_contract: OTHER_NFT, token_id: u(1) }.abi_encode())
+            ];
+
+            let result = transact(&mut sale, |sale| sale.multicall(batch));
+            assert_eq!(result.err(), Some(Errors::NftCollectionNotApproved(NftCollectionNotApproved {}).into()));
+            assert_eq!(sale.tokens_purchased.get(SENDER), U256::ZERO);
+            assert_eq!(host::balance(CURRENCY, SENDER), u(BUDGET));
+
+            let result = sale.multicall(vec![Bytes(vec![1, 2, 3])]);
+            assert_eq!(result.err(), Some(Errors::UnknownSelector(UnknownSelector {}).into()));
+        }
+
+        #[test]
+        fn final_claims_release_the_exact_remainder_in_every_vesting_mode() {
+            let allocation = 999_999_937u64;
+            for release_interval in [U256::ZERO, u(VESTING / 8)] {
+                let (_chain, mut sale) = deploy(vesting_config());
+                assert!(sale.set_fractional_purchases(true).is_ok());
+                assert!(sale.set_release_interval(release_interval).is_ok());
+                assert!(sale.purchase_tokens(u(allocation)).is_ok());
+
+                let mut claimed = U256::ZERO;
+                for elapsed in [VESTING / 3, VESTING / 2 + 7, VESTING - 1] {
+                    vest_for(&mut sale, SENDER, elapsed);
+                    claimed += sale.claim_tokens().ok().unwrap();
+                }
+                assert!(claimed < u(allocation));
+
+                // The final claim rounds nothing down and leaves no dust behind
+                vest_for(&mut sale, SENDER, VESTING);
+                assert_eq!(sale.claim_tokens().ok(), Some(u(allocation) - claimed));
+                assert_eq!(sale.claimed_by(SENDER), u(allocation));
+                assert_eq!(host::balance(TOKEN, SENDER), u(allocation));
+                assert_eq!(sale.total_outstanding(), U256::ZERO);
+            }
+        }
+
+        #[test]
+        fn vesting_start_follows_the_purchase_or_the_end_of_the_sale() {
+            let (chain, mut sale) = deploy(vesting_config());
+            assert_eq!(sale.vesting_start(SENDER), U256::ZERO);
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.vesting_start(SENDER), u(NOW));
+            drop(chain);
+
+            // The cliff runs from the start rather than moving it
+            let (chain, mut sale) = deploy(SaleConfig { cliff_length_in_seconds: u(VESTING / 10), ..vesting_config() });
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.vesting_start(SENDER), u(NOW));
+            assert_eq!(sale.claimable_amount(SENDER), U256::ZERO);
+            drop(chain);
+
+            let (chain, mut sale) = deploy(SaleConfig { sale_end: u(NOW + 1_000), ..vesting_config() });
+            assert!(sale.set_vest_from_sale_end(true).is_ok());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.vesting_start(SENDER), u(NOW + 1_000));
+
+            // A purchase recorded after the sale ended starts vesting from the purchase
+            sale.sale_end.set(u(NOW - 1));
+            assert_eq!(sale.vesting_start(SENDER), u(NOW));
+            drop(chain);
+
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            assert_eq!(sale.vesting_start(SENDER), U256::ZERO);
+        }
+
+        #[test]
+        fn purchases_can_be_tokenized_in_the_same_transaction() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_and_tokenize(u(100), NFT, u(1)).is_ok());
+            assert_eq!(sale.tokens_purchased.get(SENDER), tokens(100));
+            assert_eq!(sale.tokenization_of(SENDER), (true, u(1)));
+            assert!(matches!(sale.claim_tokens(), Err(Errors::AlreadyTokenized(_))));
+
+            host::set_nft_owner(NFT, u(1), ALICE);
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert!(matches!(sale.claim_tokens_by_nft(SENDER), Err(Errors::OnlyOwner(_))));
+        }
+
+        #[test]
+        fn failed_tokenization_rolls_back_the_purchase() {
+            let (chain, mut sale) = deploy(vesting_config());
+            assert!(sale.set_blocked_token_id(u(2), true).is_ok());
+            let result = transact(&mut sale, |sale| sale.purchase_and_tokenize(u(100), OTHER_NFT, u(1)));
+            assert!(matches!(result, Err(Errors::NftCollectionNotApproved(_))));
+            let result = transact(&mut sale, |sale| sale.purchase_and_tokenize(u(100), NFT, u(2)));
+            assert!(matches!(result, Err(Errors::BlockedTokenId(_))));
+            assert_eq!(sale.tokens_purchased.get(SENDER), U256::ZERO);
+            assert_eq!(host::balance(CURRENCY, SENDER), u(BUDGET));
+            drop(chain);
+
+            let (_chain, mut sale) = deploy(config());
+            let result = transact(&mut sale, |sale| sale.purchase_and_tokenize(u(100), NFT, u(1)));
+            assert!(matches!(result, Err(Errors::VestingNotEnabled(_))));
+            assert_eq!(sale.tokens_purchased.get(SENDER), U256::ZERO);
+        }
+
+        #[test]
+        fn purchase_limits_of_an_eligible_buyer() {
+            let (_chain, mut sale) = deploy(SaleConfig { allowlist_enabled: true, ..config() });
+            assert!(sale.set_allowlist(ALICE, true).is_ok());
+            assert_eq!(sale.purchase_limits(ALICE), (tokens(SUPPLY), true, false));
+            assert_eq!(sale.purchase_limits(BOB), (U256::ZERO, false, false));
+
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(100)]).is_ok());
+            assert!(sale.set_allowlist(BOB, true).is_ok());
+            assert_eq!(sale.purchase_limits(BOB), (tokens(SUPPLY - 100), true, false));
+        }
+
+        #[test]
+        fn purchase_limits_of_a_buyer_held_back_by_the_raise_cap() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            assert!(sale.set_max_currency_raised(u(250 * PRICE + PRICE / 2)).is_ok());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(200)]).is_ok());
+
+            // Only whole tokens that fit under the cap count
+            assert_eq!(sale.purchase_limits(ALICE), (tokens(50), true, true));
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(50)]).is_ok());
+            assert_eq!(sale.purchase_limits(ALICE), (U256::ZERO, true, true));
+        }
+
+        #[test]
+        fn purchase_limits_of_a_buyer_that_already_purchased_once() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(100)]).is_ok());
+            assert_eq!(sale.purchase_limits(ALICE), (U256::ZERO, true, true));
+            assert_eq!(sale.purchase_limits(BOB), (tokens(SUPPLY - 100), true, false));
+        }
+
+        #[test]
+        fn claims_delivered_to_a_contract_call_it_back() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            host::deploy_receiver(ALICE);
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+
+            assert_eq!(sale.claim_tokens_with_callback(ALICE, Bytes(vec![0xca, 0xfe])).ok(), Some(tokens(25)));
+            assert_eq!(host::balance(TOKEN, ALICE), tokens(25));
+            assert_eq!(host::received_claims(ALICE), vec![ReceivedClaim { user: SENDER, amount: tokens(25), data: vec![0xca, 0xfe] }]);
+        }
+
+        #[test]
+        fn claims_delivered_to_a_wallet_skip_the_callback() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+            assert_eq!(sale.claim_tokens_with_callback(BOB, Bytes(vec![])).ok(), Some(tokens(25)));
+            assert_eq!(host::balance(TOKEN, BOB), tokens(25));
+        }
+
+        #[test]
+        fn failing_callbacks_revert_the_claim() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            host::deploy_contract(ALICE);
+            assert!(sale.purchase_tokens(u(100)).is_ok());
+            vest_for(&mut sale, SENDER, VESTING / 4);
+
+            let result = transact(&mut sale, |sale| sale.claim_tokens_with_callback(ALICE, Bytes(vec![])));
+            assert!(matches!(result, Err(Errors::ClaimCallbackFailed(_))));
+            assert_eq!(host::balance(TOKEN, ALICE), U256::ZERO);
+            assert_eq!(sale.claimed_by(SENDER), U256::ZERO);
+        }
+
+        #[test]
+        fn every_purchase_gets_the_next_id() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..config() });
+            assert_eq!(sale.last_purchase_id(), U256::ZERO);
+
+            assert!(sale.purchase_tokens(u(1)).is_ok());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(2), u(3)]).is_ok());
+            assert!(sale.purchase_tokens(u(4)).is_ok());
+            assert_eq!(sale.last_purchase_id(), u(4));
+
+            let purchases: Vec<_> = host::events::<TokensPurchased>().iter().map(|purchase| (purchase.purchaseId, purchase.user)).collect();
+            assert_eq!(purchases, vec![(u(1), SENDER), (u(2), ALICE), (u(3), BOB), (u(4), SENDER)]);
+        }
+
+        #[test]
+        fn failed_purchases_do_not_use_up_an_id() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(1)).is_ok());
+            assert!(matches!(sale.purchase_tokens(u(1)), Err(Errors::OnlyOnePurchase(_))));
+            assert!(sale.purchase_tokens_for(vec![ALICE], vec![u(1)]).is_ok());
+            assert_eq!(sale.last_purchase_id(), u(2));
+            assert_eq!(host::events::<TokensPurchased>()[1].purchaseId, u(2));
+        }
+
+        #[test]
+        fn uneven_supplies_are_rejected_at_initialization() {
+            let (chain, mut sale) = uninitialized(tokens(SUPPLY) + u(1));
+            let result = sale.init_with_config(SaleConfig { total_tokens_available: tokens(SUPPLY) + u(1), ..config() });
+            assert!(matches!(result, Err(Errors::UnevenSupply(_))));
+            assert!(!sale.is_initialized());
+            drop(chain);
+
+            // An even supply can be bought out to the last token
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB], vec![u(SUPPLY - 1), u(1)]).is_ok());
+            assert_eq!(sale.tokens_remaining().ok(), Some(U256::ZERO));
+        }
+
+        #[test]
+        fn outstanding_tokens_fall_as_buyers_claim() {
+            let (_chain, mut sale) = deploy(vesting_config());
+            assert!(sale.purchase_tokens_for(vec![ALICE, BOB, TREASURY], vec![u(100), u(60), u(40)]).is_ok());
+            assert_eq!(sale.total_outstanding(), tokens(200));
+
+            let users = [ALICE, BOB, TREASURY];
+            for (elapsed, outstanding) in [(VESTING / 4, 150), (VESTING / 2, 100), (VESTING, 0)] {
+                for user in users {
+                    vest_for(&mut sale, user, elapsed);
+                    assert!(sale.claim_tokens_for(user).is_ok());
+                }
+
+                // The total always matches what each position still has to claim
+                let individual = users.iter().fold(U256::ZERO, |sum, user| sum + sale.tokens_purchased.get(*user) - sale.claimed_by(*user));
+                assert_eq!(sale.total_outstanding(), tokens(outstanding));
+                assert_eq!(sale.total_outstanding(), individual);
+            }
+        }
+
+        #[test]
+        fn outstanding_tokens_bottom_out_at_zero_if_the_totals_disagree() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.purchase_tokens(u(10)).is_ok());
+            sale.total_tokens_claimed.set(tokens(11));
+            assert_eq!(sale.total_outstanding(), U256::ZERO);
+        }
+
+        #[test]
+        fn fee_on_transfer_purchases_credit_what_was_received() {
+            let (_chain, mut sale) = deploy(config());
+            assert!(sale.set_credit_actual_received(true).is_ok());
+            host::with_token(CURRENCY, |mock| mock.transfer_fee_bps = 100);
+
+            // A 1% fee on 10 tokens worth of currency leaves enough for 9 whole tokens
+            assert!(sale.purchase_tokens(u(10)).is_ok());
+            let received = u(10 * PRICE * 99 / 100);
+            assert_eq!(sale.tokens_for_currency(received).ok(), Some(u(9)));
+            assert_eq!(sale.tokens_purchased.get(SENDER), tokens(9));
+            assert_eq!(sale.total_tokens_purchased(), tokens(9));
+            assert_eq!(host::balance(CURRENCY, SENDER), u(BUDGET - 10 * PRICE));
+
+            // Everything that arrived is forwarded including the remainder too small to buy a whole token
+            assert_eq!(host::balance(CURRENCY, TREASURY), received);
+            assert_eq!(host::balance(CURRENCY, CONTRACT), U256::ZERO);
+        }
+
+        #[test]
+        fn purchases_crediting_what_was_received_are_written_before_the_currency_is_called() {
+            let (_chain, mut sale) = deploy(SaleConfig {
+                allow_multiple_purchases: true,
+                instant_unlock_bps: u(1_000),
+                ..vesting_config()
+            });
+            assert!(sale.set_credit_actual_received(true).is_ok());
+            assert!(sale.set_referral_bps(u(500)).is_ok());
+            host::with_token(CURRENCY, |mock| mock.transfer_fee_bps = 100);
+            let during_payment = Rc::new(RefCell::new(None));
+            let captured = Rc::clone(&during_payment);
+            host::with_token(CURRENCY, |mock| {
+                mock.on_transfer = Some(Rc::new(move || {
+                    let sale = unsafe { TokenSaleWithTokenizedVesting::new(U256::ZERO, 0) };
+                    captured.borrow_mut().get_or_insert((sale.locked.get(), sale.tokens_purchased.get(SENDER), sale.last_purchase_id()));
+                }));
+            });
+
+            // The requested purchase is committed under the lock while the payment is pulled
+            assert!(sale.purchase_tokens_with_referrer(u(10), ALICE).is_ok());
+            assert_eq!(during_payment.borrow_mut().take(), Some((true, tokens(10), u(1))));
+
+            // It is then settled on the 9 tokens paid for, with the instant unlock and the raise following suit
+            let received = u(10 * PRICE * 99 / 100);
+            assert_eq!(sale.tokens_purchased.get(SENDER), tokens(9));
+            assert_eq!(sale.claimed_by(SENDER), tokens(9) / u(10));
+            assert_eq!(host::balance(TOKEN, SENDER), tokens(9) / u(10));
+            assert_eq!(sale.total_currency_raised(), u(9 * PRICE));
+            let purchase = &host::events::<TokensPurchased>()[0];
+            assert_eq!((purchase.purchaseId, purchase.amount, purchase.cost), (u(1), u(9), u(9 * PRICE)));
+
+            // The referrer is credited with the currency that actually arrived
+            assert_eq!(sale.referral_volume(ALICE), received);
+            assert_eq!(host::balance(CURRENCY, ALICE), received * u(500) / U256::from(BASIS_POINTS));
+        }
+
+        #[test]
+        fn top_ups_crediting_what_was_received_shrink_their_own_lot() {
+            let (_chain, mut sale) = deploy(SaleConfig { allow_multiple_purchases: true, ..vesting_config() });
+            assert!(sale.set_credit_actual_received(true).is_ok());
+            assert!(sale.purchase_tokens(u(10)).is_ok());
+            host::with_token(CURRENCY, |mock| mock.transfer_fee_bps = 100);
+
+            assert!(sale.purchase_tokens(u(10)).is_ok());
+            assert_eq!(sale.purchase_lots(SENDER), vec![(tokens(10), u(NOW)), (tokens(9), u(NOW))]);
+            assert_eq!(sale.total_tokens_purchased(), tokens(19));
+        }
+    }
+}
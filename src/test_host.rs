@@ -0,0 +1,493 @@
+//! In-memory stand-in for the Stylus host so that the sale can be driven natively in unit tests
+//!
+//! Storage, logs and the ERC20, ERC721, ERC1155 and callback contracts the sale calls live in thread local state so that
+//! every test starts from an empty chain. The SDK caches the sender, the block timestamp and the contract address for the
+//! whole process, so these are fixed and tests move positions through time by writing their timestamps to storage instead.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard};
+
+use alloy_primitives::{address, keccak256, Address, B256, U256};
+use alloy_sol_types::{sol, SolCall, SolEvent, SolValue};
+
+use crate::{permitCall, transferCall, transferFromCall};
+
+/// Caller of every method, which is the owner once the sale has been initialized
+pub const SENDER: Address = address!("00000000000000000000000000000000000b0b01");
+
+/// Address of the sale itself
+pub const CONTRACT: Address = address!("0000000000000000000000000000000000005a1e");
+
+/// Timestamp of the block every test runs in
+pub const NOW: u64 = 1_700_000_000;
+
+sol! {
+    function ownerOf(uint256 token_id) external view returns (address);
+    function balanceOf(address account, uint256 id) external view returns (uint256);
+    function onTokensClaimed(address user, uint256 amount, bytes data) external;
+}
+
+/// ERC20 deployed on the mock chain
+#[derive(Clone, Default)]
+pub struct MockToken {
+    pub balances: HashMap<Address, U256>,
+    /// Basis points of every `transferFrom` kept back from the recipient
+    pub transfer_fee_bps: u64,
+    /// Whether transfers succeed without returning a value like USDT
+    pub returns_nothing: bool,
+    /// Whether transfers return `false` instead of reverting when they fail
+    pub returns_false: bool,
+    /// Called with the chain released while a transfer is being made, standing in for a malicious token calling back
+    pub on_transfer: Option<Rc<dyn Fn()>>,
+    /// Whether the token implements EIP-2612 rather than reverting on `permit`
+    pub supports_permit: bool,
+    /// Owner, spender, value and deadline of every permit accepted
+    pub permits: Vec<(Address, Address, U256, U256)>
+}
+
+/// Claim received by a contract implementing `onTokensClaimed`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReceivedClaim {
+    pub user: Address,
+    pub amount: U256,
+    pub data: Vec<u8>
+}
+
+/// Everything on the mock chain at one point in time
+#[derive(Clone, Default)]
+pub struct Chain {
+    storage: HashMap<B256, B256>,
+    tokens: HashMap<Address, MockToken>,
+    nft_owners: HashMap<(Address, U256), Address>,
+    nft_1155_balances: HashMap<(Address, Address, U256), U256>,
+    receivers: HashMap<Address, Vec<ReceivedClaim>>,
+    contracts: HashSet<Address>,
+    logs: Vec<(Vec<B256>, Vec<u8>)>,
+    return_data: Vec<u8>
+}
+
+thread_local! {
+    static CHAIN: RefCell<Chain> = RefCell::new(Chain::default());
+}
+
+/// The SDK keeps the length of the last return data in a process wide static so calls from tests must not interleave
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Logic for starting a test on an empty chain, returning a guard that keeps other tests off the host until it is dropped
+pub fn reset() -> MutexGuard<'static, ()> {
+    let guard = SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    CHAIN.with(|chain| *chain.borrow_mut() = Chain::default());
+    guard
+}
+
+/// Copy of the chain that a failed call can be rolled back to
+pub fn snapshot() -> Chain {
+    CHAIN.with(|chain| chain.borrow().clone())
+}
+
+/// Logic for rolling the chain back to a snapshot as a reverted transaction would
+pub fn restore(snapshot: Chain) {
+    CHAIN.with(|chain| *chain.borrow_mut() = snapshot);
+}
+
+/// Every storage slot written so far
+pub fn storage() -> HashMap<B256, B256> {
+    CHAIN.with(|chain| chain.borrow().storage.clone())
+}
+
+/// Logic for deploying an ERC20 at an address
+pub fn deploy_token(token: Address, mock: MockToken) {
+    CHAIN.with(|chain| {
+        let mut chain = chain.borrow_mut();
+        chain.contracts.insert(token);
+        chain.tokens.insert(token, mock);
+    });
+}
+
+/// Logic for changing an ERC20 already deployed
+pub fn with_token<T>(token: Address, f: impl FnOnce(&mut MockToken) -> T) -> T {
+    CHAIN.with(|chain| f(chain.borrow_mut().tokens.get_mut(&token).expect("token not deployed")))
+}
+
+/// Logic for minting an ERC20 balance
+pub fn mint(token: Address, account: Address, amount: U256) {
+    with_token(token, |mock| *mock.balances.entry(account).or_default() += amount);
+}
+
+/// Balance of an account in an ERC20
+pub fn balance(token: Address, account: Address) -> U256 {
+    with_token(token, |mock| mock.balances.get(&account).copied().unwrap_or_default())
+}
+
+/// Logic for deploying an NFT collection so that it has code
+pub fn deploy_nft(collection: Address) {
+    CHAIN.with(|chain| chain.borrow_mut().contracts.insert(collection));
+}
+
+/// Logic for setting the owner of an ERC721 token
+pub fn set_nft_owner(collection: Address, token_id: U256, owner: Address) {
+    CHAIN.with(|chain| chain.borrow_mut().nft_owners.insert((collection, token_id), owner));
+}
+
+/// Logic for setting the ERC1155 balance of an account
+pub fn set_1155_balance(collection: Address, account: Address, token_id: U256, amount: U256) {
+    CHAIN.with(|chain| chain.borrow_mut().nft_1155_balances.insert((collection, account, token_id), amount));
+}
+
+/// Logic for deploying a contract that accepts `onTokensClaimed` callbacks
+pub fn deploy_receiver(receiver: Address) {
+    CHAIN.with(|chain| {
+        let mut chain = chain.borrow_mut();
+        chain.contracts.insert(receiver);
+        chain.receivers.insert(receiver, Vec::new());
+    });
+}
+
+/// Logic for deploying a contract without any functions so that every call to it reverts
+pub fn deploy_contract(contract: Address) {
+    CHAIN.with(|chain| chain.borrow_mut().contracts.insert(contract));
+}
+
+/// Claims received by a callback contract in the order they arrived
+pub fn received_claims(receiver: Address) -> Vec<ReceivedClaim> {
+    CHAIN.with(|chain| chain.borrow().receivers.get(&receiver).cloned().unwrap_or_default())
+}
+
+/// Every event of one type logged so far in the order they were logged
+pub fn events<E: SolEvent>() -> Vec<E> {
+    CHAIN.with(|chain| {
+        chain
+            .borrow()
+            .logs
+            .iter()
+            .filter(|(topics, _)| topics.first() == Some(&E::SIGNATURE_HASH))
+            .filter_map(|(topics, data)| E::decode_raw_log(topics.iter().copied(), data, true).ok())
+            .collect()
+    })
+}
+
+fn call(contract: Address, calldata: &[u8]) -> Result<Vec<u8>, Vec<u8>> {
+    let selector: [u8; 4] = match calldata.get(..4) {
+        Some(selector) => selector.try_into().unwrap(),
+        None => return Ok(Vec::new())
+    };
+
+    // Calls to accounts without code succeed without returning anything
+    if !CHAIN.with(|chain| chain.borrow().contracts.contains(&contract)) {
+        return Ok(Vec::new())
+    }
+
+    if CHAIN.with(|chain| chain.borrow().tokens.contains_key(&contract)) {
+        return call_token(contract, selector, calldata)
+    }
+
+    CHAIN.with(|chain| {
+        let mut chain = chain.borrow_mut();
+        match selector {
+            ownerOfCall::SELECTOR => {
+                let call = ownerOfCall::abi_decode(calldata, true).map_err(|_| Vec::new())?;
+                match chain.nft_owners.get(&(contract, call.token_id)) {
+                    Some(owner) => Ok(owner.abi_encode()),
+                    None => Err(Vec::new())
+                }
+            },
+            balanceOfCall::SELECTOR => {
+                let call = balanceOfCall::abi_decode(calldata, true).map_err(|_| Vec::new())?;
+                let balance = chain.nft_1155_balances.get(&(contract, call.account, call.id)).copied().unwrap_or_default();
+                Ok(balance.abi_encode())
+            },
+            onTokensClaimedCall::SELECTOR => {
+                let call = onTokensClaimedCall::abi_decode(calldata, true).map_err(|_| Vec::new())?;
+                match chain.receivers.get_mut(&contract) {
+                    Some(claims) => {
+                        claims.push(ReceivedClaim { user: call.user, amount: call.amount, data: call.data.to_vec() });
+                        Ok(Vec::new())
+                    },
+                    None => Err(Vec::new())
+                }
+            },
+            _ => Err(Vec::new())
+        }
+    })
+}
+
+fn call_token(token: Address, selector: [u8; 4], calldata: &[u8]) -> Result<Vec<u8>, Vec<u8>> {
+    let (from, to, amount) = match selector {
+        crate::balanceOfCall::SELECTOR => {
+            let call = crate::balanceOfCall::abi_decode(calldata, true).map_err(|_| Vec::new())?;
+            return Ok(balance(token, call.account).abi_encode())
+        },
+        permitCall::SELECTOR => {
+            let call = permitCall::abi_decode(calldata, true).map_err(|_| Vec::new())?;
+            return with_token(token, |mock| {
+                if !mock.supports_permit {
+                    return Err(Vec::new())
+                }
+
+                mock.permits.push((call.owner, call.spender, call.value, call.deadline));
+                Ok(Vec::new())
+            })
+        },
+        transferCall::SELECTOR => {
+            let call = transferCall::abi_decode(calldata, true).map_err(|_| Vec::new())?;
+            (CONTRACT, call.to, call.amount)
+        },
+        transferFromCall::SELECTOR => {
+            let call = transferFromCall::abi_decode(calldata, true).map_err(|_| Vec::new())?;
+            (call.from, call.to, call.amount)
+        },
+        _ => return Err(Vec::new())
+    };
+
+    // Let a malicious token call back into the sale before the balances move
+    if let Some(on_transfer) = with_token(token, |mock| mock.on_transfer.clone()) {
+        on_transfer();
+    }
+
+    with_token(token, |mock| {
+        let success = |succeeded: bool| if mock.returns_nothing { Vec::new() } else { succeeded.abi_encode() };
+        let from_balance = mock.balances.get(&from).copied().unwrap_or_default();
+        if from_balance < amount {
+            return if mock.returns_false { Ok(success(false)) } else { Err(Vec::new()) }
+        }
+
+        let fee = if selector == transferFromCall::SELECTOR {
+            amount * U256::from(mock.transfer_fee_bps) / U256::from(10_000)
+        } else {
+            U256::ZERO
+        };
+        mock.balances.insert(from, from_balance - amount);
+        *mock.balances.entry(to).or_default() += amount - fee;
+        Ok(success(true))
+    })
+}
+
+unsafe fn read_address(pointer: *const u8) -> Address {
+    Address::from_slice(std::slice::from_raw_parts(pointer, 20))
+}
+
+unsafe fn finish_call(result: Result<Vec<u8>, Vec<u8>>, return_data_len: *mut usize) -> u8 {
+    let (data, status) = match result {
+        Ok(data) => (data, 0),
+        Err(data) => (data, 1)
+    };
+    *return_data_len = data.len();
+    CHAIN.with(|chain| chain.borrow_mut().return_data = data);
+    status
+}
+
+#[no_mangle]
+unsafe extern "C" fn storage_load_bytes32(key: *const u8, dest: *mut u8) {
+    let key = B256::from_slice(std::slice::from_raw_parts(key, 32));
+    let value = CHAIN.with(|chain| chain.borrow().storage.get(&key).copied().unwrap_or_default());
+    std::ptr::copy_nonoverlapping(value.as_ptr(), dest, 32);
+}
+
+#[no_mangle]
+unsafe extern "C" fn storage_cache_bytes32(key: *const u8, value: *const u8) {
+    let key = B256::from_slice(std::slice::from_raw_parts(key, 32));
+    let value = B256::from_slice(std::slice::from_raw_parts(value, 32));
+    CHAIN.with(|chain| chain.borrow_mut().storage.insert(key, value));
+}
+
+#[no_mangle]
+extern "C" fn storage_flush_cache(_clear: bool) {}
+
+#[no_mangle]
+unsafe extern "C" fn call_contract(
+    contract: *const u8,
+    calldata: *const u8,
+    calldata_len: usize,
+    _value: *const u8,
+    _gas: u64,
+    return_data_len: *mut usize
+) -> u8 {
+    let result = call(read_address(contract), std::slice::from_raw_parts(calldata, calldata_len));
+    finish_call(result, return_data_len)
+}
+
+#[no_mangle]
+unsafe extern "C" fn static_call_contract(
+    contract: *const u8,
+    calldata: *const u8,
+    calldata_len: usize,
+    _gas: u64,
+    return_data_len: *mut usize
+) -> u8 {
+    let result = call(read_address(contract), std::slice::from_raw_parts(calldata, calldata_len));
+    finish_call(result, return_data_len)
+}
+
+#[no_mangle]
+unsafe extern "C" fn delegate_call_contract(
+    _contract: *const u8,
+    _calldata: *const u8,
+    _calldata_len: usize,
+    _gas: u64,
+    return_data_len: *mut usize
+) -> u8 {
+    finish_call(Err(Vec::new()), return_data_len)
+}
+
+#[no_mangle]
+unsafe extern "C" fn read_return_data(dest: *mut u8, offset: usize, size: usize) -> usize {
+    CHAIN.with(|chain| {
+        let chain = chain.borrow();
+        let data = chain.return_data.get(offset..).unwrap_or_default();
+        let written = data.len().min(size);
+        std::ptr::copy_nonoverlapping(data.as_ptr(), dest, written);
+        written
+    })
+}
+
+#[no_mangle]
+extern "C" fn return_data_size() -> usize {
+    CHAIN.with(|chain| chain.borrow().return_data.len())
+}
+
+#[no_mangle]
+unsafe extern "C" fn emit_log(data: *const u8, len: usize, topics: usize) {
+    let bytes = std::slice::from_raw_parts(data, len);
+    let (topic_bytes, data) = bytes.split_at(topics * 32);
+    let topics = topic_bytes.chunks(32).map(B256::from_slice).collect();
+    CHAIN.with(|chain| chain.borrow_mut().logs.push((topics, data.to_vec())));
+}
+
+#[no_mangle]
+unsafe extern "C" fn account_codehash(address: *const u8, dest: *mut u8) {
+    let has_code = CHAIN.with(|chain| chain.borrow().contracts.contains(&read_address(address)));
+    let hash = if has_code { keccak256(b"code") } else { B256::ZERO };
+    std::ptr::copy_nonoverlapping(hash.as_ptr(), dest, 32);
+}
+
+#[no_mangle]
+unsafe extern "C" fn account_code_size(address: *const u8) -> usize {
+    usize::from(CHAIN.with(|chain| chain.borrow().contracts.contains(&read_address(address))))
+}
+
+#[no_mangle]
+unsafe extern "C" fn account_code(_address: *const u8, _offset: usize, _size: usize, _dest: *mut u8) -> usize {
+    0
+}
+
+#[no_mangle]
+unsafe extern "C" fn account_balance(_address: *const u8, dest: *mut u8) {
+    std::ptr::write_bytes(dest, 0, 32);
+}
+
+#[no_mangle]
+unsafe extern "C" fn native_keccak256(bytes: *const u8, len: usize, output: *mut u8) {
+    let hash = keccak256(std::slice::from_raw_parts(bytes, len));
+    std::ptr::copy_nonoverlapping(hash.as_ptr(), output, 32);
+}
+
+#[no_mangle]
+unsafe extern "C" fn msg_sender(sender: *mut u8) {
+    std::ptr::copy_nonoverlapping(SENDER.as_ptr(), sender, 20);
+}
+
+#[no_mangle]
+unsafe extern "C" fn tx_origin(origin: *mut u8) {
+    std::ptr::copy_nonoverlapping(SENDER.as_ptr(), origin, 20);
+}
+
+#[no_mangle]
+unsafe extern "C" fn contract_address(address: *mut u8) {
+    std::ptr::copy_nonoverlapping(CONTRACT.as_ptr(), address, 20);
+}
+
+#[no_mangle]
+unsafe extern "C" fn msg_value(value: *mut u8) {
+    std::ptr::write_bytes(value, 0, 32);
+}
+
+#[no_mangle]
+extern "C" fn msg_reentrant() -> bool {
+    false
+}
+
+#[no_mangle]
+extern "C" fn block_timestamp() -> u64 {
+    NOW
+}
+
+#[no_mangle]
+extern "C" fn block_number() -> u64 {
+    1
+}
+
+#[no_mangle]
+extern "C" fn chainid() -> u64 {
+    1
+}
+
+#[no_mangle]
+extern "C" fn block_gas_limit() -> u64 {
+    u64::MAX
+}
+
+#[no_mangle]
+unsafe extern "C" fn block_basefee(basefee: *mut u8) {
+    std::ptr::write_bytes(basefee, 0, 32);
+}
+
+#[no_mangle]
+unsafe extern "C" fn block_coinbase(coinbase: *mut u8) {
+    std::ptr::write_bytes(coinbase, 0, 20);
+}
+
+#[no_mangle]
+unsafe extern "C" fn tx_gas_price(gas_price: *mut u8) {
+    std::ptr::write_bytes(gas_price, 0, 32);
+}
+
+#[no_mangle]
+extern "C" fn tx_ink_price() -> u32 {
+    1
+}
+
+#[no_mangle]
+extern "C" fn evm_gas_left() -> u64 {
+    u64::MAX
+}
+
+#[no_mangle]
+extern "C" fn evm_ink_left() -> u64 {
+    u64::MAX
+}
+
+#[no_mangle]
+extern "C" fn pay_for_memory_grow(_pages: u16) {}
+
+#[no_mangle]
+unsafe extern "C" fn read_args(_dest: *mut u8) {}
+
+#[no_mangle]
+unsafe extern "C" fn write_result(_data: *const u8, _len: usize) {}
+
+#[no_mangle]
+unsafe extern "C" fn create1(
+    _code: *const u8,
+    _code_len: usize,
+    _endowment: *const u8,
+    contract: *mut u8,
+    revert_data_len: *mut usize
+) {
+    std::ptr::write_bytes(contract, 0, 20);
+    *revert_data_len = 0;
+}
+
+#[no_mangle]
+unsafe extern "C" fn create2(
+    _code: *const u8,
+    _code_len: usize,
+    _endowment: *const u8,
+    _salt: *const u8,
+    contract: *mut u8,
+    revert_data_len: *mut usize
+) {
+    std::ptr::write_bytes(contract, 0, 20);
+    *revert_data_len = 0;
+}